@@ -26,7 +26,7 @@ pub fn bench_write(c: &mut Criterion) {
     let sled = Sled::new(&tmp_dir.path().to_path_buf()).unwrap();
 
     let kvs_tmp_dir = TempDir::new().unwrap();
-    let kvs_store = KVStore::new(&kvs_tmp_dir.path().to_path_buf()).unwrap();
+    let kvs_store = KVStore::new(kvs_tmp_dir.path()).unwrap();
 
     let mut group: criterion::BenchmarkGroup<'_, criterion::measurement::WallTime> =
         c.benchmark_group("get_write");
@@ -65,7 +65,7 @@ pub fn bench_read(c: &mut Criterion) {
     });
 
     let kvs_tmp_dir = TempDir::new().unwrap();
-    let kvs_store = KVStore::new(&kvs_tmp_dir.path().to_path_buf()).unwrap();
+    let kvs_store = KVStore::new(kvs_tmp_dir.path()).unwrap();
     keys.iter().enumerate().for_each(|(index, elem)| {
         kvs_store
             .set(elem.to_owned(), values[index].to_owned())
@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use crossbeam::sync::WaitGroup;
+
+use kvs::thread_pool::{
+    native::NativeThreadPool, rayon::RayonThreadPool, shared_queue::SharedQueueThreadPool,
+    work_stealing::WorkStealingThreadPool, ThreadPool,
+};
+
+// small jobs like the engine requests of the server, the cost is mostly the
+// queue
+const JOBS: u64 = 1000;
+
+fn run_jobs<T: ThreadPool>(pool: &T, counter: &Arc<AtomicU64>) {
+    let wg = WaitGroup::new();
+    for i in 0..JOBS {
+        let (counter, wg) = (counter.clone(), wg.clone());
+        pool.spawn(move || {
+            counter.fetch_add(i, Ordering::Relaxed);
+            drop(wg)
+        });
+    }
+    wg.wait();
+}
+
+fn bench_pool<T: ThreadPool>(c: &mut Criterion, name: &str) {
+    let threads = thread::available_parallelism().map_or(4, |n| n.get() as u32);
+    let pool = T::new(threads).unwrap();
+    let counter = Arc::new(AtomicU64::new(0));
+
+    let mut group = c.benchmark_group("thread_pool_small_jobs");
+    group.bench_function(BenchmarkId::new(name, threads), |b| {
+        b.iter(|| run_jobs(&pool, &counter))
+    });
+    group.finish();
+}
+
+pub fn bench_pools(c: &mut Criterion) {
+    bench_pool::<NativeThreadPool>(c, "native");
+    bench_pool::<SharedQueueThreadPool>(c, "shared_queue");
+    bench_pool::<RayonThreadPool>(c, "rayon");
+    bench_pool::<WorkStealingThreadPool>(c, "work_stealing");
+}
+
+criterion_group!(benches, bench_pools);
+criterion_main!(benches);
@@ -0,0 +1,150 @@
+// key generators and latency summaries of the kvs_bench load generator
+use std::fmt;
+use std::time::Duration;
+
+use rand::Rng;
+
+// KeyGenerator picks key indexes in 0..n, index 0 is the hottest key of the
+// zipfian distribution.
+#[derive(Debug, Clone)]
+pub enum KeyGenerator {
+    Uniform {
+        n: u64,
+    },
+    // the generator of Gray et al., "Quickly Generating Billion-Record
+    // Synthetic Databases", also used by YCSB
+    Zipfian {
+        n: u64,
+        theta: f64,
+        alpha: f64,
+        zetan: f64,
+        eta: f64,
+    },
+}
+
+impl KeyGenerator {
+    pub fn uniform(n: u64) -> Self {
+        KeyGenerator::Uniform { n: n.max(1) }
+    }
+
+    // theta in (0, 1), larger is more skewed; computing zeta is O(n)
+    pub fn zipfian(n: u64, theta: f64) -> Self {
+        let n = n.max(1);
+        let theta = theta.clamp(0.01, 0.999);
+        let zetan = zeta(n, theta);
+        let zeta2 = zeta(2.min(n), theta);
+        KeyGenerator::Zipfian {
+            n,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta: (1.0 - (2.0 / n as f64).powf(1.0 - theta)) / (1.0 - zeta2 / zetan),
+        }
+    }
+
+    pub fn next<R: Rng>(&self, rng: &mut R) -> u64 {
+        match *self {
+            KeyGenerator::Uniform { n } => rng.gen_range(0..n),
+            KeyGenerator::Zipfian {
+                n,
+                theta,
+                alpha,
+                zetan,
+                eta,
+            } => {
+                let u: f64 = rng.gen();
+                let uz = u * zetan;
+                if uz < 1.0 {
+                    return 0;
+                }
+                if uz < 1.0 + 0.5f64.powf(theta) {
+                    return 1.min(n - 1);
+                }
+                let index = (n as f64 * (eta * u - eta + 1.0).powf(alpha)) as u64;
+                index.min(n - 1)
+            }
+        }
+    }
+}
+
+fn zeta(n: u64, theta: f64) -> f64 {
+    (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum()
+}
+
+// Latencies keeps every sample, a run of a few million requests needs a few
+// megabytes and the percentiles are exact.
+#[derive(Debug, Default, Clone)]
+pub struct Latencies {
+    samples: Vec<Duration>,
+    sorted: bool,
+}
+
+impl Latencies {
+    pub fn record(&mut self, latency: Duration) {
+        self.samples.push(latency);
+        self.sorted = false;
+    }
+
+    pub fn merge(&mut self, other: Latencies) {
+        self.samples.extend(other.samples);
+        self.sorted = false;
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    // the nearest-rank percentile, p in [0, 100]
+    pub fn percentile(&mut self, p: f64) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        if !self.sorted {
+            self.samples.sort_unstable();
+            self.sorted = true;
+        }
+        // the epsilon keeps e.g. 99.9% of 1000 samples at rank 999
+        let rank = (p / 100.0 * self.samples.len() as f64 - 1e-9).ceil() as usize;
+        self.samples[rank.clamp(1, self.samples.len()) - 1]
+    }
+
+    pub fn summary(&mut self) -> LatencySummary {
+        LatencySummary {
+            min: self.percentile(0.0),
+            p50: self.percentile(50.0),
+            p90: self.percentile(90.0),
+            p99: self.percentile(99.0),
+            p999: self.percentile(99.9),
+            max: self.percentile(100.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LatencySummary {
+    pub min: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    pub max: Duration,
+}
+
+impl fmt::Display for LatencySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "min={} p50={} p90={} p99={} p999={} max={}",
+            self.min.as_micros(),
+            self.p50.as_micros(),
+            self.p90.as_micros(),
+            self.p99.as_micros(),
+            self.p999.as_micros(),
+            self.max.as_micros()
+        )
+    }
+}
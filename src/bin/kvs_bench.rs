@@ -0,0 +1,220 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, ValueEnum};
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use kvs::bench::{KeyGenerator, Latencies};
+use kvs::client::Client;
+use kvs::server::{Request, Status};
+
+// drives a running server and reports the throughput and latency seen by
+// the clients
+#[derive(Parser, Debug)]
+struct BenchCommand {
+    // host:port or unix:///path/to/socket
+    #[arg(long)]
+    addr: String,
+
+    // connections sending requests at the same time, one task each
+    #[arg(long, default_value_t = 16)]
+    concurrency: usize,
+
+    #[arg(long, default_value_t = 10)]
+    duration_secs: u64,
+
+    // distinct keys in the key space
+    #[arg(long, default_value_t = 10_000)]
+    keys: u64,
+
+    #[arg(long, default_value_t = 16)]
+    key_size: usize,
+
+    #[arg(long, default_value_t = 100)]
+    value_size: usize,
+
+    // share of reads in [0, 1], the rest are writes
+    #[arg(long, default_value_t = 0.9)]
+    read_ratio: f64,
+
+    #[arg(long, value_enum, default_value_t = Distribution::Uniform)]
+    distribution: Distribution,
+
+    // skew of the zipfian distribution in (0, 1)
+    #[arg(long, default_value_t = 0.99)]
+    zipf_theta: f64,
+
+    // write every key once before measuring, so reads do not miss
+    #[arg(long)]
+    preload: bool,
+
+    // seed of the key and operation choices, random if none
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Distribution {
+    Uniform,
+    Zipfian,
+}
+
+// counters of one worker, merged into the report
+#[derive(Default)]
+struct WorkerStats {
+    reads: u64,
+    writes: u64,
+    misses: u64,
+    errors: u64,
+    latencies: Latencies,
+}
+
+impl WorkerStats {
+    fn merge(&mut self, other: WorkerStats) {
+        self.reads += other.reads;
+        self.writes += other.writes;
+        self.misses += other.misses;
+        self.errors += other.errors;
+        self.latencies.merge(other.latencies);
+    }
+}
+
+#[tokio::main]
+pub async fn main() -> Result<()> {
+    let cli = BenchCommand::parse();
+    if !(0.0..=1.0).contains(&cli.read_ratio) {
+        return Err(anyhow!("read ratio must be between 0 and 1"));
+    }
+
+    let generator = match cli.distribution {
+        Distribution::Uniform => KeyGenerator::uniform(cli.keys),
+        Distribution::Zipfian => KeyGenerator::zipfian(cli.keys, cli.zipf_theta),
+    };
+    let seed = cli.seed.unwrap_or_else(rand::random);
+
+    if cli.preload {
+        preload(&cli).await?;
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(cli.duration_secs);
+    let start = Instant::now();
+    let mut workers = Vec::with_capacity(cli.concurrency);
+    for id in 0..cli.concurrency.max(1) {
+        let client = Client::connect(&cli.addr).await?;
+        let generator = generator.clone();
+        let rng = StdRng::seed_from_u64(seed.wrapping_add(id as u64));
+        let (key_size, value_size, read_ratio) = (cli.key_size, cli.value_size, cli.read_ratio);
+        workers.push(tokio::spawn(async move {
+            let mut worker = Worker {
+                client,
+                generator,
+                rng,
+                key_size,
+                read_ratio,
+                value: String::new(),
+            };
+            worker.value = worker.random_value(value_size);
+            worker.run(deadline).await
+        }));
+    }
+
+    let mut stats = WorkerStats::default();
+    for worker in workers {
+        stats.merge(worker.await?);
+    }
+    let elapsed = start.elapsed();
+
+    let ops = stats.latencies.len() as u64;
+    println!(
+        "distribution={:?} concurrency={} keys={} key_size={} value_size={} read_ratio={}",
+        cli.distribution, cli.concurrency, cli.keys, cli.key_size, cli.value_size, cli.read_ratio
+    );
+    println!("duration_secs={:.2}", elapsed.as_secs_f64());
+    println!(
+        "operations={} ops_per_sec={:.1}",
+        ops,
+        ops as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "reads={} writes={} misses={} errors={}",
+        stats.reads, stats.writes, stats.misses, stats.errors
+    );
+    println!("latency_us: {}", stats.latencies.summary());
+    Ok(())
+}
+
+// writes every key with MSET batches on one connection
+async fn preload(cli: &BenchCommand) -> Result<()> {
+    let mut client = Client::connect(&cli.addr).await?;
+    let value: String = "v".repeat(cli.value_size);
+    let mut index = 0;
+    while index < cli.keys {
+        let end = (index + 1000).min(cli.keys);
+        let pairs = (index..end)
+            .map(|i| (format_key(i, cli.key_size), value.clone()))
+            .collect();
+        client.set_many(pairs).await?;
+        index = end;
+    }
+    Ok(())
+}
+
+// keys are zero padded indexes, so every key has the same size
+fn format_key(index: u64, size: usize) -> String {
+    format!("key{:0width$}", index, width = size.saturating_sub(3))
+}
+
+struct Worker {
+    client: Client,
+    generator: KeyGenerator,
+    rng: StdRng,
+    key_size: usize,
+    read_ratio: f64,
+    // every write sends the same value
+    value: String,
+}
+
+impl Worker {
+    fn random_value(&mut self, size: usize) -> String {
+        (&mut self.rng)
+            .sample_iter(&Alphanumeric)
+            .take(size)
+            .map(char::from)
+            .collect()
+    }
+
+    async fn run(&mut self, deadline: Instant) -> WorkerStats {
+        let mut stats = WorkerStats::default();
+        while Instant::now() < deadline {
+            let key = format_key(self.generator.next(&mut self.rng), self.key_size);
+            let read = self.rng.gen_bool(self.read_ratio);
+            let request = if read {
+                stats.reads += 1;
+                Request::Get(key)
+            } else {
+                stats.writes += 1;
+                Request::Set(key, self.value.clone())
+            };
+
+            let start = Instant::now();
+            let res = self.client.call(request).await;
+            stats.latencies.record(start.elapsed());
+
+            match res {
+                Ok(response) => match response.status {
+                    Status::Ok => {}
+                    Status::NotFound => stats.misses += 1,
+                    Status::Error | Status::Redirect | Status::Conflict => stats.errors += 1,
+                },
+                // the connection is broken, the worker stops
+                Err(_) => {
+                    stats.errors += 1;
+                    break;
+                }
+            }
+        }
+        stats
+    }
+}
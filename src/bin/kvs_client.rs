@@ -1,15 +1,44 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Write};
+use std::path::PathBuf;
+use std::process;
+use std::time::Instant;
 
-use kvs::client::Client;
+use anyhow::anyhow;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use serde_json::json;
+use tokio::task::JoinSet;
+
+use kvs::client::dump::{DumpFormat, DumpReader, DumpWriter};
+use kvs::client::{Client, ClientPool, PoolConfig};
+use kvs::server::{AdminCommand, Request, ServerError, Status};
+use kvs::watch::WatchEvent;
 
 #[derive(Parser, Debug)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
 
+    // not needed by V
     #[clap(long)]
-    addr: String,
+    addr: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = Output::Text, global = true)]
+    output: Output,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Output {
+    // the value, or a message for commands without one
+    Text,
+    // one json object with the status per command, or per event of a watch
+    Json,
+    // the value exactly as stored, without a trailing newline
+    Raw,
 }
 
 #[derive(Subcommand, Debug)]
@@ -20,34 +49,571 @@ enum Commands {
     Set { key: String, value: String },
     #[command(name = "rm", about = "remove key from kv store")]
     Remove { key: String },
+    #[command(about = "print the changes of keys starting with prefix")]
+    Watch {
+        #[arg(default_value_t = String::new())]
+        prefix: String,
+    },
+    #[command(about = "print the requests slower than the server threshold")]
+    Slowlog {
+        #[arg(long)]
+        count: Option<u32>,
+
+        #[arg(long, help = "clear the slow log after printing it")]
+        reset: bool,
+    },
+    #[command(about = "print server and engine statistics")]
+    Info {},
+    #[command(about = "run an admin command on the server")]
+    Admin {
+        #[arg(long)]
+        token: Option<String>,
+
+        #[command(subcommand)]
+        command: AdminCommands,
+    },
     #[command(name = "V", about = "print the version")]
     Version {},
+    #[command(about = "write all pairs, or those of a key prefix, to a file")]
+    Export {
+        #[arg(help = "file to write, - for stdout")]
+        file: PathBuf,
+
+        #[arg(long, default_value_t = String::new())]
+        prefix: String,
+
+        #[arg(long, default_value = "json", help = "json or binary")]
+        format: DumpFormat,
+
+        #[arg(long, default_value_t = 1000, help = "pairs fetched per request")]
+        batch_size: u32,
+    },
+    #[command(about = "load the pairs of an exported file")]
+    Import {
+        #[arg(help = "file to read, - for stdin")]
+        file: PathBuf,
+
+        #[arg(long, default_value = "json", help = "json or binary")]
+        format: DumpFormat,
+
+        #[arg(long, default_value_t = 500, help = "pairs written per request")]
+        batch_size: usize,
+
+        #[arg(long, default_value_t = 4, help = "requests in flight at once")]
+        concurrency: usize,
+    },
+    #[command(about = "run commands interactively or from a script on one connection")]
+    Shell {
+        #[arg(long, help = "run the commands of a script file, - for stdin")]
+        file: Option<PathBuf>,
+
+        #[arg(long, help = "print the duration of every command of a script")]
+        timing: bool,
+    },
+}
+
+// a line typed in the shell, one of the subcommands without the binary name
+#[derive(Parser, Debug)]
+#[command(no_binary_name = true, disable_version_flag = true)]
+struct ShellLine {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+enum AdminCommands {
+    #[command(about = "compact the storage engine")]
+    Compact {},
+    #[command(about = "flush and sync the storage engine to disk")]
+    Flush {},
+    #[command(about = "write a snapshot of the store to a path on the server")]
+    Snapshot { path: String },
+    #[command(about = "print storage engine statistics")]
+    Stats {},
+}
+
+// exit codes, 2 is used by clap for invalid arguments
+const EXIT_NOT_FOUND: i32 = 1;
+const EXIT_CONNECTION: i32 = 3;
+const EXIT_SERVER: i32 = 4;
+const EXIT_OTHER: i32 = 5;
+
+// Failure is why a command did not succeed, it selects the exit code.
+#[derive(Debug)]
+enum Failure {
+    NotFound(String),
+    Connection(anyhow::Error),
+    Server(String),
+    Other(anyhow::Error),
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::NotFound(_) => EXIT_NOT_FOUND,
+            Failure::Connection(_) => EXIT_CONNECTION,
+            Failure::Server(_) => EXIT_SERVER,
+            Failure::Other(_) => EXIT_OTHER,
+        }
+    }
+
+    // the status of a failure in json output
+    fn status(&self) -> &'static str {
+        match self {
+            Failure::NotFound(_) => "not_found",
+            Failure::Connection(_) => "connection_error",
+            Failure::Server(_) => "server_error",
+            Failure::Other(_) => "error",
+        }
+    }
+
+    // text and raw output only write diagnostics to stderr
+    fn report(&self, output: Output) {
+        match (output, self) {
+            (Output::Json, Failure::NotFound(key)) => {
+                println!("{}", json!({ "status": self.status(), "key": key }))
+            }
+            (Output::Json, _) => {
+                println!(
+                    "{}",
+                    json!({ "status": self.status(), "error": self.to_string() })
+                )
+            }
+            (_, Failure::NotFound(_)) => eprintln!("{}", self),
+            _ => eprintln!("error: {}", self),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::NotFound(_) => write!(f, "Key not found"),
+            Failure::Connection(e) => write!(f, "{:#}", e),
+            Failure::Server(e) => write!(f, "{}", e),
+            Failure::Other(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+// errors of the client are either reported by the server or failures to
+// reach it, local errors are mapped to Other where they happen
+impl From<anyhow::Error> for Failure {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<ServerError>() {
+            Ok(e) => Failure::Server(e.0),
+            Err(e) => Failure::Connection(e),
+        }
+    }
+}
+
+// prints the result of a successful command, value is none for the commands
+// that only change the store
+fn print_ok(output: Output, key: Option<&str>, value: Option<&str>) {
+    match output {
+        Output::Text => match value {
+            Some(value) if value.ends_with('\n') => print!("{}", value),
+            Some(value) => println!("{}", value),
+            None => {}
+        },
+        Output::Raw => print!("{}", value.unwrap_or_default()),
+        Output::Json => {
+            let mut object = json!({ "status": "ok" });
+            if let Some(key) = key {
+                object["key"] = json!(key);
+            }
+            if let Some(value) = value {
+                object["value"] = json!(value);
+            }
+            println!("{}", object)
+        }
+    }
 }
 
 #[tokio::main]
-pub async fn main() -> Result<()> {
+pub async fn main() {
     let cli = Cli::parse();
+    let output = cli.output;
+
+    if let Err(failure) = run(cli).await {
+        failure.report(output);
+        process::exit(failure.exit_code());
+    }
+}
 
-    let mut client = Client::connect(&cli.addr).await?;
+async fn run(cli: Cli) -> Result<(), Failure> {
+    let output = cli.output;
+    if let Commands::Version {} = cli.command {
+        return execute(None, output, cli.command).await;
+    }
+
+    let addr = match cli.addr {
+        Some(addr) => addr,
+        None => Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--addr <ADDR> is required by every command except V",
+            )
+            .exit(),
+    };
+    let mut client = Client::connect(&addr).await.map_err(Failure::Connection)?;
 
     match cli.command {
+        Commands::Watch { prefix } => watch(client, output, prefix).await,
+        Commands::Shell { file, timing } => shell(&mut client, output, file, timing).await,
+        Commands::Import {
+            file,
+            format,
+            batch_size,
+            concurrency,
+        } => import(&addr, file, format, batch_size, concurrency).await,
+        command => execute(Some(&mut client), output, command).await,
+    }
+}
+
+// sends a request and maps its status to a failure
+async fn call(client: &mut Client, request: Request) -> Result<String, Failure> {
+    let key = request.key().unwrap_or_default().to_string();
+    let response = client.call(request).await?;
+    match response.status {
+        Status::Ok => Ok(response.response),
+        Status::NotFound => Err(Failure::NotFound(key)),
+        Status::Error => Err(Failure::Server(response.response)),
+        Status::Redirect => Err(Failure::Server("no leader to redirect to".to_string())),
+        Status::Conflict => Err(Failure::Server(response.response)),
+    }
+}
+
+// runs a command of the command line or of a shell line, the client is only
+// none for V
+async fn execute(
+    client: Option<&mut Client>,
+    output: Output,
+    command: Commands,
+) -> Result<(), Failure> {
+    let (client, command) = match (client, command) {
+        (_, Commands::Version {}) => {
+            let version = format!("kvs version {}", env!("CARGO_PKG_VERSION"));
+            print_ok(output, None, Some(&version));
+            return Ok(());
+        }
+        (Some(client), command) => (client, command),
+        (None, _) => return Err(Failure::Other(anyhow!("not connected"))),
+    };
+
+    match command {
         Commands::Get { key } => {
-            let resp = client.get(key).await?;
-            println!("{}", resp)
+            let value = call(client, Request::Get(key.clone())).await?;
+            print_ok(output, Some(&key), Some(&value))
         }
         Commands::Set { key, value } => {
-            let resp = client.set(key, value).await?;
-            println!("{}", resp)
+            call(client, Request::Set(key.clone(), value)).await?;
+            print_ok(output, Some(&key), None)
         }
         Commands::Remove { key } => {
-            let resp = client.remove(key).await?;
-            println!("{}", resp)
+            call(client, Request::Remove(key.clone())).await?;
+            print_ok(output, Some(&key), None)
+        }
+        Commands::Slowlog { count, reset } => {
+            let value = call(client, Request::SlowLog { count, reset }).await?;
+            print_ok(output, None, Some(&value))
         }
-        Commands::Version {} => {
-            let version = env!("CARGO_PKG_VERSION");
-            println!("kvs version {:}", version);
+        Commands::Info {} => {
+            let value = call(client, Request::Info {}).await?;
+            print_ok(output, None, Some(&value))
+        }
+        Commands::Admin { token, command } => {
+            let command = match command {
+                AdminCommands::Compact {} => AdminCommand::Compact,
+                AdminCommands::Flush {} => AdminCommand::Flush,
+                AdminCommands::Snapshot { path } => AdminCommand::Snapshot(path),
+                AdminCommands::Stats {} => AdminCommand::Stats,
+            };
+            let value = call(client, Request::Admin { token, command }).await?;
+            print_ok(output, None, Some(&value))
+        }
+        Commands::Export {
+            file,
+            prefix,
+            format,
+            batch_size,
+        } => export(client, file, prefix, format, batch_size).await?,
+        _ => {
+            return Err(Failure::Other(anyhow!(
+                "command is not available in the shell"
+            )))
         }
     };
 
     Ok(())
 }
+
+async fn watch(client: Client, output: Output, prefix: String) -> Result<(), Failure> {
+    let mut stream = client.watch(prefix).await?;
+    while let Some(event) = stream.next().await? {
+        match (output, event) {
+            (Output::Json, WatchEvent::Set { seq, key, value }) => println!(
+                "{}",
+                json!({ "seq": seq, "op": "set", "key": key, "value": value })
+            ),
+            (Output::Json, WatchEvent::Remove { seq, key }) => {
+                println!("{}", json!({ "seq": seq, "op": "rm", "key": key }))
+            }
+            (_, WatchEvent::Set { seq, key, value }) => {
+                println!("{} set {} {}", seq, key, value)
+            }
+            (_, WatchEvent::Remove { seq, key }) => println!("{} rm {}", seq, key),
+        }
+    }
+    Ok(())
+}
+
+// pages through the pairs with scan requests, so the server never holds more
+// than one batch for the export
+async fn export(
+    client: &mut Client,
+    file: PathBuf,
+    prefix: String,
+    format: DumpFormat,
+    batch_size: u32,
+) -> Result<(), Failure> {
+    let output: Box<dyn Write> = if file.as_os_str() == "-" {
+        Box::new(io::stdout().lock())
+    } else {
+        Box::new(File::create(file).map_err(|e| Failure::Other(e.into()))?)
+    };
+    let mut writer = DumpWriter::new(BufWriter::new(output), format).map_err(Failure::Other)?;
+
+    let mut progress = Progress::new("exported");
+    let mut after = None;
+    loop {
+        let pairs = client
+            .scan(prefix.clone(), after.take(), batch_size.max(1))
+            .await?;
+        for (key, value) in pairs.iter() {
+            writer.write(key, value).map_err(Failure::Other)?;
+        }
+        progress.add(pairs.len());
+
+        match pairs.into_iter().last() {
+            Some((key, _)) => after = Some(key),
+            None => break,
+        }
+    }
+
+    writer.finish().map_err(Failure::Other)?;
+    progress.finish();
+    Ok(())
+}
+
+// Batches are written with MSET on a pool of `concurrency` connections, so
+// several batches are in flight while the next ones are read from the file.
+async fn import(
+    addr: &str,
+    file: PathBuf,
+    format: DumpFormat,
+    batch_size: usize,
+    concurrency: usize,
+) -> Result<(), Failure> {
+    let input: Box<dyn BufRead> = if file.as_os_str() == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(
+            File::open(file).map_err(|e| Failure::Other(e.into()))?,
+        ))
+    };
+    let reader = DumpReader::new(input, format).map_err(Failure::Other)?;
+
+    let concurrency = concurrency.max(1);
+    let pool = ClientPool::new(
+        addr,
+        PoolConfig {
+            size: concurrency,
+            ..Default::default()
+        },
+    );
+
+    let mut progress = Progress::new("imported");
+    let mut in_flight = JoinSet::new();
+    let mut batch = Vec::with_capacity(batch_size);
+    for pair in reader {
+        batch.push(pair.map_err(Failure::Other)?);
+        if batch.len() < batch_size.max(1) {
+            continue;
+        }
+
+        if in_flight.len() >= concurrency {
+            progress.add(joined(in_flight.join_next().await)?);
+        }
+        let pairs = std::mem::replace(&mut batch, Vec::with_capacity(batch_size));
+        let pool = pool.clone();
+        in_flight.spawn(async move {
+            let len = pairs.len();
+            pool.set_many(pairs).await.map(|_| len)
+        });
+    }
+    if !batch.is_empty() {
+        let len = batch.len();
+        pool.set_many(batch).await?;
+        progress.add(len);
+    }
+    while let Some(res) = in_flight.join_next().await {
+        progress.add(joined(Some(res))?);
+    }
+
+    progress.finish();
+    Ok(())
+}
+
+// the pairs written by a finished import batch
+fn joined(
+    res: Option<Result<anyhow::Result<usize>, tokio::task::JoinError>>,
+) -> Result<usize, Failure> {
+    match res {
+        Some(res) => Ok(res.map_err(|e| Failure::Other(e.into()))??),
+        None => Ok(0),
+    }
+}
+
+// Progress counts the pairs of an export or import on stderr, updating one
+// line in place when stderr is a terminal.
+struct Progress {
+    verb: &'static str,
+    count: usize,
+    start: Instant,
+}
+
+impl Progress {
+    fn new(verb: &'static str) -> Self {
+        Progress {
+            verb,
+            count: 0,
+            start: Instant::now(),
+        }
+    }
+
+    fn add(&mut self, count: usize) {
+        self.count += count;
+        if io::stderr().is_terminal() {
+            eprint!("\r{} {} keys", self.verb, self.count);
+        }
+    }
+
+    fn finish(self) {
+        if io::stderr().is_terminal() {
+            eprint!("\r");
+        }
+        eprintln!(
+            "{} {} keys in {:.2}s",
+            self.verb,
+            self.count,
+            self.start.elapsed().as_secs_f64()
+        );
+    }
+}
+
+// Commands are read from the terminal with line editing and history, or from
+// a script when a file is given or stdin is not a terminal. Words are split
+// like a shell does, so values with spaces are quoted.
+async fn shell(
+    client: &mut Client,
+    output: Output,
+    file: Option<PathBuf>,
+    timing: bool,
+) -> Result<(), Failure> {
+    let script: Box<dyn BufRead> = match file {
+        Some(path) if path.as_os_str() == "-" => Box::new(io::stdin().lock()),
+        Some(path) => Box::new(BufReader::new(
+            File::open(path).map_err(|e| Failure::Other(e.into()))?,
+        )),
+        None if !io::stdin().is_terminal() => Box::new(io::stdin().lock()),
+        None => return interactive(client, output).await,
+    };
+
+    // a missing key is reported and the script goes on, it stops at any
+    // other failure
+    for (number, line) in script.lines().enumerate() {
+        let line = line.map_err(|e| Failure::Other(e.into()))?;
+        if matches!(line.trim(), "exit" | "quit") {
+            break;
+        }
+        match run_line(client, output, &line, timing).await {
+            Ok(()) => {}
+            Err(failure @ Failure::NotFound(_)) => failure.report(output),
+            Err(failure) => {
+                eprintln!("failed at line {}", number + 1);
+                return Err(failure);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn interactive(client: &mut Client, output: Output) -> Result<(), Failure> {
+    let mut editor = DefaultEditor::new().map_err(|e| Failure::Other(e.into()))?;
+    let history = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".kvs_history"));
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+
+    loop {
+        let line = match editor.readline("kvs> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(Failure::Other(e.into())),
+        };
+        match line.trim() {
+            "" => continue,
+            "exit" | "quit" => break,
+            _ => {}
+        }
+
+        let _ = editor.add_history_entry(line.as_str());
+        if let Err(failure) = run_line(client, output, &line, true).await {
+            failure.report(output);
+        }
+    }
+
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+    Ok(())
+}
+
+// runs one line of the shell, blank lines and comments starting with # are skipped
+async fn run_line(
+    client: &mut Client,
+    output: Output,
+    line: &str,
+    timing: bool,
+) -> Result<(), Failure> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(());
+    }
+
+    let words = shlex::split(line).ok_or_else(|| Failure::Other(anyhow!("unterminated quote")))?;
+    let command = match ShellLine::try_parse_from(words) {
+        Ok(parsed) => parsed.command,
+        // help is printed as the output of the line
+        Err(e) if !e.use_stderr() => {
+            print!("{}", e);
+            return Ok(());
+        }
+        Err(e) => {
+            let message = e.render().to_string();
+            let first = message.lines().next().unwrap_or_default();
+            return Err(Failure::Other(anyhow!(first
+                .trim_start_matches("error: ")
+                .to_string())));
+        }
+    };
+
+    let start = Instant::now();
+    let res = execute(Some(client), output, command).await;
+    if timing {
+        eprintln!("({:.3} ms)", start.elapsed().as_secs_f64() * 1000.0);
+    }
+    res
+}
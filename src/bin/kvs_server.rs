@@ -1,28 +1,176 @@
 use std::env::current_dir;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use clap::Parser;
-use kvs::sled::Sled;
-use kvs::{kvs::KVStore, server::Server};
+use env_logger::Env;
+use kvs::engine::KvsEngine;
+use kvs::raft::{RaftConfig, RaftEngine};
+use kvs::replication::{ReadOnlyEngine, Replica};
+use kvs::sled::{Sled, SledConfig};
+use kvs::{
+    kvs::KVStore,
+    server::{Server, ServerConfig},
+};
+use tokio::signal::unix::{signal, SignalKind};
 
 #[derive(Parser, Debug)]
 struct ServerCommand {
+    // host:port or unix:///path/to/socket
     #[arg(short, long)]
     listen_addr: String,
 
     #[arg(short, long, default_value_t = String::from("kvs"))]
     engine: String,
+
+    #[arg(long)]
+    metrics_addr: Option<String>,
+
+    #[arg(long)]
+    admin_token: Option<String>,
+
+    // unix domain socket to listen on in addition to listen_addr
+    #[arg(long)]
+    unix_socket: Option<PathBuf>,
+
+    // octal permissions of the unix socket file, e.g. 660
+    #[arg(long, value_parser = parse_mode)]
+    unix_socket_mode: Option<u32>,
+
+    // requests slower than this are kept in the slow log
+    #[arg(long, default_value_t = 10_000)]
+    slowlog_threshold_us: u64,
+
+    #[arg(long, default_value_t = 128)]
+    slowlog_max_len: usize,
+
+    // log every request with its command, key, size, peer and duration
+    #[arg(long)]
+    log_requests: bool,
+
+    // transactions open for longer are aborted, 0 keeps them open
+    #[arg(long, default_value_t = 60_000)]
+    transaction_timeout_ms: u64,
+
+    // workers of the pool running the engine calls
+    #[arg(long, default_value_t = 16)]
+    threads: u32,
+
+    // host:port of a primary to replicate, the server is read-only then
+    #[arg(long, conflicts_with = "raft_addr")]
+    replica_of: Option<String>,
+
+    // host:port the other nodes of a raft group reach this node at
+    #[arg(long, requires = "raft_peers")]
+    raft_addr: Option<String>,
+
+    // raft addresses of the other nodes of the group
+    #[arg(long, value_delimiter = ',')]
+    raft_peers: Vec<String>,
+
+    // applied entries kept in the raft log before it is compacted
+    #[arg(long, default_value_t = 1000)]
+    raft_snapshot_entries: u64,
+
+    // flush every sled write to the disk before answering it
+    #[arg(long)]
+    sync_writes: bool,
+}
+
+fn parse_mode(mode: &str) -> Result<u32> {
+    u32::from_str_radix(mode, 8).map_err(|e| anyhow!(e))
 }
 
 #[tokio::main]
 pub async fn main() -> Result<()> {
     let cli = ServerCommand::parse();
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let config = ServerConfig {
+        metrics_addr: cli.metrics_addr.clone(),
+        admin_token: cli.admin_token.clone(),
+        unix_socket: cli.unix_socket.clone(),
+        unix_socket_mode: cli.unix_socket_mode,
+        slowlog_threshold: Some(Duration::from_micros(cli.slowlog_threshold_us)),
+        slowlog_max_len: cli.slowlog_max_len,
+        log_requests: cli.log_requests,
+        transaction_timeout: Some(Duration::from_millis(cli.transaction_timeout_ms))
+            .filter(|timeout| !timeout.is_zero()),
+        threads: cli.threads,
+    };
 
+    let dir = current_dir().map_err(|e| anyhow!(e))?;
     if cli.engine.eq("kvs") {
-        let engine = KVStore::new(&current_dir().map_err(|e| anyhow!(e))?)?;
-        Server::<KVStore>::new(engine)?.serve(cli.listen_addr).await
+        start(KVStore::new(&dir)?, cli, config, dir).await
     } else {
-        let engine = Sled::new(&current_dir().map_err(|e| anyhow!(e))?)?;
-        Server::<Sled>::new(engine)?.serve(cli.listen_addr).await
+        let sled_config = SledConfig {
+            sync_writes: cli.sync_writes,
+        };
+        start(Sled::with_config(&dir, sled_config)?, cli, config, dir).await
+    }
+}
+
+// wraps the engine in a raft node or starts the replication of a primary
+// if asked to
+async fn start<E: KvsEngine>(
+    engine: E,
+    cli: ServerCommand,
+    config: ServerConfig,
+    dir: PathBuf,
+) -> Result<()> {
+    if let Some(raft_addr) = cli.raft_addr {
+        let raft_config = RaftConfig {
+            addr: raft_addr,
+            peers: cli.raft_peers,
+            client_addr: cli.listen_addr.clone(),
+            dir,
+            snapshot_entries: cli.raft_snapshot_entries,
+            ..Default::default()
+        };
+        let engine = RaftEngine::start(engine, raft_config)?;
+        return run(
+            Server::with_config(engine, config)?,
+            cli.listen_addr,
+            None::<Replica<E>>,
+        )
+        .await;
+    }
+
+    // a replica serves the reads and redirects the writes to its primary
+    if let Some(primary) = cli.replica_of {
+        let replica = Replica::new(engine.clone(), primary, &dir)?;
+        let engine = ReadOnlyEngine::new(engine, replica.state());
+        let server = Server::with_config(engine, config)?;
+        server.stats().register_replica(replica.state());
+        return run(server, cli.listen_addr, Some(replica)).await;
+    }
+
+    run(
+        Server::with_config(engine, config)?,
+        cli.listen_addr,
+        None::<Replica<E>>,
+    )
+    .await
+}
+
+// serves until SIGINT or SIGTERM or until the replication stops on an
+// error, dropping the serve future cleans up the unix socket files
+async fn run<E: KvsEngine, R: KvsEngine>(
+    mut server: Server<E>,
+    addr: String,
+    replica: Option<Replica<R>>,
+) -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let replicate = async {
+        match replica {
+            Some(mut replica) => replica.run().await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        res = server.serve(addr) => res,
+        res = replicate => res,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+        _ = terminate.recv() => Ok(()),
     }
 }
@@ -0,0 +1,188 @@
+// a synchronous client for callers without a tokio runtime
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use crate::connection::UNIX_SCHEME;
+use serde::de::DeserializeOwned;
+
+use crate::client::{redirect_target, MAX_REDIRECTS};
+use crate::server::{AdminCommand, MultiResponse, Request, Response};
+
+trait BlockingStream: Read + Write + Send {
+    fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+}
+
+impl BlockingStream for TcpStream {
+    fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.set_read_timeout(timeout)?;
+        self.set_write_timeout(timeout)
+    }
+}
+
+impl BlockingStream for UnixStream {
+    fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.set_read_timeout(timeout)?;
+        self.set_write_timeout(timeout)
+    }
+}
+
+// Client speaks the same protocol as the async client, every call blocks
+// the current thread until the response arrives. Redirects are followed the
+// same way too.
+pub struct Client {
+    stream: BufReader<Box<dyn BlockingStream>>,
+    // the server connected to, which changes when a redirect is followed
+    addr: String,
+    timeout: Option<Duration>,
+    // An I/O or decode error may leave part of a response in the stream,
+    // which the next call would read as its own. The connection is opened
+    // again before it then.
+    broken: bool,
+}
+
+impl Client {
+    // addr is either host:port or unix:///path/to/socket
+    pub fn connect(addr: &str) -> Result<Client> {
+        Ok(Client {
+            stream: BufReader::new(open(addr)?),
+            addr: addr.to_string(),
+            timeout: None,
+            broken: false,
+        })
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    // timeout of reading and writing a request, none blocks forever
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.stream
+            .get_ref()
+            .set_timeout(timeout)
+            .map_err(|e| anyhow!(e))?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    pub fn get(&mut self, key: String) -> Result<String> {
+        self.request(Request::Get(key))
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<String> {
+        self.request(Request::Set(key, value))
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<String> {
+        self.request(Request::Remove(key))
+    }
+
+    pub fn info(&mut self) -> Result<String> {
+        self.request(Request::Info {})
+    }
+
+    pub fn admin(&mut self, token: Option<String>, command: AdminCommand) -> Result<String> {
+        self.request(Request::Admin { token, command })
+    }
+
+    pub fn slowlog(&mut self, count: Option<u32>, reset: bool) -> Result<String> {
+        self.request(Request::SlowLog { count, reset })
+    }
+
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let response: MultiResponse = self.send(Request::MGet(keys))?;
+        Ok(response.into_result()?.values)
+    }
+
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<Vec<Option<String>>> {
+        let response: MultiResponse = self.send(Request::MSet(pairs))?;
+        Ok(response.into_result()?.values)
+    }
+
+    pub fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let response: MultiResponse = self.send(Request::MDel(keys))?;
+        Ok(response.into_result()?.removed)
+    }
+
+    pub fn scan(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        limit: u32,
+    ) -> Result<Vec<(String, String)>> {
+        let request = Request::Scan {
+            prefix,
+            after,
+            limit,
+        };
+        let response: MultiResponse = self.send(request)?;
+        Ok(response.into_result()?.into_pairs())
+    }
+
+    fn request(&mut self, request: Request) -> Result<String> {
+        let response: Response = self.send(request)?;
+        Ok(response.response)
+    }
+
+    // a redirect moves the client to the server it names and sends the
+    // request again, with the timeout of the old connection
+    fn send<T: DeserializeOwned>(&mut self, request: Request) -> Result<T> {
+        let bytes = bson::to_vec(&request)?;
+        if self.broken {
+            self.connect_to(self.addr.clone())?;
+        }
+        let mut redirects = 0;
+        loop {
+            let document = self.exchange(&bytes).inspect_err(|_| self.broken = true)?;
+
+            match redirect_target(&document) {
+                Some(addr) if redirects < MAX_REDIRECTS && addr != self.addr => {
+                    log::debug!("redirected from {} to {}", self.addr, addr);
+                    self.connect_to(addr)?;
+                    redirects += 1;
+                }
+                _ => return Ok(bson::from_document(document)?),
+            }
+        }
+    }
+
+    // writes the request and reads the document of the response
+    fn exchange(&mut self, bytes: &[u8]) -> Result<bson::Document> {
+        let stream = self.stream.get_mut();
+        stream.write_all(bytes)?;
+        stream.flush()?;
+
+        match bson::from_reader::<_, bson::Document>(&mut self.stream) {
+            Ok(document) => Ok(document),
+            Err(bson::de::Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                Err(anyhow!("connection closed by server"))
+            }
+            Err(e) => Err(anyhow!(e)),
+        }
+    }
+
+    // opens a connection with the timeout of the old one
+    fn connect_to(&mut self, addr: String) -> Result<()> {
+        let stream = open(&addr)?;
+        stream.set_timeout(self.timeout)?;
+        self.stream = BufReader::new(stream);
+        self.addr = addr;
+        self.broken = false;
+        Ok(())
+    }
+}
+
+fn open(addr: &str) -> Result<Box<dyn BlockingStream>> {
+    Ok(match addr.strip_prefix(UNIX_SCHEME) {
+        Some(path) => Box::new(UnixStream::connect(path)?),
+        None => {
+            let stream = TcpStream::connect(addr)?;
+            stream.set_nodelay(true)?;
+            Box::new(stream)
+        }
+    })
+}
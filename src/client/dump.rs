@@ -0,0 +1,144 @@
+// the file formats written by `kvs_client export` and read by `kvs_client import`
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+// the binary format starts with this, followed by records of a little endian
+// u32 length and the bytes of the key, then the same for the value
+const BINARY_MAGIC: &[u8; 4] = b"KVS\x01";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    // one {"key": .., "value": ..} object per line
+    Json,
+    Binary,
+}
+
+impl FromStr for DumpFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(DumpFormat::Json),
+            "binary" => Ok(DumpFormat::Binary),
+            _ => Err(anyhow!("unknown format {}, expected json or binary", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonRecord {
+    key: String,
+    value: String,
+}
+
+pub struct DumpWriter<W: Write> {
+    format: DumpFormat,
+    writer: W,
+}
+
+impl<W: Write> DumpWriter<W> {
+    pub fn new(mut writer: W, format: DumpFormat) -> Result<Self> {
+        if format == DumpFormat::Binary {
+            writer.write_all(BINARY_MAGIC)?;
+        }
+        Ok(DumpWriter { format, writer })
+    }
+
+    pub fn write(&mut self, key: &str, value: &str) -> Result<()> {
+        match self.format {
+            DumpFormat::Json => {
+                let record = JsonRecord {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                };
+                serde_json::to_writer(&mut self.writer, &record)?;
+                self.writer.write_all(b"\n")?;
+            }
+            DumpFormat::Binary => {
+                for bytes in [key.as_bytes(), value.as_bytes()] {
+                    self.writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+                    self.writer.write_all(bytes)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // flushes and returns the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+// DumpReader iterates the pairs of a dump in file order.
+pub struct DumpReader<R: BufRead> {
+    format: DumpFormat,
+    reader: R,
+    line: String,
+}
+
+impl<R: BufRead> DumpReader<R> {
+    pub fn new(mut reader: R, format: DumpFormat) -> Result<Self> {
+        if format == DumpFormat::Binary {
+            let mut magic = [0; 4];
+            reader.read_exact(&mut magic)?;
+            if &magic != BINARY_MAGIC {
+                return Err(anyhow!("not a binary kvs dump"));
+            }
+        }
+        Ok(DumpReader {
+            format,
+            reader,
+            line: String::new(),
+        })
+    }
+
+    fn next_json(&mut self) -> Result<Option<(String, String)>> {
+        loop {
+            self.line.clear();
+            if self.reader.read_line(&mut self.line)? == 0 {
+                return Ok(None);
+            }
+            if !self.line.trim().is_empty() {
+                let record: JsonRecord = serde_json::from_str(&self.line)?;
+                return Ok(Some((record.key, record.value)));
+            }
+        }
+    }
+
+    fn next_binary(&mut self) -> Result<Option<(String, String)>> {
+        // the end of the file is only expected before a record
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let mut len = [0; 4];
+        self.reader.read_exact(&mut len)?;
+        let key = self.read_string(u32::from_le_bytes(len))?;
+
+        self.reader.read_exact(&mut len)?;
+        let value = self.read_string(u32::from_le_bytes(len))?;
+        Ok(Some((key, value)))
+    }
+
+    fn read_string(&mut self, len: u32) -> Result<String> {
+        let mut bytes = vec![0; len as usize];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(String::from_utf8(bytes)?)
+    }
+}
+
+impl<R: BufRead> Iterator for DumpReader<R> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = match self.format {
+            DumpFormat::Json => self.next_json(),
+            DumpFormat::Binary => self.next_binary(),
+        };
+        next.transpose()
+    }
+}
@@ -0,0 +1,194 @@
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+
+pub mod blocking;
+pub mod dump;
+pub mod pool;
+pub mod replicated;
+pub mod sharded;
+pub mod transaction;
+
+pub use pool::{ClientPool, PoolConfig};
+pub use replicated::ReplicatedClient;
+pub use sharded::ShardedClient;
+pub use transaction::ClientTransaction;
+
+use crate::connection::Connection;
+use crate::server::{AdminCommand, MultiResponse, Request, Response, ServerError, Status};
+use crate::watch::WatchEvent;
+
+// redirects followed by one request at most
+pub(crate) const MAX_REDIRECTS: usize = 3;
+
+pub struct Client {
+    connection: Connection,
+    // the server connected to, which changes when a redirect is followed
+    addr: String,
+    // starts of the transactions dropped while open, aborted before the
+    // next request
+    aborts: Vec<u64>,
+}
+
+impl Client {
+    // addr is either host:port or unix:///path/to/socket
+    pub async fn connect(addr: &str) -> Result<Client> {
+        Ok(Client {
+            connection: Connection::connect(addr).await?,
+            addr: addr.to_string(),
+            aborts: Vec::new(),
+        })
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    pub async fn get(&mut self, key: String) -> Result<String> {
+        self.request(Request::Get(key)).await
+    }
+
+    pub async fn set(&mut self, key: String, value: String) -> Result<String> {
+        self.request(Request::Set(key, value)).await
+    }
+
+    pub async fn remove(&mut self, key: String) -> Result<String> {
+        self.request(Request::Remove(key)).await
+    }
+
+    pub async fn info(&mut self) -> Result<String> {
+        self.request(Request::Info {}).await
+    }
+
+    pub async fn admin(&mut self, token: Option<String>, command: AdminCommand) -> Result<String> {
+        self.request(Request::Admin { token, command }).await
+    }
+
+    // the newest `count` slow log entries, all if none
+    pub async fn slowlog(&mut self, count: Option<u32>, reset: bool) -> Result<String> {
+        self.request(Request::SlowLog { count, reset }).await
+    }
+
+    // the value of every key, none if it is not found
+    pub async fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let response: MultiResponse = self.send(Request::MGet(keys)).await?;
+        Ok(response.into_result()?.values)
+    }
+
+    // the previous value of every key
+    pub async fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<Vec<Option<String>>> {
+        let response: MultiResponse = self.send(Request::MSet(pairs)).await?;
+        Ok(response.into_result()?.values)
+    }
+
+    // whether every key was found and removed
+    pub async fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let response: MultiResponse = self.send(Request::MDel(keys)).await?;
+        Ok(response.into_result()?.removed)
+    }
+
+    // a page of the pairs whose keys start with prefix, in key order after
+    // the key `after`
+    pub async fn scan(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        limit: u32,
+    ) -> Result<Vec<(String, String)>> {
+        let request = Request::Scan {
+            prefix,
+            after,
+            limit,
+        };
+        let response: MultiResponse = self.send(request).await?;
+        Ok(response.into_result()?.into_pairs())
+    }
+
+    // starts a transaction on the server, which runs on this connection
+    pub async fn begin(&mut self) -> Result<ClientTransaction<'_>> {
+        ClientTransaction::begin(self).await
+    }
+
+    pub(crate) async fn request(&mut self, request: Request) -> Result<String> {
+        Ok(self.call(request).await?.response)
+    }
+
+    // sends a single-key, info, admin or slowlog request and returns the
+    // response with its status, a Redirect status is only returned if no
+    // server to follow it to is known
+    pub async fn call(&mut self, request: Request) -> Result<Response> {
+        self.send(request).await
+    }
+
+    // The response type depends on the request. A redirect moves the client
+    // to the server it names and sends the request again.
+    pub(crate) async fn send<T: DeserializeOwned>(&mut self, request: Request) -> Result<T> {
+        // the server only tells that the transaction is gone if it timed out
+        while let Some(start) = self.aborts.pop() {
+            self.connection.write(Request::Abort(start)).await?;
+            if self.connection.read::<Response>().await?.is_none() {
+                return Err(anyhow!("connection closed by server"));
+            }
+        }
+
+        let mut redirects = 0;
+        loop {
+            self.connection.write(&request).await?;
+            let document: bson::Document = match self.connection.read().await? {
+                Some(v) => v,
+                None => return Err(anyhow!("connection closed by server")),
+            };
+
+            match redirect_target(&document) {
+                Some(addr) if redirects < MAX_REDIRECTS && addr != self.addr => {
+                    log::debug!("redirected from {} to {}", self.addr, addr);
+                    self.connection = Connection::connect(&addr).await?;
+                    self.addr = addr;
+                    redirects += 1;
+                }
+                _ => return Ok(bson::from_document(document)?),
+            }
+        }
+    }
+
+    // the connection is dedicated to the watch, so the client is consumed
+    pub async fn watch(mut self, prefix: String) -> Result<WatchStream> {
+        self.connection.write(Request::Watch(prefix)).await?;
+        let response: Option<Response> = self.connection.read().await?;
+        match response {
+            Some(v) if v.status == Status::Ok => Ok(WatchStream {
+                connection: self.connection,
+            }),
+            Some(v) => Err(anyhow!(v.response)),
+            None => Err(anyhow!("connection closed by server")),
+        }
+    }
+}
+
+// the server named by a Response or MultiResponse redirect
+pub(crate) fn redirect_target(document: &bson::Document) -> Option<String> {
+    let addr = match document.get_str("status") {
+        Ok("Redirect") => document.get_str("response").ok(),
+        _ => document.get_str("redirect").ok(),
+    };
+    addr.filter(|addr| !addr.is_empty()).map(str::to_string)
+}
+
+pub struct WatchStream {
+    connection: Connection,
+}
+
+impl WatchStream {
+    // Returns none when the server closes the stream. It fails if the server
+    // ends it with an error, as it does when the watch fell behind.
+    pub async fn next(&mut self) -> Result<Option<WatchEvent>> {
+        let document: bson::Document = match self.connection.read().await? {
+            Some(document) => document,
+            None => return Ok(None),
+        };
+        if document.contains_key("status") {
+            let response: Response = bson::from_document(document)?;
+            return Err(ServerError(response.response).into());
+        }
+        Ok(Some(bson::from_document(document)?))
+    }
+}
@@ -0,0 +1,200 @@
+// a pool of clients shared by many tasks
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use tokio::sync::Semaphore;
+use tokio::time::{sleep, timeout};
+
+use crate::client::Client;
+use crate::server::{MultiResponse, Request, Response};
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    // maximum number of connections, requests wait for a free one
+    pub size: usize,
+    // timeout of connecting and of every request
+    pub timeout: Duration,
+    // attempts after the first one, idempotent requests are retried on any
+    // error and the others only when they could not be sent
+    pub max_retries: u32,
+    // first reconnect delay, doubled on every attempt up to backoff_max
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            size: 8,
+            timeout: Duration::from_secs(5),
+            max_retries: 3,
+            backoff_base: Duration::from_millis(50),
+            backoff_max: Duration::from_secs(2),
+        }
+    }
+}
+
+// ClientPool keeps up to `size` connections to one server. Connections are
+// opened lazily and dropped after an error, the next request reconnects.
+#[derive(Clone)]
+pub struct ClientPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    addr: String,
+    config: PoolConfig,
+    idle: Mutex<Vec<Client>>,
+    permits: Semaphore,
+}
+
+impl ClientPool {
+    pub fn new(addr: &str, config: PoolConfig) -> ClientPool {
+        ClientPool {
+            inner: Arc::new(PoolInner {
+                addr: addr.to_string(),
+                permits: Semaphore::new(config.size),
+                idle: Mutex::new(Vec::with_capacity(config.size)),
+                config,
+            }),
+        }
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.inner.addr
+    }
+
+    pub async fn get(&self, key: String) -> Result<String> {
+        self.execute(Request::Get(key)).await
+    }
+
+    pub async fn set(&self, key: String, value: String) -> Result<String> {
+        self.execute(Request::Set(key, value)).await
+    }
+
+    pub async fn remove(&self, key: String) -> Result<String> {
+        self.execute(Request::Remove(key)).await
+    }
+
+    pub async fn info(&self) -> Result<String> {
+        self.execute(Request::Info {}).await
+    }
+
+    pub async fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let response: MultiResponse = self.send(Request::MGet(keys)).await?;
+        Ok(response.into_result()?.values)
+    }
+
+    pub async fn set_many(&self, pairs: Vec<(String, String)>) -> Result<Vec<Option<String>>> {
+        let response: MultiResponse = self.send(Request::MSet(pairs)).await?;
+        Ok(response.into_result()?.values)
+    }
+
+    pub async fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let response: MultiResponse = self.send(Request::MDel(keys)).await?;
+        Ok(response.into_result()?.removed)
+    }
+
+    pub async fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: u32,
+    ) -> Result<Vec<(String, String)>> {
+        let request = Request::Scan {
+            prefix,
+            after,
+            limit,
+        };
+        let response: MultiResponse = self.send(request).await?;
+        Ok(response.into_result()?.into_pairs())
+    }
+
+    // connections currently idle in the pool
+    pub fn idle(&self) -> usize {
+        self.inner.idle.lock().map_or(0, |idle| idle.len())
+    }
+
+    pub(crate) async fn execute(&self, request: Request) -> Result<String> {
+        let response: Response = self.send(request).await?;
+        Ok(response.response)
+    }
+
+    pub(crate) async fn send<T: DeserializeOwned>(&self, request: Request) -> Result<T> {
+        let config = &self.inner.config;
+        let _permit = self.inner.permits.acquire().await?;
+
+        let mut attempt = 0;
+        loop {
+            let err = match self.checkout().await {
+                Ok(mut client) => {
+                    match timeout(config.timeout, client.send::<T>(request.clone())).await {
+                        Ok(Ok(response)) => {
+                            self.checkin(client);
+                            return Ok(response);
+                        }
+                        Ok(Err(e)) => self.discard(&request, e)?,
+                        Err(_) => self.discard(&request, anyhow!("request timed out"))?,
+                    }
+                }
+                Err(e) => e,
+            };
+
+            if attempt >= config.max_retries {
+                return Err(err);
+            }
+            log::warn!("request to {} failed, retrying: {}", self.inner.addr, err);
+            sleep(self.backoff(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    async fn checkout(&self) -> Result<Client> {
+        let idle = self.inner.idle.lock().ok().and_then(|mut idle| idle.pop());
+        match idle {
+            Some(client) => Ok(client),
+            None => timeout(self.inner.config.timeout, Client::connect(&self.inner.addr))
+                .await
+                .map_err(|_| anyhow!("connect to {} timed out", self.inner.addr))?,
+        }
+    }
+
+    // After a failed request the idle connections are likely broken as well,
+    // e.g. when the server restarted, so they are dropped. The error is
+    // returned if the request must not be retried.
+    fn discard(&self, request: &Request, err: anyhow::Error) -> Result<anyhow::Error> {
+        if let Ok(mut idle) = self.inner.idle.lock() {
+            idle.clear();
+        }
+        if request.is_idempotent() {
+            Ok(err)
+        } else {
+            Err(err)
+        }
+    }
+
+    // a client that followed a redirect talks to another server than the
+    // pool's, it is closed instead of being reused
+    fn checkin(&self, client: Client) {
+        if client.addr() != self.inner.addr {
+            return;
+        }
+        if let Ok(mut idle) = self.inner.idle.lock() {
+            idle.push(client);
+        }
+    }
+
+    // exponential backoff with up to 50% jitter
+    fn backoff(&self, attempt: u32) -> Duration {
+        let config = &self.inner.config;
+        let delay = config
+            .backoff_base
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(config.backoff_max);
+        let jitter = rand::thread_rng().gen_range(0..=delay.as_millis() as u64 / 2);
+        delay + Duration::from_millis(jitter)
+    }
+}
@@ -0,0 +1,126 @@
+// reads from read-only replicas, writes to their primary
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+
+use crate::client::Client;
+use crate::server::{MultiResponse, Request, Response};
+
+// ReplicatedClient sends the writes to the primary and spreads the reads over
+// the replicas in turn. A read falls back to the primary when no replica can
+// be reached, and a replica more stale than the bound redirects it there.
+pub struct ReplicatedClient {
+    primary: Client,
+    // none until connected, after a broken connection or a redirect the
+    // client is connected to the replica again for the next read
+    replicas: Vec<(String, Option<Client>)>,
+    next: usize,
+    max_staleness: Option<Duration>,
+}
+
+impl ReplicatedClient {
+    // replicas that cannot be reached yet are tried again on later reads
+    pub async fn connect(primary: &str, replicas: &[&str]) -> Result<ReplicatedClient> {
+        let mut client = ReplicatedClient {
+            primary: Client::connect(primary).await?,
+            replicas: replicas
+                .iter()
+                .map(|addr| (addr.to_string(), None))
+                .collect(),
+            next: 0,
+            max_staleness: None,
+        };
+        for i in 0..client.replicas.len() {
+            client.replica(i).await;
+        }
+        Ok(client)
+    }
+
+    // the staleness the reads accept, any if none
+    pub fn set_max_staleness(&mut self, max_staleness: Option<Duration>) {
+        self.max_staleness = max_staleness;
+    }
+
+    pub async fn get(&mut self, key: String) -> Result<String> {
+        let response: Response = self.read(Request::Get(key)).await?;
+        Ok(response.response)
+    }
+
+    pub async fn set(&mut self, key: String, value: String) -> Result<String> {
+        self.primary.set(key, value).await
+    }
+
+    pub async fn remove(&mut self, key: String) -> Result<String> {
+        self.primary.remove(key).await
+    }
+
+    pub async fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let response: MultiResponse = self.read(Request::MGet(keys)).await?;
+        Ok(response.into_result()?.values)
+    }
+
+    pub async fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<Vec<Option<String>>> {
+        self.primary.set_many(pairs).await
+    }
+
+    pub async fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        self.primary.remove_many(keys).await
+    }
+
+    pub async fn scan(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        limit: u32,
+    ) -> Result<Vec<(String, String)>> {
+        let request = Request::Scan {
+            prefix,
+            after,
+            limit,
+        };
+        let response: MultiResponse = self.read(request).await?;
+        Ok(response.into_result()?.into_pairs())
+    }
+
+    async fn read<T: DeserializeOwned>(&mut self, request: Request) -> Result<T> {
+        let request = match self.max_staleness {
+            Some(max_staleness) => Request::WithStaleness {
+                max_staleness_ms: max_staleness.as_millis() as u64,
+                request: Box::new(request),
+            },
+            None => request,
+        };
+
+        for _ in 0..self.replicas.len() {
+            let i = self.next;
+            self.next = (i + 1) % self.replicas.len();
+            let Some(replica) = self.replica(i).await else {
+                continue;
+            };
+            match replica.send(request.clone()).await {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    log::debug!("read from {} has error {}", self.replicas[i].0, e);
+                    self.replicas[i].1 = None;
+                }
+            }
+        }
+        self.primary.send(request).await
+    }
+
+    // the client of a replica, connected again if it is not connected to it
+    async fn replica(&mut self, i: usize) -> Option<&mut Client> {
+        let (addr, client) = &mut self.replicas[i];
+        if client.as_ref().is_none_or(|client| client.addr() != addr) {
+            *client = match Client::connect(addr).await {
+                Ok(client) => Some(client),
+                Err(e) => {
+                    log::debug!("connecting {} has error {}", addr, e);
+                    None
+                }
+            };
+        }
+        client.as_mut()
+    }
+}
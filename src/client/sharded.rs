@@ -0,0 +1,317 @@
+// client-side sharding of the keys over several servers
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+
+use crate::client::Client;
+use crate::server::{Request, Status};
+
+// points of every server on the ring, more spread the keys more evenly
+pub const DEFAULT_VIRTUAL_NODES: usize = 160;
+
+// pairs scanned per request while looking for the keys to migrate
+const MIGRATION_SCAN_LIMIT: u32 = 1000;
+
+// 64-bit FNV-1a. The hash decides where keys live, so it must be the same in
+// every process and version, unlike the std hasher.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+// FNV spreads strings differing only in the last bytes poorly, e.g. the
+// virtual nodes `addr#1`, `addr#2`, so the hash is finalized like murmur3
+fn ring_hash(bytes: &[u8]) -> u64 {
+    let mut hash = fnv1a(bytes);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+// HashRing maps keys to nodes with consistent hashing, adding a node only
+// moves the keys that the new node takes over.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    virtual_nodes: usize,
+    ring: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    pub fn new(virtual_nodes: usize) -> Self {
+        HashRing {
+            virtual_nodes: virtual_nodes.max(1),
+            ring: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, node: &str) {
+        for i in 0..self.virtual_nodes {
+            let point = ring_hash(format!("{}#{}", node, i).as_bytes());
+            self.ring.insert(point, node.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, node: &str) {
+        self.ring.retain(|_, n| n != node);
+    }
+
+    // the first node clockwise from the hash of the key
+    pub fn node(&self, key: &str) -> Option<&str> {
+        let hash = ring_hash(key.as_bytes());
+        self.ring
+            .range(hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+}
+
+// a key whose node changed after add_node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    pub key: String,
+    pub from: String,
+    pub to: String,
+}
+
+// ShardedClient routes every key to one of several servers with a Client per
+// server. Requests of many keys are split by server and sent at the same time.
+pub struct ShardedClient {
+    ring: HashRing,
+    clients: HashMap<String, Arc<Mutex<Client>>>,
+}
+
+impl ShardedClient {
+    pub async fn connect(addrs: &[&str]) -> Result<ShardedClient> {
+        Self::with_virtual_nodes(addrs, DEFAULT_VIRTUAL_NODES).await
+    }
+
+    pub async fn with_virtual_nodes(addrs: &[&str], virtual_nodes: usize) -> Result<ShardedClient> {
+        if addrs.is_empty() {
+            return Err(anyhow!("no server address"));
+        }
+
+        let mut client = ShardedClient {
+            ring: HashRing::new(virtual_nodes),
+            clients: HashMap::new(),
+        };
+        for addr in addrs {
+            let connection = Client::connect(addr).await?;
+            client.ring.add(addr);
+            client
+                .clients
+                .insert(addr.to_string(), Arc::new(Mutex::new(connection)));
+        }
+        Ok(client)
+    }
+
+    // addresses of the servers, sorted
+    pub fn nodes(&self) -> Vec<&str> {
+        let mut nodes: Vec<&str> = self.clients.keys().map(|addr| addr.as_str()).collect();
+        nodes.sort_unstable();
+        nodes
+    }
+
+    pub fn node_for(&self, key: &str) -> &str {
+        // the ring has a point of every client
+        self.ring.node(key).unwrap_or_default()
+    }
+
+    fn client_for(&self, key: &str) -> Arc<Mutex<Client>> {
+        self.clients[self.node_for(key)].clone()
+    }
+
+    pub async fn get(&self, key: String) -> Result<String> {
+        self.client_for(&key).lock().await.get(key).await
+    }
+
+    pub async fn set(&self, key: String, value: String) -> Result<String> {
+        self.client_for(&key).lock().await.set(key, value).await
+    }
+
+    pub async fn remove(&self, key: String) -> Result<String> {
+        self.client_for(&key).lock().await.remove(key).await
+    }
+
+    pub async fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.fan_out(
+            keys,
+            |key| key,
+            |client, keys| async move { client.lock().await.get_many(keys).await },
+        )
+        .await
+    }
+
+    pub async fn set_many(&self, pairs: Vec<(String, String)>) -> Result<Vec<Option<String>>> {
+        self.fan_out(
+            pairs,
+            |(key, _)| key,
+            |client, pairs| async move { client.lock().await.set_many(pairs).await },
+        )
+        .await
+    }
+
+    pub async fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        self.fan_out(
+            keys,
+            |key| key,
+            |client, keys| async move { client.lock().await.remove_many(keys).await },
+        )
+        .await
+    }
+
+    // Every server returns its first `limit` pairs, the first `limit` of all
+    // servers are among them.
+    pub async fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: u32,
+    ) -> Result<Vec<(String, String)>> {
+        let mut scans = JoinSet::new();
+        for client in self.clients.values() {
+            let (client, prefix, after) = (client.clone(), prefix.clone(), after.clone());
+            scans.spawn(async move { client.lock().await.scan(prefix, after, limit).await });
+        }
+
+        let mut pairs = Vec::new();
+        while let Some(res) = scans.join_next().await {
+            pairs.extend(res??);
+        }
+        pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        pairs.truncate(limit as usize);
+        Ok(pairs)
+    }
+
+    // Adds a server to the ring and returns the keys of the other servers
+    // that belong to it now. Until they are migrated, reads of those keys
+    // miss on the new server.
+    pub async fn add_node(&mut self, addr: &str) -> Result<Vec<Migration>> {
+        if self.clients.contains_key(addr) {
+            return Err(anyhow!("{} is already a node", addr));
+        }
+        let connection = Client::connect(addr).await?;
+
+        let mut ring = self.ring.clone();
+        ring.add(addr);
+
+        let mut migrations = Vec::new();
+        for (from, client) in self.clients.iter() {
+            let mut client = client.lock().await;
+            let mut after = None;
+            loop {
+                let pairs = client
+                    .scan(String::new(), after.take(), MIGRATION_SCAN_LIMIT)
+                    .await?;
+                for (key, _) in pairs.iter() {
+                    let to = ring.node(key).unwrap_or_default();
+                    if to != from {
+                        migrations.push(Migration {
+                            key: key.clone(),
+                            from: from.clone(),
+                            to: to.to_string(),
+                        });
+                    }
+                }
+                match pairs.into_iter().last() {
+                    Some((key, _)) => after = Some(key),
+                    None => break,
+                }
+            }
+        }
+
+        self.ring = ring;
+        self.clients
+            .insert(addr.to_string(), Arc::new(Mutex::new(connection)));
+        migrations.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        Ok(migrations)
+    }
+
+    // copies every key to its new server and removes it from the old one,
+    // keys removed in the meantime are skipped
+    pub async fn migrate(&self, migrations: &[Migration]) -> Result<()> {
+        for migration in migrations {
+            let from = self.node_client(&migration.from)?;
+            let to = self.node_client(&migration.to)?;
+
+            let response = from
+                .lock()
+                .await
+                .call(Request::Get(migration.key.clone()))
+                .await?;
+            match response.status {
+                Status::Ok => {}
+                Status::NotFound => continue,
+                Status::Error => return Err(anyhow!(response.response)),
+                Status::Redirect => return Err(anyhow!("{} has no leader", migration.from)),
+                Status::Conflict => return Err(anyhow!(response.response)),
+            }
+
+            to.lock()
+                .await
+                .set(migration.key.clone(), response.response)
+                .await?;
+            from.lock().await.remove(migration.key.clone()).await?;
+        }
+        Ok(())
+    }
+
+    fn node_client(&self, addr: &str) -> Result<Arc<Mutex<Client>>> {
+        self.clients
+            .get(addr)
+            .cloned()
+            .ok_or_else(|| anyhow!("{} is not a node", addr))
+    }
+
+    // Splits the items by server, sends one request per server at the same
+    // time and returns the results in the order of the items.
+    async fn fan_out<I, T, K, F, Fut>(&self, items: Vec<I>, key: K, request: F) -> Result<Vec<T>>
+    where
+        I: Send + 'static,
+        T: Send + 'static,
+        K: Fn(&I) -> &String,
+        F: Fn(Arc<Mutex<Client>>, Vec<I>) -> Fut,
+        Fut: Future<Output = Result<Vec<T>>> + Send + 'static,
+    {
+        let len = items.len();
+        let mut groups: HashMap<&str, (Vec<usize>, Vec<I>)> = HashMap::new();
+        for (index, item) in items.into_iter().enumerate() {
+            let node = self.node_for(key(&item));
+            let group = groups.entry(node).or_default();
+            group.0.push(index);
+            group.1.push(item);
+        }
+
+        let mut requests = JoinSet::new();
+        for (node, (indexes, items)) in groups {
+            let response = request(self.clients[node].clone(), items);
+            requests.spawn(async move { response.await.map(|results| (indexes, results)) });
+        }
+
+        let mut results: Vec<Option<T>> = (0..len).map(|_| None).collect();
+        while let Some(res) = requests.join_next().await {
+            let (indexes, values) = res??;
+            if indexes.len() != values.len() {
+                return Err(anyhow!(
+                    "server returned {} results for {} keys",
+                    values.len(),
+                    indexes.len()
+                ));
+            }
+            for (index, value) in indexes.into_iter().zip(values) {
+                results[index] = Some(value);
+            }
+        }
+        Ok(results.into_iter().flatten().collect())
+    }
+}
@@ -0,0 +1,93 @@
+// transactions run over the connection of a client
+use anyhow::Result;
+
+use crate::client::Client;
+use crate::engine::Conflict;
+use crate::server::{Request, ServerError, Status};
+use crate::transaction::Buffer;
+
+// ClientTransaction reads from the server it began on and buffers the writes
+// until the commit, like Transaction does with an engine. A transaction
+// dropped without commit or abort is aborted before the next request of the
+// client.
+pub struct ClientTransaction<'a> {
+    client: &'a mut Client,
+    start: u64,
+    buffer: Buffer,
+    // set once the server answered a commit or abort, an abort sent again
+    // for a transaction ended already is answered with an error only
+    ended: bool,
+}
+
+impl<'a> ClientTransaction<'a> {
+    pub(crate) async fn begin(client: &'a mut Client) -> Result<ClientTransaction<'a>> {
+        let response = client.call(Request::Begin {}).await?;
+        if response.status != Status::Ok {
+            return Err(ServerError(response.response).into());
+        }
+        Ok(ClientTransaction {
+            start: response.response.parse()?,
+            client,
+            buffer: Buffer::default(),
+            ended: false,
+        })
+    }
+
+    // the sequence number of the last write the transaction may have seen
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    // the value of the key, none if it is not found or removed by the
+    // transaction
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.buffer.get(&key) {
+            return Ok(value);
+        }
+        let response = self.client.call(Request::Get(key.clone())).await?;
+        let value = match response.status {
+            Status::Ok => Some(response.response),
+            Status::NotFound => None,
+            _ => return Err(ServerError(response.response).into()),
+        };
+        self.buffer.read(key, value.clone());
+        Ok(value)
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.buffer.write(key, Some(value));
+    }
+
+    pub fn remove(&mut self, key: String) {
+        self.buffer.write(key, None);
+    }
+
+    // fails with Conflict if a key read was written since the transaction began
+    pub async fn commit(mut self) -> Result<()> {
+        let commit = std::mem::take(&mut self.buffer).into_commit(self.start);
+        let response = self.client.call(Request::Commit(commit)).await?;
+        self.ended = true;
+        match response.status {
+            Status::Ok => Ok(()),
+            Status::Conflict => Err(Conflict.into()),
+            _ => Err(ServerError(response.response).into()),
+        }
+    }
+
+    pub async fn abort(mut self) -> Result<()> {
+        let response = self.client.call(Request::Abort(self.start)).await?;
+        self.ended = true;
+        match response.status {
+            Status::Ok => Ok(()),
+            _ => Err(ServerError(response.response).into()),
+        }
+    }
+}
+
+impl Drop for ClientTransaction<'_> {
+    fn drop(&mut self) {
+        if !self.ended {
+            self.client.aborts.push(self.start);
+        }
+    }
+}
@@ -5,17 +5,37 @@ use anyhow::{anyhow, Result};
 use bytes::{Buf, BytesMut};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpStream, UnixStream};
 
-pub(crate) struct Connection {
-    stream: BufWriter<TcpStream>,
+// prefix of the addresses of unix domain sockets, e.g. unix:///tmp/kvs.sock
+pub const UNIX_SCHEME: &str = "unix://";
+
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub(crate) type BoxedStream = Box<dyn Stream>;
+
+pub(crate) struct Connection<S: Stream = BoxedStream> {
+    stream: BufWriter<S>,
 
     buf: BytesMut,
 }
 
 impl Connection {
-    pub fn new(stream: TcpStream) -> Connection {
+    // connects to a tcp address or to a unix:// socket path
+    pub async fn connect(addr: &str) -> Result<Connection> {
+        let stream: BoxedStream = match addr.strip_prefix(UNIX_SCHEME) {
+            Some(path) => Box::new(UnixStream::connect(path).await?),
+            None => Box::new(TcpStream::connect(addr).await?),
+        };
+        Ok(Connection::new(stream))
+    }
+}
+
+impl<S: Stream> Connection<S> {
+    pub fn new(stream: S) -> Connection<S> {
         Connection {
             stream: BufWriter::new(stream),
             buf: BytesMut::with_capacity(1024 * 4),
@@ -58,7 +78,7 @@ impl Connection {
     pub async fn write<T: Serialize>(&mut self, info: T) -> Result<()> {
         let resp_bz = bson::to_vec(&info)?;
 
-        self.stream.write(&resp_bz).await?;
+        self.stream.write_all(&resp_bz).await?;
 
         self.stream.flush().await.map_err(|e| anyhow!(e))
     }
@@ -1,4 +1,67 @@
-use anyhow::Result;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use crate::kvs::Transaction;
+use crate::replication::LogCursor;
+use crate::transaction::Commit;
+use crate::watch::Watcher;
+
+// the error of get and remove when the key does not exist
+#[derive(Debug)]
+pub struct KeyNotFound;
+
+impl fmt::Display for KeyNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Key not found")
+    }
+}
+
+impl std::error::Error for KeyNotFound {}
+
+// the error of a request that only the leader serves, with the address
+// clients reach the leader at if it is known
+#[derive(Debug)]
+pub struct NotLeader(pub Option<String>);
+
+impl fmt::Display for NotLeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some(leader) => write!(f, "not the leader, the leader is {}", leader),
+            None => write!(f, "not the leader, no leader is known"),
+        }
+    }
+}
+
+impl std::error::Error for NotLeader {}
+
+// the error of a commit when a key the transaction read was written since
+// it began, the transaction can be run again
+#[derive(Debug)]
+pub struct Conflict;
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "transaction conflicts with a concurrent write")
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+// the error of a read view or a transaction kept open for longer than the
+// versions are retained, it has to be taken again
+#[derive(Debug)]
+pub struct SnapshotExpired;
+
+impl fmt::Display for SnapshotExpired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "snapshot expired")
+    }
+}
+
+impl std::error::Error for SnapshotExpired {}
 
 pub trait KvsEngine: Clone + Send + 'static {
     fn get(&self, key: String) -> Result<String>;
@@ -6,4 +69,116 @@ pub trait KvsEngine: Clone + Send + 'static {
     fn set(&self, key: String, value: String) -> Result<Option<String>>;
 
     fn remove(&self, key: String) -> Result<()>;
+
+    // the value of every key, none if it is not found
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter()
+            .map(|key| match self.get(key) {
+                Ok(value) => Ok(Some(value)),
+                Err(e) if e.is::<KeyNotFound>() => Ok(None),
+                Err(e) => Err(e),
+            })
+            .collect()
+    }
+
+    // the previous value of every key
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<Vec<Option<String>>> {
+        pairs
+            .into_iter()
+            .map(|(key, value)| self.set(key, value))
+            .collect()
+    }
+
+    // whether every key was found and removed
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        keys.into_iter()
+            .map(|key| match self.remove(key) {
+                Ok(()) => Ok(true),
+                Err(e) if e.is::<KeyNotFound>() => Ok(false),
+                Err(e) => Err(e),
+            })
+            .collect()
+    }
+
+    // Up to `limit` pairs whose keys start with prefix, in key order and after
+    // the key `after`. Passing the last key back fetches the next page.
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>>;
+
+    fn stats(&self) -> Result<EngineStats>;
+
+    // reclaims the disk space of overwritten and removed values
+    fn compact(&self) -> Result<()>;
+
+    // makes every acknowledged write durable
+    fn flush(&self) -> Result<()>;
+
+    // writes a copy of the data that the same engine can be opened from at `path`
+    fn snapshot(&self, path: &Path) -> Result<()>;
+
+    // subscribes to the sets and removes of the keys starting with `prefix`
+    fn watch(&self, prefix: String) -> Result<Watcher>;
+
+    // a read view of the data as of now, see ReadView
+    fn read_view(&self) -> Result<Box<dyn ReadView>> {
+        Err(anyhow!("the engine does not support read views"))
+    }
+
+    // Up to `limit` records of the log after the cursor and the cursor after
+    // the last of them, for the replicas. Fails with CursorGone if the log of
+    // the cursor was compacted away.
+    fn read_log(&self, _cursor: LogCursor, _limit: usize) -> Result<(Vec<Transaction>, LogCursor)> {
+        Err(anyhow!("the engine does not support replication"))
+    }
+
+    // every pair and the cursor of the log right after them
+    fn log_snapshot(&self) -> Result<(Vec<(String, String)>, LogCursor)> {
+        Err(anyhow!("the engine does not support replication"))
+    }
+
+    // Fails if the data may be more than max_staleness behind the primary it
+    // is replicated from, the data of a primary is never stale.
+    fn ensure_fresh(&self, _max_staleness: Duration) -> Result<()> {
+        Ok(())
+    }
+
+    // Starts a transaction and returns the sequence number of the last write
+    // before it, the engine keeps what it needs to check the reads of the
+    // transaction until it is committed or aborted.
+    fn begin_transaction(&self) -> Result<u64> {
+        Err(anyhow!("the engine does not support transactions"))
+    }
+
+    // Applies the writes at once if no key the transaction read was written
+    // since it began, fails with Conflict otherwise. Ends the transaction.
+    fn commit_transaction(&self, _commit: Commit) -> Result<()> {
+        Err(anyhow!("the engine does not support transactions"))
+    }
+
+    // ends a transaction without applying it
+    fn abort_transaction(&self, _start: u64) {}
+}
+
+// ReadView reads the data as it was when the view was taken, the engine
+// keeps the values it sees until it is dropped.
+pub trait ReadView: Send {
+    fn get(&self, key: String) -> Result<String>;
+
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>>;
+}
+
+// EngineStats is a point-in-time view of the storage engine.
+#[derive(Debug, Default, Clone)]
+pub struct EngineStats {
+    pub keys: u64,
+    pub disk_usage: u64,
 }
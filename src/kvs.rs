@@ -1,89 +1,127 @@
 // kv store
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
     fs::{self, File},
     io::{self, BufReader, BufWriter, Read, Seek, SeekFrom::Start, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicU32, AtomicU64, Ordering},
         Arc, Mutex, RwLock,
     },
+    time::Duration,
     vec,
 };
 
 use anyhow::{anyhow, Ok, Result};
 use serde::{Deserialize, Serialize};
 
-use crate::engine::KvsEngine;
+use crate::engine::{Conflict, EngineStats, KeyNotFound, KvsEngine, ReadView};
+use crate::replication::{CursorGone, LogCursor};
+use crate::transaction::Commit;
+use crate::versions::Versions;
+use crate::watch::{WatchEvent, WatchRegistry, Watcher};
 
 #[derive(Clone)]
 pub struct KVStore {
     path: PathBuf,
 
     max_reader_id: Arc<AtomicU32>,
+    // the logs of the last compaction, none before the first one
+    compaction: Arc<RwLock<Option<Compaction>>>,
     readers: Arc<RwLock<HashMap<u32, BufferReader<File>>>>,
 
     writer: Arc<Mutex<BufferWriter<File>>>,
     index: Arc<RwLock<HashMap<String, TransactionPosition>>>,
+
+    // sequence number of the last write, assigned under the writer lock
+    seq: Arc<AtomicU64>,
+    // the positions of the values overwritten since the oldest live snapshot
+    versions: Arc<Versions<TransactionPosition>>,
+    watchers: WatchRegistry,
 }
 
 const LOG_MAX_SIZE: u32 = 1024 * 1024 * 24;
 
+// name of the file keeping the logs of the last compaction, next to the
+// directory of the logs so that only logs are in it
+const COMPACTED_FILE: &str = "db.compacted";
+
+// The records of the logs before the last compaction are copied into the
+// compacted log out of order, so the cursors in them are gone. The log that
+// was active is kept and sealed, a cursor at its end goes on with the log
+// after the compacted one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct Compaction {
+    sealed: LogCursor,
+    log_id: u32,
+}
+
+impl Compaction {
+    // The sealed log is removed by the next compaction, if nothing was
+    // written in between it keeps the end of the sealed log of this one.
+    fn gone(&self, cursor: LogCursor) -> bool {
+        let sealed_kept = self.sealed.log_id + 1 == self.log_id;
+        cursor.log_id < self.log_id
+            && cursor != self.sealed
+            && !(sealed_kept && cursor.log_id == self.sealed.log_id)
+    }
+}
+
 impl KVStore {
-    pub fn new(root_path: &PathBuf) -> Result<Self> {
+    pub fn new(root_path: &Path) -> Result<Self> {
         let path = root_path.join("db");
         if !path.exists() {
             fs::create_dir(&path)?;
         }
 
-        let mut files = fs::read_dir(&path)?
+        let mut log_ids = fs::read_dir(&path)?
             .map(|res| res.map(|e| e.path()))
-            .collect::<Result<Vec<_>, std::io::Error>>()?;
-        files.sort();
-
-        if files.is_empty() {
-            files.insert(0, path.join("0.log").to_path_buf());
+            .collect::<Result<Vec<_>, std::io::Error>>()?
+            .iter()
+            .filter_map(|p| parse_log_id(p))
+            .collect::<Vec<_>>();
+        log_ids.sort_unstable();
+
+        if log_ids.is_empty() {
+            log_ids.push(0);
         }
 
-        let active_file = File::options()
-            .create(true)
-            .read(true)
-            .append(true)
-            .open(files.last().ok_or(anyhow!("inner file system error"))?)?;
-
-        let writer = BufferWriter::<File> {
-            writer: BufWriter::new(active_file),
-            pos: 0,
-        };
+        let active_log_id = *log_ids.last().ok_or(anyhow!("inner file system error"))?;
+        let writer = new_log_writer(active_log_id, &path)?;
 
         let mut readers: HashMap<u32, BufferReader<File>> = HashMap::new();
-        for (i, fp) in files.iter().enumerate() {
-            readers.insert(
-                i as u32,
-                BufferReader::<File> {
-                    inner: BufReader::new(File::open(fp)?),
-                },
-            );
+        for id in log_ids.iter() {
+            readers.insert(*id, new_log_reader(*id, &path)?);
         }
 
+        let compaction = match fs::read(root_path.join(COMPACTED_FILE)) {
+            std::io::Result::Ok(bytes) => Some(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
         let mut kv_store: KVStore = KVStore {
             path: path.clone(),
-            max_reader_id: Arc::new(AtomicU32::new((files.len() - 1) as u32)),
+            max_reader_id: Arc::new(AtomicU32::new(active_log_id)),
+            compaction: Arc::new(RwLock::new(compaction)),
             readers: Arc::new(RwLock::new(readers)),
             writer: Arc::new(Mutex::new(writer)),
             index: Arc::new(RwLock::new(HashMap::new())),
+            seq: Arc::new(AtomicU64::new(0)),
+            versions: Arc::new(Versions::default()),
+            watchers: WatchRegistry::default(),
         };
 
         kv_store.load_index()?;
 
-        return Ok(kv_store);
+        Ok(kv_store)
     }
 
     fn load_index(&mut self) -> Result<()> {
         let f = File::options()
             .read(true)
             .append(true)
-            .open(self.path.join("index").to_path_buf());
+            .open(self.path.join("index"));
 
         if let std::io::Result::Ok(index_file) = f {
             self.load_index_from_file(index_file)
@@ -97,12 +135,23 @@ impl KVStore {
     fn load_index_from_file(&mut self, _index_file: File) {}
 
     // type(1bit)| timestamp(32bit) | ksz(32bit) | vsz(32bite)| key| value
+    //
+    // The transactions of a commit are applied once all of them are read. The
+    // active log is cut after the last one applied, so the next writes do
+    // not follow a commit torn by a crash.
     fn load_index_from_readers(&mut self) -> Result<()> {
-        let mut readers = self.readers.write().map_err(|e| anyhow!(e.to_string()))?;
+        let mut writer = self.writer.lock().map_err(|e| anyhow!(e.to_string()))?;
         let mut index = self.index.write().map_err(|e| anyhow!(e.to_string()))?;
+        let mut readers = self.readers.write().map_err(|e| anyhow!(e.to_string()))?;
 
-        for i in 0..=self.max_reader_id.load(Ordering::Relaxed) {
+        let mut log_ids: Vec<u32> = readers.keys().cloned().collect();
+        log_ids.sort_unstable();
+
+        for i in log_ids {
             let reader = readers.get_mut(&i).ok_or(anyhow!("index error"))?;
+            let mut end = 0;
+            let mut batch = Vec::new();
+            let mut batch_len = 0;
             loop {
                 let pos_before = reader.inner.stream_position()? as u32;
 
@@ -117,13 +166,35 @@ impl KVStore {
                     };
 
                     match t {
-                        Transaction::Set(k, _) => index.insert(k, t_pos),
-                        Transaction::Remove(k) => index.remove(&k),
-                    };
+                        Transaction::Begin(len) => batch_len = len as usize,
+                        t => batch.push((t, t_pos)),
+                    }
+                    if batch.len() >= batch_len {
+                        for (t, t_pos) in batch.drain(..) {
+                            match t {
+                                Transaction::Set(k, _) => index.insert(k, t_pos),
+                                Transaction::Remove(k) => index.remove(&k),
+                                Transaction::Begin(_) => None,
+                            };
+                        }
+                        batch_len = 0;
+                        end = pos_after;
+                    }
                 } else {
                     break;
                 }
             }
+
+            let path = log_path(i, &self.path);
+            if i == self.max_reader_id.load(Ordering::Relaxed)
+                && fs::metadata(&path)?.len() > end as u64
+            {
+                File::options()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(end as u64)?;
+                *writer = new_log_writer(i, &self.path)?;
+            }
         }
 
         Ok(())
@@ -134,9 +205,17 @@ impl KVStore {
     // to a newly created compressed file,and update the index to point to the new locations.
     // Finally, all previous files can be deleted.
     // If the index is very large, then this will lock the db for a long time.
-    pub fn compress_by_index(&mut self) -> Result<()> {
-        let mut readers = self.readers.write().map_err(|e| anyhow!(e.to_string()))?;
+    //
+    // The active log is kept as it is, but it is sealed: the compressed file gets a larger id,
+    // so later writes must go to a new log for the replay order to stay correct.
+    //
+    // The older values that live snapshots still see are copied as well.
+    pub fn compress_by_index(&self) -> Result<()> {
+        let mut writer = self.writer.lock().map_err(|e| anyhow!(e.to_string()))?;
         let mut index = self.index.write().map_err(|e| anyhow!(e.to_string()))?;
+        let mut readers = self.readers.write().map_err(|e| anyhow!(e.to_string()))?;
+
+        writer.flush()?;
 
         let max_reader_id = self.max_reader_id.load(Ordering::Relaxed);
         let compress_log_id = max_reader_id + 1;
@@ -144,11 +223,7 @@ impl KVStore {
 
         let mut compress_log_writer = new_log_writer(compress_log_id, &self.path)?;
 
-        for (_, pos) in index.iter_mut() {
-            if pos.log_reader_id == max_reader_id {
-                continue;
-            }
-
+        let mut copy = |pos: &mut TransactionPosition| -> Result<()> {
             let reader = readers
                 .get_mut(&pos.log_reader_id)
                 .ok_or(anyhow!("index has err"))?;
@@ -160,11 +235,56 @@ impl KVStore {
             let len = io::copy(&mut buf.as_slice(), &mut compress_log_writer.writer)?;
             pos.log_reader_id = compress_log_id;
             pos.offset = new_offset;
-            new_offset = new_offset + len as u32;
+            new_offset += len as u32;
+            Ok(())
+        };
+        // The logs are replayed in order when the store is opened, so the
+        // older values come first. The keys that have them are copied even
+        // from the active log, and the ones removed since are removed again.
+        let retained: HashSet<String> = self.versions.keys("").into_iter().collect();
+        self.versions.for_each_mut(|pos| {
+            if pos.log_reader_id == max_reader_id {
+                return Ok(());
+            }
+            copy(pos)
+        })?;
+        for (key, pos) in index.iter_mut() {
+            if pos.log_reader_id != max_reader_id || retained.contains(key) {
+                copy(pos)?;
+            }
+        }
+        for key in retained {
+            if !index.contains_key(&key) {
+                let bytes = Transaction::Remove(key).to_bytes()?;
+                compress_log_writer.writer.write_all(&bytes)?;
+            }
         }
 
         compress_log_writer.writer.flush()?;
 
+        // the compaction is kept before the old logs are removed, a replica
+        // must not read on from them into the compacted log after a restart
+        // either
+        let sealed = match self.compaction()? {
+            Some(c) if c.log_id == max_reader_id => c.sealed,
+            _ => LogCursor {
+                log_id: max_reader_id,
+                offset: fs::metadata(log_path(max_reader_id, &self.path))?.len() as u32,
+            },
+        };
+        let compaction = Compaction {
+            sealed,
+            log_id: compress_log_id,
+        };
+        let compacted_path = self.path.with_file_name(COMPACTED_FILE);
+        let tmp = compacted_path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&compaction)?)?;
+        fs::rename(&tmp, &compacted_path)?;
+        *self
+            .compaction
+            .write()
+            .map_err(|e| anyhow!(e.to_string()))? = Some(compaction);
+
         let compressed_log_ids: Vec<_> = readers
             .keys()
             .filter(|&&id| id < max_reader_id)
@@ -181,10 +301,146 @@ impl KVStore {
             compress_log_id,
             new_log_reader(compress_log_id, &self.path)?,
         );
+        writer.sealed = true;
 
         Ok(())
     }
 
+    // Switches the writer to a new log if the active one is full or sealed,
+    // returns the id of the log the next transaction is written to.
+    fn roll_log(&self, writer: &mut BufferWriter<File>) -> Result<u32> {
+        let mut max_reader_id = self.max_reader_id.load(Ordering::Relaxed);
+
+        if writer.pos > LOG_MAX_SIZE || writer.sealed {
+            let mut readers = self.readers.write().map_err(|e| anyhow!(e.to_string()))?;
+            max_reader_id += 1;
+            let last_writer = new_log_writer(max_reader_id, &self.path)?;
+            let last_reader: BufferReader<File> = new_log_reader(max_reader_id, &self.path)?;
+
+            readers.insert(max_reader_id, last_reader);
+            self.max_reader_id.store(max_reader_id, Ordering::Relaxed);
+
+            *writer = last_writer;
+        }
+
+        Ok(max_reader_id)
+    }
+
+    fn compaction(&self) -> Result<Option<Compaction>> {
+        Ok(*self.compaction.read().map_err(|e| anyhow!(e.to_string()))?)
+    }
+
+    // the smallest log id after log_id, none if the log is the active one
+    fn next_log_id(&self, log_id: u32) -> Result<Option<u32>> {
+        let readers = self.readers.read().map_err(|e| anyhow!(e.to_string()))?;
+        Ok(readers.keys().filter(|&&id| id > log_id).min().cloned())
+    }
+
+    // A read view of the store as of now. Writers are blocked while the
+    // sequence number is taken, so the view sees every write up to it.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Ok(Snapshot {
+            store: self.clone(),
+            seq: self.pin()?,
+        })
+    }
+
+    // A snapshot or a transaction older than this when a key is written
+    // expires, its reads fail from then on. It is a minute by default.
+    pub fn set_snapshot_max_age(&self, max_age: Duration) {
+        self.versions.set_max_age(max_age);
+    }
+
+    // keeps the versions overwritten after the sequence number of the last
+    // write, which is returned
+    fn pin(&self) -> Result<u64> {
+        let _index = self.index.read().map_err(|e| anyhow!(e.to_string()))?;
+        let seq = self.seq.load(Ordering::SeqCst);
+        self.versions.pin(seq);
+        Ok(seq)
+    }
+
+    // A key read by the transaction has a version after its start if it was
+    // written since. The writes are visible at once and flushed together.
+    fn apply_commit(&self, commit: Commit) -> Result<()> {
+        let mut writer = self.writer.lock().map_err(|e| anyhow!(e.to_string()))?;
+        let max_reader_id = self.roll_log(&mut writer)?;
+        let mut index = self.index.write().map_err(|e| anyhow!(e.to_string()))?;
+
+        let conflict = commit
+            .reads
+            .iter()
+            .any(|(key, _)| self.versions.get(key, commit.start).is_some());
+        self.versions.check(commit.start)?;
+        if conflict {
+            return Err(Conflict.into());
+        }
+
+        // whether a key exists after the writes of the commit before it
+        let mut exists = HashMap::new();
+        let mut transactions = Vec::with_capacity(commit.writes.len());
+        for (key, value) in commit.writes {
+            let existed = exists
+                .get(&key)
+                .copied()
+                .unwrap_or_else(|| index.contains_key(&key));
+            exists.insert(key.clone(), value.is_some());
+            transactions.push(match value {
+                Some(value) => Transaction::Set(key, value),
+                None if existed => Transaction::Remove(key),
+                None => continue,
+            });
+        }
+
+        // A log replayed after a crash applies the writes only if all of
+        // them were written.
+        if transactions.len() > 1 {
+            writer.write(&Transaction::Begin(transactions.len() as u32).to_bytes()?)?;
+        }
+        let mut events = Vec::with_capacity(transactions.len());
+        for transaction in transactions {
+            let bytes = transaction.to_bytes()?;
+            let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+            let (previous, event) = match transaction {
+                Transaction::Set(key, value) => {
+                    let pos = TransactionPosition {
+                        log_reader_id: max_reader_id,
+                        offset: writer.pos,
+                        len: bytes.len() as u32,
+                    };
+                    (
+                        index.insert(key.clone(), pos),
+                        WatchEvent::Set { seq, key, value },
+                    )
+                }
+                Transaction::Remove(key) => (index.remove(&key), WatchEvent::Remove { seq, key }),
+                Transaction::Begin(_) => continue,
+            };
+            self.versions.record(event.key(), seq, previous);
+            writer.write(&bytes)?;
+            events.push(event);
+        }
+        writer.flush()?;
+
+        for event in events {
+            self.watchers.publish(event);
+        }
+        Ok(())
+    }
+
+    // the position of the value of the key as of seq, none if it did not exist
+    fn position_at(
+        &self,
+        index: &HashMap<String, TransactionPosition>,
+        key: &str,
+        seq: u64,
+    ) -> Option<TransactionPosition> {
+        match self.versions.get(key, seq) {
+            Some(previous) => previous,
+            None => index.get(key).cloned(),
+        }
+    }
+
     // A better way to compress logs may be to read the Transaction from the Reader,
     // and then query whether the key exists in the index, so that we can split the
     // compression task into more small tasks.
@@ -203,36 +459,13 @@ impl KvsEngine for KVStore {
             .write()
             .map_err(|_| anyhow!("acquire reader read lock failed"))?;
 
-        let pos = index.get(&key).ok_or(anyhow!("key not found"))?;
-        let reader = readers
-            .get_mut(&pos.log_reader_id)
-            .ok_or(anyhow!("db maybe breaded"))?;
-
-        let mut data = vec![0; pos.len as usize];
-        reader.read_exact(pos.offset, &mut data)?;
-
-        match Transaction::from_bytes(&data)? {
-            Transaction::Set(_, value) => Ok(value),
-            Transaction::Remove(_) => Err(anyhow!("Key not found")),
-        }
+        let pos = index.get(&key).ok_or(KeyNotFound)?;
+        Ok(read_value(&mut readers, pos)?.ok_or(KeyNotFound)?)
     }
 
     fn set(&self, key: String, value: String) -> Result<Option<String>> {
         let mut writer = self.writer.lock().map_err(|e| anyhow!(e.to_string()))?;
-        let mut max_reader_id = self.max_reader_id.load(Ordering::Relaxed);
-
-        if writer.pos > LOG_MAX_SIZE {
-            let mut readers = self.readers.write().map_err(|e| anyhow!(e.to_string()))?;
-            max_reader_id += 1;
-            let last_writer = new_log_writer(max_reader_id, &self.path)?;
-            let last_reader: BufferReader<File> = new_log_reader(max_reader_id, &self.path)?;
-
-            readers.insert(max_reader_id, last_reader);
-            self.max_reader_id.store(max_reader_id, Ordering::Relaxed);
-
-            writer.writer = last_writer.writer;
-            writer.pos = 0;
-        }
+        let max_reader_id = self.roll_log(&mut writer)?;
 
         let old_value = self.get(key.clone()).ok();
         let mut index = self.index.write().map_err(|e| anyhow!(e.to_string()))?;
@@ -240,7 +473,8 @@ impl KvsEngine for KVStore {
         let transaction: Transaction = Transaction::Set(key.to_string(), value.to_string());
         let bytes = transaction.to_bytes()?;
 
-        index.insert(
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let previous = index.insert(
             key.to_string(),
             TransactionPosition {
                 log_reader_id: max_reader_id,
@@ -248,44 +482,502 @@ impl KvsEngine for KVStore {
                 len: bytes.len() as u32,
             },
         );
+        self.versions.record(&key, seq, previous);
         writer.write(&bytes)?;
         writer.flush()?;
 
+        self.watchers.publish(WatchEvent::Set { seq, key, value });
+
         Ok(old_value)
     }
 
+    // the writer is locked before the index, in the same order as set and compaction
     fn remove(&self, key: String) -> Result<()> {
+        let mut writer = self.writer.lock().map_err(|e| anyhow!(e.to_string()))?;
         let mut index = self
             .index
             .write()
             .map_err(|_| anyhow!("acquire index read lock failed"))?;
 
-        index.remove(&key).ok_or(anyhow!("Key not found"))?;
-        let mut writer = self.writer.lock().map_err(|e| anyhow!(e.to_string()))?;
+        if !index.contains_key(&key) {
+            return Err(KeyNotFound.into());
+        }
+        self.roll_log(&mut writer)?;
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let previous = index.remove(&key);
+        self.versions.record(&key, seq, previous);
 
-        let transaction: Transaction = Transaction::Remove(key);
+        let transaction: Transaction = Transaction::Remove(key.clone());
         let bytes = transaction.to_bytes()?;
 
         writer.write(&bytes)?;
         writer.flush()?;
+
+        self.watchers.publish(WatchEvent::Remove { seq, key });
+        Ok(())
+    }
+
+    // the index and readers are locked once for all keys
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let index = self
+            .index
+            .read()
+            .map_err(|_| anyhow!("acquire index read lock failed"))?;
+        let mut readers = self
+            .readers
+            .write()
+            .map_err(|_| anyhow!("acquire reader read lock failed"))?;
+
+        keys.iter()
+            .map(|key| match index.get(key) {
+                Some(pos) => read_value(&mut readers, pos),
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    // all pairs are appended under one writer lock and flushed once
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<Vec<Option<String>>> {
+        let mut writer = self.writer.lock().map_err(|e| anyhow!(e.to_string()))?;
+        let max_reader_id = self.roll_log(&mut writer)?;
+        let mut index = self.index.write().map_err(|e| anyhow!(e.to_string()))?;
+        let mut readers = self.readers.write().map_err(|e| anyhow!(e.to_string()))?;
+
+        // values written by this batch are not flushed yet, so they are not
+        // read back from the log
+        let mut written: HashMap<String, String> = HashMap::new();
+        let mut old_values = Vec::with_capacity(pairs.len());
+        let mut seqs = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs.iter() {
+            let old_value = match written.get(key) {
+                Some(v) => Some(v.clone()),
+                None => match index.get(key) {
+                    Some(pos) => read_value(&mut readers, pos).ok().flatten(),
+                    None => None,
+                },
+            };
+            old_values.push(old_value);
+
+            let bytes = Transaction::Set(key.clone(), value.clone()).to_bytes()?;
+            let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+            let previous = index.insert(
+                key.clone(),
+                TransactionPosition {
+                    log_reader_id: max_reader_id,
+                    offset: writer.pos,
+                    len: bytes.len() as u32,
+                },
+            );
+            self.versions.record(key, seq, previous);
+            seqs.push(seq);
+            writer.write(&bytes)?;
+            written.insert(key.clone(), value.clone());
+        }
+        writer.flush()?;
+
+        for ((key, value), seq) in pairs.into_iter().zip(seqs) {
+            self.watchers.publish(WatchEvent::Set { seq, key, value });
+        }
+
+        Ok(old_values)
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let mut writer = self.writer.lock().map_err(|e| anyhow!(e.to_string()))?;
+        self.roll_log(&mut writer)?;
+        let mut index = self.index.write().map_err(|e| anyhow!(e.to_string()))?;
+
+        let mut removed = Vec::with_capacity(keys.len());
+        let mut events = Vec::new();
+        for key in keys {
+            let previous = match index.remove(&key) {
+                Some(previous) => previous,
+                None => {
+                    removed.push(false);
+                    continue;
+                }
+            };
+            let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+            self.versions.record(&key, seq, Some(previous));
+
+            let bytes = Transaction::Remove(key.clone()).to_bytes()?;
+            writer.write(&bytes)?;
+            removed.push(true);
+            events.push(WatchEvent::Remove { seq, key });
+        }
+        writer.flush()?;
+
+        // like the sets, the removes are only published once they are flushed
+        for event in events {
+            self.watchers.publish(event);
+        }
+
+        Ok(removed)
+    }
+
+    // the index is not ordered, so the matching keys are sorted on every page
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let index = self
+            .index
+            .read()
+            .map_err(|_| anyhow!("acquire index read lock failed"))?;
+        let mut keys: Vec<&String> = index
+            .keys()
+            .filter(|key| key.starts_with(&prefix))
+            .filter(|key| after.as_ref().is_none_or(|after| *key > after))
+            .collect();
+        keys.sort_unstable();
+        keys.truncate(limit);
+
+        let mut readers = self
+            .readers
+            .write()
+            .map_err(|_| anyhow!("acquire reader read lock failed"))?;
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = read_value(&mut readers, &index[key])? {
+                pairs.push((key.clone(), value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let keys = self
+            .index
+            .read()
+            .map_err(|_| anyhow!("acquire index read lock failed"))?
+            .len() as u64;
+
+        let mut disk_usage = 0;
+        for entry in fs::read_dir(&self.path)? {
+            disk_usage += entry?.metadata()?.len();
+        }
+
+        Ok(EngineStats { keys, disk_usage })
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.compress_by_index()
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        Ok(self.watchers.subscribe(prefix))
+    }
+
+    fn read_view(&self) -> Result<Box<dyn ReadView>> {
+        Ok(Box::new(KVStore::snapshot(self)?))
+    }
+
+    // Flushes the active log and syncs it to the disk.
+    fn flush(&self) -> Result<()> {
+        let mut writer = self.writer.lock().map_err(|e| anyhow!(e.to_string()))?;
+        writer.flush()?;
+        writer
+            .writer
+            .get_ref()
+            .sync_all()
+            .map_err(|e| anyhow!(e.to_string()))
+    }
+
+    // Reads the log files in id order. At the end of a log that is not the
+    // active one, the cursor moves to the start of the next log. The
+    // compacted log holds older values and the retained versions before the
+    // current ones, so a cursor at the end of the sealed log skips it.
+    fn read_log(&self, cursor: LogCursor, limit: usize) -> Result<(Vec<Transaction>, LogCursor)> {
+        if self.compaction()?.is_some_and(|c| c.gone(cursor)) {
+            return Err(CursorGone.into());
+        }
+        let mut cursor = cursor;
+        let mut transactions = Vec::new();
+        loop {
+            if let Some(c) = self.compaction()?.filter(|c| cursor == c.sealed) {
+                match self.next_log_id(c.log_id)? {
+                    Some(log_id) => cursor = LogCursor { log_id, offset: 0 },
+                    None => break,
+                }
+            }
+            let path = log_path(cursor.log_id, &self.path);
+            cursor.offset = read_log_file(&path, cursor.offset, limit, &mut transactions)?;
+            if transactions.len() >= limit {
+                break;
+            }
+            // a compaction adds its log after it is set, so it is read after
+            // the next log
+            let next = self.next_log_id(cursor.log_id)?;
+            match (next, self.compaction()?) {
+                // the log was merged while it was read, the cursor is gone
+                // the next time
+                (_, Some(c)) if c.gone(cursor) => break,
+                // the log was sealed while it was read, the records written
+                // before are read on up to its end
+                (_, Some(c)) if cursor.log_id == c.sealed.log_id => continue,
+                (Some(log_id), _) => cursor = LogCursor { log_id, offset: 0 },
+                (None, _) => break,
+            }
+        }
+        Ok((transactions, cursor))
+    }
+
+    // writers are blocked while the pairs are read, so no write falls
+    // between them and the cursor
+    fn log_snapshot(&self) -> Result<(Vec<(String, String)>, LogCursor)> {
+        let mut writer = self.writer.lock().map_err(|e| anyhow!(e.to_string()))?;
+        let index = self.index.read().map_err(|e| anyhow!(e.to_string()))?;
+        let mut readers = self.readers.write().map_err(|e| anyhow!(e.to_string()))?;
+
+        writer.flush()?;
+
+        let mut pairs = Vec::with_capacity(index.len());
+        for (key, pos) in index.iter() {
+            if let Some(value) = read_value(&mut readers, pos)? {
+                pairs.push((key.clone(), value));
+            }
+        }
+
+        // after a compaction the writer is sealed and the next write goes to
+        // a log after the compacted one, which is the newest log now
+        let log_id = self.max_reader_id.load(Ordering::Relaxed);
+        let offset = fs::metadata(log_path(log_id, &self.path))?.len() as u32;
+        Ok((pairs, LogCursor { log_id, offset }))
+    }
+
+    fn begin_transaction(&self) -> Result<u64> {
+        self.pin()
+    }
+
+    fn commit_transaction(&self, commit: Commit) -> Result<()> {
+        let start = commit.start;
+        let res = self.apply_commit(commit);
+        self.versions.unpin(start);
+        res
+    }
+
+    fn abort_transaction(&self, start: u64) {
+        self.versions.unpin(start);
+    }
+
+    // Writes every live transaction into a new store rooted at `root_path`,
+    // writers are blocked while the snapshot is taken.
+    fn snapshot(&self, root_path: &Path) -> Result<()> {
+        let path = root_path.join("db");
+        if path.exists() {
+            return Err(anyhow!("snapshot target {} already exists", path.display()));
+        }
+        fs::create_dir_all(&path)?;
+
+        let mut writer = self.writer.lock().map_err(|e| anyhow!(e.to_string()))?;
+        let index = self.index.read().map_err(|e| anyhow!(e.to_string()))?;
+        let mut readers = self.readers.write().map_err(|e| anyhow!(e.to_string()))?;
+
+        writer.flush()?;
+
+        let mut snapshot_writer = new_log_writer(0, &path)?;
+        for pos in index.values() {
+            let reader = readers
+                .get_mut(&pos.log_reader_id)
+                .ok_or(anyhow!("index has err"))?;
+
+            let mut buf = vec![0; pos.len as usize];
+            reader.read_exact(pos.offset, &mut buf)?;
+            snapshot_writer.write(&buf)?;
+        }
+        snapshot_writer.flush()?;
+        snapshot_writer.writer.get_ref().sync_all()?;
+
         Ok(())
     }
 }
 
-fn new_log_writer(log_id: u32, path_buf: &PathBuf) -> Result<BufferWriter<File>> {
-    File::options()
+// Snapshot is a read view of the store pinned to the sequence number of a
+// write. The values it sees survive compactions until it is dropped.
+pub struct Snapshot {
+    store: KVStore,
+    seq: u64,
+}
+
+impl Snapshot {
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn get(&self, key: String) -> Result<String> {
+        let index = self
+            .store
+            .index
+            .read()
+            .map_err(|e| anyhow!(e.to_string()))?;
+        let pos = self.store.position_at(&index, &key, self.seq);
+        self.store.versions.check(self.seq)?;
+        let pos = pos.ok_or(KeyNotFound)?;
+        let mut readers = self
+            .store
+            .readers
+            .write()
+            .map_err(|e| anyhow!(e.to_string()))?;
+        Ok(read_value(&mut readers, &pos)?.ok_or(KeyNotFound)?)
+    }
+
+    // like KvsEngine::scan, the keys removed since the snapshot are found in
+    // the versions
+    pub fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let index = self
+            .store
+            .index
+            .read()
+            .map_err(|e| anyhow!(e.to_string()))?;
+        let keys: BTreeSet<String> = index
+            .keys()
+            .filter(|key| key.starts_with(&prefix))
+            .cloned()
+            .chain(self.store.versions.keys(&prefix))
+            .filter(|key| after.as_ref().is_none_or(|after| key > after))
+            .collect();
+
+        let mut readers = self
+            .store
+            .readers
+            .write()
+            .map_err(|e| anyhow!(e.to_string()))?;
+        let mut pairs = Vec::new();
+        for key in keys {
+            if pairs.len() >= limit {
+                break;
+            }
+            let pos = match self.store.position_at(&index, &key, self.seq) {
+                Some(pos) => pos,
+                None => continue,
+            };
+            if let Some(value) = read_value(&mut readers, &pos)? {
+                pairs.push((key, value));
+            }
+        }
+        self.store.versions.check(self.seq)?;
+        Ok(pairs)
+    }
+}
+
+impl ReadView for Snapshot {
+    fn get(&self, key: String) -> Result<String> {
+        Snapshot::get(self, key)
+    }
+
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        Snapshot::scan(self, prefix, after, limit)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.store.versions.unpin(self.seq);
+    }
+}
+
+// Appends the transactions of the log from offset on until there are `limit`,
+// and returns the offset after the last one. A transaction still being
+// written at the end of the log is left for the next read, the records of a
+// commit follow their Begin marker.
+fn read_log_file(
+    path: &Path,
+    offset: u32,
+    limit: usize,
+    transactions: &mut Vec<Transaction>,
+) -> Result<u32> {
+    let file = match File::open(path) {
+        std::io::Result::Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(CursorGone.into()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut reader = BufReader::new(file);
+    reader.seek(Start(offset as u64))?;
+
+    // the transactions of a commit are read as a whole, even past the limit
+    let (mut offset, mut read) = (offset, offset);
+    let mut batch = Vec::new();
+    let mut batch_len = 0;
+    while transactions.len() < limit || batch_len > 0 {
+        // a bson document starts with its length
+        let mut len = [0; 4];
+        if reader.read_exact(&mut len).is_err() {
+            break;
+        }
+        let len = u32::from_le_bytes(len);
+        if len < 4 {
+            return Err(anyhow!("log {} is broken at {}", path.display(), read));
+        }
+        let mut data = vec![0; len as usize];
+        data[..4].copy_from_slice(&len.to_le_bytes());
+        if reader.read_exact(&mut data[4..]).is_err() {
+            break;
+        }
+        read += len;
+
+        // the marker is kept, a replica applies the records of a commit
+        // together too
+        let transaction = Transaction::from_bytes(&data)?;
+        if let Transaction::Begin(len) = transaction {
+            batch_len = len as usize + 1;
+        }
+        batch.push(transaction);
+        if batch.len() >= batch_len {
+            transactions.append(&mut batch);
+            batch_len = 0;
+            offset = read;
+        }
+    }
+    Ok(offset)
+}
+
+// reads the value of the transaction at pos, none if it is a remove
+fn read_value(
+    readers: &mut HashMap<u32, BufferReader<File>>,
+    pos: &TransactionPosition,
+) -> Result<Option<String>> {
+    let reader = readers
+        .get_mut(&pos.log_reader_id)
+        .ok_or(anyhow!("db maybe breaded"))?;
+
+    let mut data = vec![0; pos.len as usize];
+    reader.read_exact(pos.offset, &mut data)?;
+
+    match Transaction::from_bytes(&data)? {
+        Transaction::Set(_, value) => Ok(Some(value)),
+        Transaction::Remove(_) => Ok(None),
+        Transaction::Begin(_) => Err(anyhow!("index points at the start of a commit")),
+    }
+}
+
+// the writer continues at the end of an existing log
+fn new_log_writer(log_id: u32, path_buf: &Path) -> Result<BufferWriter<File>> {
+    let f = File::options()
         .create(true)
         .read(true)
         .append(true)
-        .open(log_path(log_id, path_buf))
-        .map(|f| BufferWriter::<File> {
-            writer: BufWriter::new(f),
-            pos: 0,
-        })
-        .map_err(|e| anyhow!(e))
+        .open(log_path(log_id, path_buf))?;
+
+    Ok(BufferWriter::<File> {
+        pos: f.metadata()?.len() as u32,
+        writer: BufWriter::new(f),
+        sealed: false,
+    })
 }
 
-fn new_log_reader(log_id: u32, path_buf: &PathBuf) -> Result<BufferReader<File>> {
+fn new_log_reader(log_id: u32, path_buf: &Path) -> Result<BufferReader<File>> {
     File::open(log_path(log_id, path_buf))
         .map(|f| BufferReader::<File> {
             inner: BufReader::new(f),
@@ -293,14 +985,24 @@ fn new_log_reader(log_id: u32, path_buf: &PathBuf) -> Result<BufferReader<File>>
         .map_err(|e| anyhow!(e))
 }
 
-fn log_path(log_id: u32, path_buf: &PathBuf) -> PathBuf {
+fn log_path(log_id: u32, path_buf: &Path) -> PathBuf {
     path_buf.join(format!("{}.log", log_id))
 }
 
+fn parse_log_id(path: &Path) -> Option<u32> {
+    if path.extension()? != "log" {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Transaction {
     Set(String, String),
     Remove(String),
+    // the number of transactions after it written by one commit, they are
+    // applied together or not at all
+    Begin(u32),
 }
 
 impl Transaction {
@@ -313,13 +1015,14 @@ impl Transaction {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct TransactionPosition {
     log_reader_id: u32,
     offset: u32,
     len: u32,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug)]
 struct TransactionIndex {
     key: String,
@@ -345,6 +1048,8 @@ impl<T: Read + Seek> BufferReader<T> {
 pub struct BufferWriter<T: Write> {
     writer: BufWriter<T>,
     pos: u32,
+    // set when the log was compacted over and must not be appended anymore
+    sealed: bool,
 }
 
 impl<T: Write> BufferWriter<T> {
@@ -1,12 +1,21 @@
 // kv-server define a key-value server
 
+pub mod bench;
 pub mod client;
 mod connection;
 pub mod engine;
 pub mod kvs;
+mod listener;
+pub mod raft;
+pub mod replication;
 pub mod server;
 pub mod sled;
+pub mod slowlog;
+pub mod stats;
 pub mod thread_pool;
+pub mod transaction;
+mod versions;
+pub mod watch;
 
 #[cfg(test)]
 pub mod tests;
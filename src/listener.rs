@@ -0,0 +1,66 @@
+use std::fs::{self, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use tokio::net::{TcpListener, UnixListener};
+
+use crate::connection::{BoxedStream, UNIX_SCHEME};
+
+// Listener accepts the connections of the server on a tcp address or a unix
+// domain socket, the socket file is removed when the listener is dropped.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub async fn bind(addr: &str, mode: Option<u32>) -> Result<Listener> {
+        match addr.strip_prefix(UNIX_SCHEME) {
+            Some(path) => Self::bind_unix(Path::new(path), mode),
+            None => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+        }
+    }
+
+    pub fn bind_unix(path: &Path, mode: Option<u32>) -> Result<Listener> {
+        if path.exists() {
+            // the socket file of a server that is gone can be reused
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(anyhow!("unix socket {} is in use", path.display()));
+            }
+            fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        if let Some(mode) = mode {
+            fs::set_permissions(path, Permissions::from_mode(mode))?;
+        }
+
+        Ok(Listener::Unix(listener, path.to_path_buf()))
+    }
+
+    // returns the stream and the address of the peer
+    pub async fn accept(&self) -> Result<(BoxedStream, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Box::new(stream), peer.to_string()))
+            }
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((
+                    Box::new(stream),
+                    format!("{}{}", UNIX_SCHEME, path.display()),
+                ))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
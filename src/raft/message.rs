@@ -0,0 +1,64 @@
+// messages between the nodes of a raft group
+use serde::{Deserialize, Serialize};
+
+// Command is a write replicated through the log, it is applied to the engine
+// of every node once committed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    // appended by a new leader to commit the entries of the older terms
+    Noop,
+    Set(String, String),
+    Remove(String),
+    SetMany(Vec<(String, String)>),
+    RemoveMany(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub index: u64,
+    pub term: u64,
+    pub command: Command,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RaftMessage {
+    RequestVote {
+        term: u64,
+        candidate: String,
+        last_index: u64,
+        last_term: u64,
+    },
+    // a heartbeat if there are no entries
+    AppendEntries {
+        term: u64,
+        leader: String,
+        // the address clients reach the leader at
+        leader_client: String,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    },
+    // a page of the data of the leader as of last_index, sent to a node whose
+    // next entry was compacted away
+    InstallSnapshot {
+        term: u64,
+        leader: String,
+        leader_client: String,
+        last_index: u64,
+        last_term: u64,
+        pairs: Vec<(String, String)>,
+        first: bool,
+        done: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RaftReply {
+    Vote { term: u64, granted: bool },
+    // next is the index the leader sends from next time, after the last
+    // entry matching the leader's on success
+    Append { term: u64, success: bool, next: u64 },
+    // next is zero until the last page is installed
+    Snapshot { term: u64, next: u64 },
+}
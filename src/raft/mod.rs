@@ -0,0 +1,374 @@
+// Raft replication of the writes of a group of servers. RaftEngine wraps the
+// engine of a server: writes are appended to a log replicated to the other
+// nodes and applied to the engine of every node once a quorum has them.
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+
+use crate::connection::Connection;
+use crate::engine::{EngineStats, KeyNotFound, KvsEngine, NotLeader, ReadView};
+use crate::kvs::Transaction;
+use crate::replication::LogCursor;
+use crate::transaction::Commit;
+use crate::watch::Watcher;
+
+pub mod message;
+pub(crate) mod node;
+pub(crate) mod storage;
+
+use message::{Command, RaftMessage, RaftReply};
+use node::{Applied, Node, Outbox};
+
+// how long a write waits for its entry to be applied
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how often the election and lease timeouts are checked
+const TICK: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    // the address the other nodes reach this node at, it names the node
+    pub addr: String,
+    // the raft addresses of the other nodes
+    pub peers: Vec<String>,
+    // the address clients reach this node at, followers redirect to it
+    pub client_addr: String,
+    // directory of the raft state, the data directory of the engine
+    pub dir: PathBuf,
+    pub heartbeat: Duration,
+    // a follower not hearing from the leader for this long, plus a random
+    // part of it, starts an election
+    pub election_timeout: Duration,
+    // applied entries kept in the log before it is compacted
+    pub snapshot_entries: u64,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        RaftConfig {
+            addr: String::new(),
+            peers: Vec::new(),
+            client_addr: String::new(),
+            dir: PathBuf::from("."),
+            heartbeat: Duration::from_millis(100),
+            election_timeout: Duration::from_millis(500),
+            snapshot_entries: 1000,
+        }
+    }
+}
+
+// Reads are served by the leader from its engine while it holds a lease, a
+// leader that loses the quorum stops reading before another one is elected
+// and steps down. Other nodes answer every read and write with NotLeader,
+// which servers turn into a redirect.
+#[derive(Clone)]
+pub struct RaftEngine<E: KvsEngine> {
+    engine: E,
+    raft: Arc<Raft<E>>,
+}
+
+struct Raft<E: KvsEngine> {
+    node: Mutex<Node<E>>,
+    // a peer task waits on its notify for entries to send
+    peers: Vec<(String, Arc<Notify>)>,
+    config: RaftConfig,
+}
+
+impl<E: KvsEngine> RaftEngine<E> {
+    // Starts the node on a runtime of its own, so the server threads blocked
+    // on writes waiting for their commit never hold up the raft messages.
+    pub fn start(engine: E, config: RaftConfig) -> Result<Self> {
+        let node = Node::open(engine.clone(), config.clone())?;
+        let listener = std::net::TcpListener::bind(&config.addr)?;
+        listener.set_nonblocking(true)?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("raft")
+            .enable_all()
+            .build()?;
+
+        let raft = Arc::new(Raft {
+            node: Mutex::new(node),
+            peers: config
+                .peers
+                .iter()
+                .map(|peer| (peer.clone(), Arc::new(Notify::new())))
+                .collect(),
+            config,
+        });
+        let shared = raft.clone();
+        thread::Builder::new()
+            .name("raft".to_string())
+            .spawn(move || runtime.block_on(shared.run(listener)))?;
+
+        Ok(RaftEngine { engine, raft })
+    }
+
+    // reads are answered by a leader holding its lease only
+    fn check_leader(&self) -> Result<()> {
+        let node = self.raft.node()?;
+        if node.can_read() {
+            Ok(())
+        } else if node.is_leader() {
+            Err(anyhow!("the leader has no lease to serve reads, try again"))
+        } else {
+            Err(NotLeader(node.leader()).into())
+        }
+    }
+
+    // Appends the command and waits until it is applied. Servers call the
+    // engine on their blocking threads, so the wait holds up no connection.
+    fn propose(&self, command: Command) -> Result<Applied> {
+        let receiver = self.raft.node()?.propose(command)?;
+        self.raft.notify_peers();
+        match receiver.recv_timeout(COMMIT_TIMEOUT) {
+            Ok(applied) => applied,
+            Err(_) => Err(anyhow!("timed out waiting for the write to commit")),
+        }
+    }
+
+    fn propose_set(&self, command: Command) -> Result<Vec<Option<String>>> {
+        match self.propose(command)? {
+            Applied::Values(values) => Ok(values),
+            applied => Err(anyhow!("a set applied as {:?}", applied)),
+        }
+    }
+
+    fn propose_remove(&self, command: Command) -> Result<Vec<bool>> {
+        match self.propose(command)? {
+            Applied::Removed(removed) => Ok(removed),
+            applied => Err(anyhow!("a remove applied as {:?}", applied)),
+        }
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for RaftEngine<E> {
+    fn get(&self, key: String) -> Result<String> {
+        self.check_leader()?;
+        self.engine.get(key)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<Option<String>> {
+        let mut values = self.propose_set(Command::Set(key, value))?;
+        Ok(values.pop().flatten())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        match self.propose_remove(Command::Remove(key))?.as_slice() {
+            [true] => Ok(()),
+            _ => Err(KeyNotFound.into()),
+        }
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.check_leader()?;
+        self.engine.get_many(keys)
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<Vec<Option<String>>> {
+        self.propose_set(Command::SetMany(pairs))
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        self.propose_remove(Command::RemoveMany(keys))
+    }
+
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.check_leader()?;
+        self.engine.scan(prefix, after, limit)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        self.engine.stats()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.engine.compact()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn snapshot(&self, path: &Path) -> Result<()> {
+        self.engine.snapshot(path)
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        self.engine.watch(prefix)
+    }
+
+    fn read_view(&self) -> Result<Box<dyn ReadView>> {
+        self.check_leader()?;
+        self.engine.read_view()
+    }
+
+    fn read_log(&self, cursor: LogCursor, limit: usize) -> Result<(Vec<Transaction>, LogCursor)> {
+        self.engine.read_log(cursor, limit)
+    }
+
+    fn log_snapshot(&self) -> Result<(Vec<(String, String)>, LogCursor)> {
+        self.engine.log_snapshot()
+    }
+
+    // the checks of a commit would have to run where the entry is applied,
+    // which the log does not do yet
+    fn begin_transaction(&self) -> Result<u64> {
+        Err(anyhow!("transactions are not supported in raft mode"))
+    }
+
+    fn commit_transaction(&self, _commit: Commit) -> Result<()> {
+        Err(anyhow!("transactions are not supported in raft mode"))
+    }
+}
+
+impl<E: KvsEngine> Raft<E> {
+    fn node(&self) -> Result<MutexGuard<'_, Node<E>>> {
+        self.node.lock().map_err(|e| anyhow!(e.to_string()))
+    }
+
+    fn notify_peers(&self) {
+        for (_, notify) in &self.peers {
+            notify.notify_one();
+        }
+    }
+
+    async fn run(self: Arc<Self>, listener: std::net::TcpListener) {
+        for (peer, notify) in &self.peers {
+            tokio::spawn(self.clone().replicate(peer.clone(), notify.clone()));
+        }
+        tokio::spawn(self.clone().tick());
+
+        let listener = match TcpListener::from_std(listener) {
+            Ok(listener) => listener,
+            Err(e) => return log::error!("raft listener has error {}", e),
+        };
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let raft = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = raft.serve_peer(Connection::new(stream)).await {
+                            log::debug!("raft connection has error {}", e);
+                        }
+                    });
+                }
+                Err(e) => log::error!("raft listener has error {}", e),
+            }
+        }
+    }
+
+    async fn tick(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(TICK).await;
+            match self.node().and_then(|mut node| node.tick()) {
+                Ok(true) => self.notify_peers(),
+                Ok(false) => {}
+                Err(e) => log::error!("raft tick has error {}", e),
+            }
+        }
+    }
+
+    async fn serve_peer(&self, mut conn: Connection<tokio::net::TcpStream>) -> Result<()> {
+        while let Some(message) = conn.read::<RaftMessage>().await? {
+            let reply = self.node()?.handle(message)?;
+            conn.write(reply).await?;
+        }
+        Ok(())
+    }
+
+    // Sends the messages of the node to a peer over one connection, which is
+    // opened again after an error. The leader sends a heartbeat every
+    // interval and its entries as soon as it has them.
+    async fn replicate(self: Arc<Self>, peer: String, notify: Arc<Notify>) {
+        let mut conn = None;
+        loop {
+            let failed = match self.node().and_then(|mut node| node.messages_for(&peer)) {
+                Ok(outbox) => self.send(&mut conn, &peer, outbox).await,
+                Err(e) => {
+                    log::error!("raft messages for {} have error {}", peer, e);
+                    false
+                }
+            };
+
+            let behind = self.node().is_ok_and(|node| node.is_behind(&peer));
+            if failed || !behind {
+                tokio::select! {
+                    _ = notify.notified() => {}
+                    _ = tokio::time::sleep(self.config.heartbeat) => {}
+                }
+            }
+        }
+    }
+
+    // Sends the messages of the outbox one by one and handles the replies,
+    // returns whether the connection failed.
+    async fn send(&self, conn: &mut Option<Connection>, peer: &str, mut outbox: Outbox) -> bool {
+        loop {
+            let message = match outbox.next() {
+                Ok(Some(message)) => message,
+                Ok(None) => return false,
+                Err(e) => {
+                    log::error!("raft messages for {} have error {}", peer, e);
+                    return false;
+                }
+            };
+            let sent = Instant::now();
+            match self.call(conn, peer, message).await {
+                Ok(reply) => {
+                    if let Err(e) = self.handle_reply(peer, reply, sent) {
+                        log::error!("raft reply of {} has error {}", peer, e);
+                    }
+                }
+                Err(e) => {
+                    log::debug!("raft message to {} has error {}", peer, e);
+                    *conn = None;
+                    return true;
+                }
+            }
+        }
+    }
+
+    fn handle_reply(&self, peer: &str, reply: RaftReply, sent: Instant) -> Result<()> {
+        let mut node = self.node()?;
+        let was_leader = node.is_leader();
+        node.handle_reply(peer, reply, sent)?;
+        // the peers tell the other nodes about the new leader right away
+        if !was_leader && node.is_leader() {
+            self.notify_peers();
+        }
+        Ok(())
+    }
+
+    async fn call(
+        &self,
+        conn: &mut Option<Connection>,
+        peer: &str,
+        message: RaftMessage,
+    ) -> Result<RaftReply> {
+        let timeout = self.config.election_timeout;
+        let call = async {
+            if conn.is_none() {
+                *conn = Some(Connection::connect(peer).await?);
+            }
+            let conn = conn.as_mut().ok_or_else(|| anyhow!("not connected"))?;
+            conn.write(message).await?;
+            conn.read::<RaftReply>()
+                .await?
+                .ok_or_else(|| anyhow!("connection closed by {}", peer))
+        };
+        tokio::time::timeout(timeout, call)
+            .await
+            .map_err(|_| anyhow!("{} did not reply in {:?}", peer, timeout))?
+    }
+}
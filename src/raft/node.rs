@@ -0,0 +1,738 @@
+// the state of a raft node, the messages are sent and received by the
+// parent module
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use rand::Rng;
+
+use crate::engine::{KeyNotFound, KvsEngine, NotLeader, ReadView};
+use crate::raft::message::{Command, Entry, RaftMessage, RaftReply};
+use crate::raft::storage::{HardState, Storage};
+use crate::raft::RaftConfig;
+use crate::replication::{remove_keys_except, SNAPSHOT_PAGE};
+
+// entries sent in one AppendEntries at most
+const APPEND_BATCH: usize = 256;
+
+// the result of applying a command to the engine
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Applied {
+    // the previous values of a set
+    Values(Vec<Option<String>>),
+    // whether the keys of a remove were found
+    Removed(Vec<bool>),
+    Empty,
+}
+
+// Outbox holds the messages for a peer. The pages of a snapshot are read
+// from a view of the engine one by one as they are sent, without the node.
+pub(crate) enum Outbox {
+    Messages(std::vec::IntoIter<RaftMessage>),
+    Snapshot(SnapshotPages),
+}
+
+impl Outbox {
+    pub fn next(&mut self) -> Result<Option<RaftMessage>> {
+        match self {
+            Outbox::Messages(messages) => Ok(messages.next()),
+            Outbox::Snapshot(pages) => pages.next(),
+        }
+    }
+}
+
+// the data of the engine as of the last applied entry when the view was taken
+pub(crate) struct SnapshotPages {
+    view: Box<dyn ReadView>,
+    term: u64,
+    leader: String,
+    leader_client: String,
+    last_index: u64,
+    last_term: u64,
+    after: Option<String>,
+    first: bool,
+    done: bool,
+}
+
+impl SnapshotPages {
+    fn next(&mut self) -> Result<Option<RaftMessage>> {
+        if self.done {
+            return Ok(None);
+        }
+        let pairs = self
+            .view
+            .scan(String::new(), self.after.take(), SNAPSHOT_PAGE)?;
+        self.done = pairs.len() < SNAPSHOT_PAGE;
+        self.after = pairs.last().map(|(key, _)| key.clone());
+        let first = std::mem::replace(&mut self.first, false);
+        Ok(Some(RaftMessage::InstallSnapshot {
+            term: self.term,
+            leader: self.leader.clone(),
+            leader_client: self.leader_client.clone(),
+            last_index: self.last_index,
+            last_term: self.last_term,
+            pairs,
+            first,
+            done: self.done,
+        }))
+    }
+}
+
+enum Role {
+    Follower,
+    Candidate {
+        votes: HashSet<String>,
+        // the peers the vote was requested from
+        asked: HashSet<String>,
+    },
+    Leader {
+        next: HashMap<String, u64>,
+        matched: HashMap<String, u64>,
+        // when the last message a peer replied to was sent, a leader cut
+        // off from a quorum steps down instead of serving stale reads
+        acked: HashMap<String, Instant>,
+        since: Instant,
+    },
+}
+
+pub(crate) struct Node<E: KvsEngine> {
+    engine: E,
+    config: RaftConfig,
+    storage: Storage,
+    state: HardState,
+    // the entries after the snapshot, as in the log of the storage
+    entries: Vec<Entry>,
+    commit: u64,
+    applied: u64,
+    role: Role,
+    // the address clients reach the leader at
+    leader: Option<String>,
+    // when a message of the leader was last received
+    heard_at: Option<Instant>,
+    election_deadline: Instant,
+    // writes of this leader waiting for their entry to be applied, by index
+    pending: HashMap<u64, Sender<Result<Applied>>>,
+    // keys of the snapshot being installed
+    installing: Option<HashSet<String>>,
+}
+
+impl<E: KvsEngine> Node<E> {
+    pub fn open(engine: E, config: RaftConfig) -> Result<Self> {
+        let (storage, state, entries) = Storage::open(&config.dir)?;
+
+        // The entries after the snapshot are applied again once they are
+        // known to be committed. Every command writes the keys it names, so
+        // applying a suffix of the log again in order gives the same data.
+        let applied = state.snapshot_index;
+        let mut node = Node {
+            engine,
+            config,
+            storage,
+            state,
+            entries,
+            commit: applied,
+            applied,
+            role: Role::Follower,
+            leader: None,
+            heard_at: None,
+            election_deadline: Instant::now(),
+            pending: HashMap::new(),
+            installing: None,
+        };
+        node.reset_election_deadline();
+        Ok(node)
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader { .. })
+    }
+
+    pub fn leader(&self) -> Option<String> {
+        self.leader.clone()
+    }
+
+    // Whether the leader may answer reads from its engine. A quorum replied
+    // to messages sent within the lease, and no node votes for another
+    // leader for an election timeout after it heard from this one. The
+    // entry of the term is applied, so the engine has every committed write.
+    pub fn can_read(&self) -> bool {
+        self.has_lease(Instant::now()) && self.term_at(self.commit) == Some(self.state.term)
+    }
+
+    fn has_lease(&self, now: Instant) -> bool {
+        let acked = match &self.role {
+            Role::Leader { acked, .. } => acked,
+            _ => return false,
+        };
+        let lease = self.lease();
+        let acks = acked
+            .values()
+            .filter(|sent| now.duration_since(**sent) < lease)
+            .count();
+        acks + 1 >= self.quorum()
+    }
+
+    // Shorter than the shortest election timeout by a fifth of it, the
+    // clocks of the nodes may run at slightly different rates.
+    fn lease(&self) -> Duration {
+        let timeout = self.config.election_timeout;
+        timeout - timeout / 5
+    }
+
+    // whether the leader has entries the peer does not have yet
+    pub fn is_behind(&self, peer: &str) -> bool {
+        match &self.role {
+            Role::Leader { next, .. } => next.get(peer).is_none_or(|&n| n <= self.last_index()),
+            _ => false,
+        }
+    }
+
+    // Appends a write to the log of the leader. The receiver gets the result
+    // once the entry is applied, or NotLeader if the node loses the lead.
+    pub fn propose(&mut self, command: Command) -> Result<Receiver<Result<Applied>>> {
+        if !self.is_leader() {
+            return Err(NotLeader(self.leader.clone()).into());
+        }
+        let (sender, receiver) = mpsc::channel();
+        let index = self.last_index() + 1;
+        self.pending.insert(index, sender);
+        if let Err(e) = self.append(command) {
+            self.pending.remove(&index);
+            return Err(e);
+        }
+        Ok(receiver)
+    }
+
+    // Starts an election when no leader was heard from for too long, and
+    // steps a leader down that no quorum was heard from. Returns whether an
+    // election started.
+    pub fn tick(&mut self) -> Result<bool> {
+        let now = Instant::now();
+        if let Role::Leader { since, .. } = &self.role {
+            if now.duration_since(*since) > self.lease() && !self.has_lease(now) {
+                log::warn!(
+                    "{} lost the quorum in term {}",
+                    self.config.addr,
+                    self.state.term
+                );
+                self.become_follower(self.state.term, None)?;
+                self.reset_election_deadline();
+            }
+            return Ok(false);
+        }
+
+        if now < self.election_deadline {
+            return Ok(false);
+        }
+        self.state.term += 1;
+        self.state.voted_for = Some(self.config.addr.clone());
+        self.storage.save_state(&self.state)?;
+        log::info!(
+            "{} starts an election in term {}",
+            self.config.addr,
+            self.state.term
+        );
+
+        self.leader = None;
+        self.role = Role::Candidate {
+            votes: HashSet::from([self.config.addr.clone()]),
+            asked: HashSet::new(),
+        };
+        self.reset_election_deadline();
+        self.become_leader_if_elected()?;
+        Ok(true)
+    }
+
+    // the messages to send to a peer now
+    pub fn messages_for(&mut self, peer: &str) -> Result<Outbox> {
+        let term = self.state.term;
+        let next = match &mut self.role {
+            Role::Follower => return Ok(Outbox::Messages(Vec::new().into_iter())),
+            Role::Candidate { asked, .. } => {
+                if !asked.insert(peer.to_string()) {
+                    return Ok(Outbox::Messages(Vec::new().into_iter()));
+                }
+                let vote = RaftMessage::RequestVote {
+                    term,
+                    candidate: self.config.addr.clone(),
+                    last_index: self.last_index(),
+                    last_term: self.last_term(),
+                };
+                return Ok(Outbox::Messages(vec![vote].into_iter()));
+            }
+            Role::Leader { next, .. } => next.get(peer).copied().unwrap_or(1),
+        };
+
+        if next <= self.state.snapshot_index {
+            return Ok(Outbox::Snapshot(self.snapshot_pages()?));
+        }
+        let prev_index = next - 1;
+        let entries = self
+            .entries
+            .iter()
+            .skip((next - self.state.snapshot_index - 1) as usize)
+            .take(APPEND_BATCH)
+            .cloned()
+            .collect();
+        let append = RaftMessage::AppendEntries {
+            term,
+            leader: self.config.addr.clone(),
+            leader_client: self.config.client_addr.clone(),
+            prev_index,
+            prev_term: self.term_at(prev_index).unwrap_or_default(),
+            entries,
+            commit: self.commit,
+        };
+        Ok(Outbox::Messages(vec![append].into_iter()))
+    }
+
+    pub fn handle(&mut self, message: RaftMessage) -> Result<RaftReply> {
+        match message {
+            RaftMessage::RequestVote {
+                term,
+                candidate,
+                last_index,
+                last_term,
+            } => self.handle_vote(term, candidate, last_index, last_term),
+            RaftMessage::AppendEntries {
+                term,
+                leader,
+                leader_client,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => {
+                if term < self.state.term {
+                    return Ok(RaftReply::Append {
+                        term: self.state.term,
+                        success: false,
+                        next: 0,
+                    });
+                }
+                self.follow(term, &leader, leader_client)?;
+                self.handle_append(term, prev_index, prev_term, entries, commit)
+            }
+            RaftMessage::InstallSnapshot {
+                term,
+                leader,
+                leader_client,
+                last_index,
+                last_term,
+                pairs,
+                first,
+                done,
+            } => {
+                if term < self.state.term {
+                    return Ok(RaftReply::Snapshot {
+                        term: self.state.term,
+                        next: 0,
+                    });
+                }
+                self.follow(term, &leader, leader_client)?;
+                let next = self.install_snapshot(last_index, last_term, pairs, first, done)?;
+                Ok(RaftReply::Snapshot { term, next })
+            }
+        }
+    }
+
+    // sent is when the message replied to was sent
+    pub fn handle_reply(&mut self, peer: &str, reply: RaftReply, sent: Instant) -> Result<()> {
+        let term = match reply {
+            RaftReply::Vote { term, .. }
+            | RaftReply::Append { term, .. }
+            | RaftReply::Snapshot { term, .. } => term,
+        };
+        if term > self.state.term {
+            return self.become_follower(term, None);
+        }
+        if term < self.state.term {
+            return Ok(());
+        }
+
+        match (&mut self.role, reply) {
+            (Role::Candidate { votes, .. }, RaftReply::Vote { granted: true, .. }) => {
+                votes.insert(peer.to_string());
+                self.become_leader_if_elected()?;
+            }
+            (
+                Role::Leader {
+                    next,
+                    matched,
+                    acked,
+                    ..
+                },
+                reply,
+            ) => {
+                let last = acked.entry(peer.to_string()).or_insert(sent);
+                *last = (*last).max(sent);
+                match reply {
+                    RaftReply::Append {
+                        success: true,
+                        next: n,
+                        ..
+                    }
+                    | RaftReply::Snapshot { next: n, .. }
+                        if n > 0 =>
+                    {
+                        let m = matched.entry(peer.to_string()).or_default();
+                        *m = (*m).max(n - 1);
+                        next.insert(peer.to_string(), n);
+                    }
+                    RaftReply::Append {
+                        success: false,
+                        next: n,
+                        ..
+                    } => {
+                        next.insert(peer.to_string(), n.max(1));
+                    }
+                    _ => {}
+                }
+                self.advance_commit();
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_vote(
+        &mut self,
+        term: u64,
+        candidate: String,
+        last_index: u64,
+        last_term: u64,
+    ) -> Result<RaftReply> {
+        // A leader heard from within an election timeout may still hold its
+        // lease, the vote is refused without taking the term.
+        let timeout = self.config.election_timeout;
+        if self.heard_at.is_some_and(|at| at.elapsed() < timeout) {
+            return Ok(RaftReply::Vote {
+                term: self.state.term,
+                granted: false,
+            });
+        }
+        if term > self.state.term {
+            self.become_follower(term, None)?;
+        }
+
+        let up_to_date = (last_term, last_index) >= (self.last_term(), self.last_index());
+        let granted = term == self.state.term
+            && up_to_date
+            && self
+                .state
+                .voted_for
+                .as_ref()
+                .is_none_or(|voted| *voted == candidate);
+        if granted {
+            self.state.voted_for = Some(candidate);
+            self.storage.save_state(&self.state)?;
+            self.reset_election_deadline();
+        }
+        Ok(RaftReply::Vote {
+            term: self.state.term,
+            granted,
+        })
+    }
+
+    fn handle_append(
+        &mut self,
+        term: u64,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    ) -> Result<RaftReply> {
+        if prev_index > self.last_index() {
+            return Ok(RaftReply::Append {
+                term,
+                success: false,
+                next: self.last_index() + 1,
+            });
+        }
+        // the committed entries are the same on every node, so the leader
+        // can go on from there
+        if prev_index >= self.state.snapshot_index && self.term_at(prev_index) != Some(prev_term) {
+            return Ok(RaftReply::Append {
+                term,
+                success: false,
+                next: self.commit + 1,
+            });
+        }
+
+        let last_new = prev_index + entries.len() as u64;
+        // the position of the first entry written, the log is synced from
+        // there before the reply
+        let mut first_new = None;
+        for entry in entries {
+            // the entries up to the snapshot are committed
+            if entry.index <= self.state.snapshot_index {
+                continue;
+            }
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                // a conflicting entry and the ones after it were never committed
+                Some(_) => {
+                    let keep = entry.index - self.state.snapshot_index - 1;
+                    self.entries.truncate(keep as usize);
+                }
+                None => {}
+            }
+            first_new.get_or_insert(self.entries.len());
+            self.entries.push(entry);
+        }
+        if let Some(first) = first_new {
+            self.storage.write_from(first, &self.entries[first..])?;
+        }
+
+        self.commit = self.commit.max(commit.min(last_new));
+        self.apply();
+        Ok(RaftReply::Append {
+            term,
+            success: true,
+            next: last_new + 1,
+        })
+    }
+
+    // Installs a page of the data of the leader, returns the next index of
+    // the log after the last page and zero before.
+    fn install_snapshot(
+        &mut self,
+        last_index: u64,
+        last_term: u64,
+        pairs: Vec<(String, String)>,
+        first: bool,
+        done: bool,
+    ) -> Result<u64> {
+        // the data is already as new as the snapshot
+        if last_index <= self.applied {
+            self.installing = None;
+            return Ok(if done { last_index + 1 } else { 0 });
+        }
+
+        if first {
+            log::info!(
+                "{} installs the snapshot of {} at {}",
+                self.config.addr,
+                self.leader.as_deref().unwrap_or_default(),
+                last_index
+            );
+            self.installing = Some(HashSet::new());
+        }
+        let keys = self
+            .installing
+            .as_mut()
+            .ok_or_else(|| anyhow!("snapshot page received without the first one"))?;
+        keys.extend(pairs.iter().map(|(key, _)| key.clone()));
+        self.engine.set_many(pairs)?;
+        if !done {
+            return Ok(0);
+        }
+
+        let keys = self.installing.take().unwrap_or_default();
+        remove_keys_except(&self.engine, &keys)?;
+        self.engine.flush()?;
+
+        // the entries after the snapshot are kept if the log agrees with it
+        if last_index > self.state.snapshot_index && self.term_at(last_index) == Some(last_term) {
+            let dropped = last_index - self.state.snapshot_index;
+            self.entries.drain(..dropped as usize);
+        } else {
+            self.entries.clear();
+        }
+        self.state.snapshot_index = last_index;
+        self.state.snapshot_term = last_term;
+        self.commit = self.commit.max(last_index);
+        self.applied = last_index;
+        self.storage.save_state(&self.state)?;
+        self.storage.rewrite(&self.entries)?;
+        Ok(last_index + 1)
+    }
+
+    // The entries are applied under the node, so a view taken now has the
+    // data as of the last applied one.
+    fn snapshot_pages(&self) -> Result<SnapshotPages> {
+        Ok(SnapshotPages {
+            view: self.engine.read_view()?,
+            term: self.state.term,
+            leader: self.config.addr.clone(),
+            leader_client: self.config.client_addr.clone(),
+            last_index: self.applied,
+            last_term: self.term_at(self.applied).unwrap_or_default(),
+            after: None,
+            first: true,
+            done: false,
+        })
+    }
+
+    // a message of the leader of term makes this node its follower
+    fn follow(&mut self, term: u64, leader: &str, leader_client: String) -> Result<()> {
+        if self.leader.as_deref() != Some(leader_client.as_str()) {
+            log::info!("{} follows {} in term {}", self.config.addr, leader, term);
+        }
+        self.become_follower(term, Some(leader_client))?;
+        self.heard_at = Some(Instant::now());
+        self.reset_election_deadline();
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<String>) -> Result<()> {
+        if term > self.state.term {
+            self.state.term = term;
+            self.state.voted_for = None;
+            self.storage.save_state(&self.state)?;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        for (_, sender) in self.pending.drain() {
+            let _ = sender.send(Err(NotLeader(self.leader.clone()).into()));
+        }
+        Ok(())
+    }
+
+    fn become_leader_if_elected(&mut self) -> Result<()> {
+        match &self.role {
+            Role::Candidate { votes, .. } if votes.len() >= self.quorum() => {}
+            _ => return Ok(()),
+        }
+        log::info!(
+            "{} is the leader of term {}",
+            self.config.addr,
+            self.state.term
+        );
+
+        let next = self.last_index() + 1;
+        let peers = &self.config.peers;
+        self.role = Role::Leader {
+            next: peers.iter().map(|peer| (peer.clone(), next)).collect(),
+            matched: peers.iter().map(|peer| (peer.clone(), 0)).collect(),
+            acked: HashMap::new(),
+            since: Instant::now(),
+        };
+        self.leader = Some(self.config.client_addr.clone());
+        // an entry of the new term commits the entries of the older ones
+        self.append(Command::Noop).map(|_| ())
+    }
+
+    fn append(&mut self, command: Command) -> Result<u64> {
+        let index = self.last_index() + 1;
+        let entry = Entry {
+            index,
+            term: self.state.term,
+            command,
+        };
+        self.storage.append(std::slice::from_ref(&entry))?;
+        self.entries.push(entry);
+        self.advance_commit();
+        Ok(index)
+    }
+
+    // a leader commits the newest entry of its term that a quorum has
+    fn advance_commit(&mut self) {
+        if let Role::Leader { matched, .. } = &self.role {
+            let quorum = self.quorum();
+            let commit = (self.commit + 1..=self.last_index()).rev().find(|&index| {
+                self.term_at(index) == Some(self.state.term)
+                    && 1 + matched.values().filter(|&&m| m >= index).count() >= quorum
+            });
+            if let Some(commit) = commit {
+                self.commit = commit;
+            }
+        }
+        self.apply();
+    }
+
+    fn apply(&mut self) {
+        while self.applied < self.commit {
+            let index = self.applied + 1;
+            let command = match self.entry(index) {
+                Some(entry) => entry.command.clone(),
+                None => break,
+            };
+            let applied = apply_command(&self.engine, command);
+            if let Err(e) = &applied {
+                log::error!("applying entry {} has error {}", index, e);
+            }
+            self.applied = index;
+            if let Some(sender) = self.pending.remove(&index) {
+                let _ = sender.send(applied);
+            }
+        }
+        self.compact_log();
+    }
+
+    // Drops the applied entries once there are enough of them. The engine is
+    // flushed first, as it keeps the only copy of their effects then.
+    fn compact_log(&mut self) {
+        let compacted = self.applied - self.state.snapshot_index;
+        if compacted < self.config.snapshot_entries {
+            return;
+        }
+        if let Err(e) = self.engine.flush() {
+            log::error!(
+                "flushing the engine before compacting the log has error {}",
+                e
+            );
+            return;
+        }
+
+        self.state.snapshot_term = self.term_at(self.applied).unwrap_or_default();
+        self.entries.drain(..compacted as usize);
+        self.state.snapshot_index = self.applied;
+        let res = self
+            .storage
+            .save_state(&self.state)
+            .and_then(|_| self.storage.rewrite(&self.entries));
+        if let Err(e) = res {
+            log::error!("persisting the raft state has error {}", e);
+        }
+    }
+
+    fn reset_election_deadline(&mut self) {
+        let timeout = self.config.election_timeout;
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..timeout);
+        self.election_deadline = Instant::now() + timeout + jitter;
+    }
+
+    fn quorum(&self) -> usize {
+        let nodes = self.config.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.state.snapshot_index + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.state.snapshot_term, |entry| entry.term)
+    }
+
+    // none if the entry is not in the log or was compacted away
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.state.snapshot_index {
+            return Some(self.state.snapshot_term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&Entry> {
+        let offset = index.checked_sub(self.state.snapshot_index + 1)?;
+        self.entries.get(offset as usize)
+    }
+}
+
+fn apply_command<E: KvsEngine>(engine: &E, command: Command) -> Result<Applied> {
+    match command {
+        Command::Noop => Ok(Applied::Empty),
+        Command::Set(key, value) => Ok(Applied::Values(vec![engine.set(key, value)?])),
+        Command::Remove(key) => match engine.remove(key) {
+            Ok(()) => Ok(Applied::Removed(vec![true])),
+            Err(e) if e.is::<KeyNotFound>() => Ok(Applied::Removed(vec![false])),
+            Err(e) => Err(e),
+        },
+        Command::SetMany(pairs) => Ok(Applied::Values(engine.set_many(pairs)?)),
+        Command::RemoveMany(keys) => Ok(Applied::Removed(engine.remove_many(keys)?)),
+    }
+}
@@ -0,0 +1,178 @@
+// the raft state on disk: the term and vote in a small file replaced as a
+// whole, the entries in a log only appended to between snapshots
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::raft::message::Entry;
+
+// name of the file keeping the term, the vote and the snapshot position
+const STATE_FILE: &str = "raft.state";
+
+// name of the file keeping the entries after the snapshot, one per line
+const LOG_FILE: &str = "raft.log";
+
+// the state written before a message depending on it is answered
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HardState {
+    pub term: u64,
+    pub voted_for: Option<String>,
+    // the last entry dropped from the log, its effects are in the engine
+    pub snapshot_index: u64,
+    pub snapshot_term: u64,
+}
+
+// Storage keeps the entries of the log file in the order of the log in
+// memory. Every write is synced to the disk before it returns.
+pub(crate) struct Storage {
+    dir: PathBuf,
+    log: File,
+    // the offset in the log file after every entry
+    ends: Vec<u64>,
+}
+
+impl Storage {
+    // Reads the state and the entries following its snapshot. A torn last
+    // entry and the entries a snapshot replaced before the log was written
+    // again are dropped.
+    pub fn open(dir: &Path) -> Result<(Storage, HardState, Vec<Entry>)> {
+        let state: HardState = match fs::read(dir.join(STATE_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut ends = Vec::new();
+        let mut stale = false;
+        if let Ok(file) = File::open(dir.join(LOG_FILE)) {
+            let mut reader = BufReader::new(file);
+            let mut offset = 0;
+            loop {
+                let mut line = Vec::new();
+                if reader.read_until(b'\n', &mut line)? == 0 {
+                    break;
+                }
+                offset += line.len() as u64;
+                // an entry without its newline was torn by a crash
+                let entry: Entry = match line.strip_suffix(b"\n") {
+                    Some(line) => match serde_json::from_slice(line) {
+                        Ok(entry) => entry,
+                        Err(_) => {
+                            stale = true;
+                            break;
+                        }
+                    },
+                    None => {
+                        stale = true;
+                        break;
+                    }
+                };
+                // a log older than the snapshot disagrees with it from there
+                if entry.index == state.snapshot_index && entry.term != state.snapshot_term {
+                    stale = true;
+                    entries.clear();
+                    break;
+                }
+                if entry.index <= state.snapshot_index {
+                    stale = true;
+                    continue;
+                }
+                if entry.index != state.snapshot_index + entries.len() as u64 + 1 {
+                    stale = true;
+                    break;
+                }
+                entries.push(entry);
+                ends.push(offset);
+            }
+        }
+
+        let mut storage = Storage {
+            dir: dir.to_path_buf(),
+            log: OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(LOG_FILE))?,
+            ends,
+        };
+        if stale {
+            storage.rewrite(&entries)?;
+        }
+        Ok((storage, state, entries))
+    }
+
+    // written to a temporary file first, so a crash keeps the old state
+    pub fn save_state(&self, state: &HardState) -> Result<()> {
+        let path = self.dir.join(STATE_FILE);
+        let tmp = self.dir.join(format!("{}.tmp", STATE_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(state)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        self.sync_dir()
+    }
+
+    // Replaces the entries from the position in the log on, the ones before
+    // it stay as they are.
+    pub fn write_from(&mut self, first: usize, entries: &[Entry]) -> Result<()> {
+        if first < self.ends.len() {
+            let end = first.checked_sub(1).map_or(0, |last| self.ends[last]);
+            self.log.set_len(end)?;
+            self.ends.truncate(first);
+        }
+
+        let mut end = self.ends.last().copied().unwrap_or(0);
+        let mut bytes = Vec::new();
+        for entry in entries {
+            let line = encode(entry)?;
+            end += line.len() as u64;
+            bytes.extend_from_slice(&line);
+            self.ends.push(end);
+        }
+        self.log.write_all(&bytes)?;
+        self.log.sync_data()?;
+        Ok(())
+    }
+
+    pub fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        self.write_from(self.ends.len(), entries)
+    }
+
+    // Writes the log again with only the entries, after a snapshot dropped
+    // the ones before them. The state naming the snapshot is saved first.
+    pub fn rewrite(&mut self, entries: &[Entry]) -> Result<()> {
+        let path = self.dir.join(LOG_FILE);
+        let tmp = self.dir.join(format!("{}.tmp", LOG_FILE));
+        let mut file = File::create(&tmp)?;
+        let mut ends = Vec::with_capacity(entries.len());
+        let mut end = 0;
+        for entry in entries {
+            let line = encode(entry)?;
+            end += line.len() as u64;
+            file.write_all(&line)?;
+            ends.push(end);
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        self.sync_dir()?;
+
+        self.log = OpenOptions::new().append(true).open(&path)?;
+        self.ends = ends;
+        Ok(())
+    }
+
+    // a rename is only durable once the directory is synced
+    fn sync_dir(&self) -> Result<()> {
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+fn encode(entry: &Entry) -> Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    Ok(line)
+}
@@ -0,0 +1,458 @@
+// primary/replica replication by shipping the log of the primary
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::connection::Connection;
+use crate::engine::{EngineStats, KeyNotFound, KvsEngine, NotLeader, ReadView};
+use crate::kvs::Transaction;
+use crate::server::{Request, Response, Status};
+use crate::transaction::Commit;
+use crate::watch::Watcher;
+
+// records sent in one message at most
+pub const REPLICATION_BATCH: usize = 1000;
+
+// an idle primary sends a heartbeat this often, it bounds how stale a
+// replica looks while nothing is written
+pub const REPLICATION_HEARTBEAT: Duration = Duration::from_millis(250);
+
+// pairs of a snapshot sent in one message at most
+pub(crate) const SNAPSHOT_PAGE: usize = 1000;
+
+// name of the file keeping the cursor of a replica, in its data directory
+const CURSOR_FILE: &str = "replica.cursor";
+
+// LogCursor is the position in the log of the primary after the last record
+// a replica applied. It stays valid until compaction removes the log file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LogCursor {
+    pub log_id: u32,
+    pub offset: u32,
+}
+
+// the log file of a cursor was compacted away, the replica needs a snapshot
+#[derive(Debug)]
+pub struct CursorGone;
+
+impl fmt::Display for CursorGone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "log cursor was compacted away")
+    }
+}
+
+impl std::error::Error for CursorGone {}
+
+// the replica could not apply the stream of the primary to its engine, it
+// stops replicating instead of applying the same records again
+#[derive(Debug)]
+pub struct ApplyFailed;
+
+impl fmt::Display for ApplyFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "replica failed to apply the stream of its primary")
+    }
+}
+
+impl std::error::Error for ApplyFailed {}
+
+// ReplicationMessage is streamed by the primary after the Response accepting
+// a Replicate request, until either side closes the connection.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ReplicationMessage {
+    // a page of the data of the primary, the replica drops the keys missing
+    // from the pages after the last one and continues at cursor
+    Snapshot {
+        pairs: Vec<(String, String)>,
+        last: bool,
+        cursor: LogCursor,
+    },
+    // Records appended to the log, cursor is after the last of them. The
+    // batch reached the end of the log when it was read if `end` is set, a
+    // primary written to all the time may never send a heartbeat.
+    Records {
+        transactions: Vec<Transaction>,
+        cursor: LogCursor,
+        #[serde(default)]
+        end: bool,
+    },
+    // the replica has every record up to cursor, the end of the log
+    Heartbeat {
+        cursor: LogCursor,
+    },
+}
+
+// ReplicaState is the progress of a replica, shared by the replication with
+// the engine serving the reads and with the server stats.
+#[derive(Debug)]
+pub struct ReplicaState {
+    primary: String,
+    progress: Mutex<Progress>,
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    // after the last record applied
+    cursor: Option<LogCursor>,
+    // the end of the log of the primary as of its last message
+    primary_cursor: Option<LogCursor>,
+    // when the replica last had every record of the primary
+    synced_at: Option<Instant>,
+}
+
+impl ReplicaState {
+    pub fn primary(&self) -> &str {
+        &self.primary
+    }
+
+    pub fn cursor(&self) -> Option<LogCursor> {
+        self.progress().cursor
+    }
+
+    pub fn primary_cursor(&self) -> Option<LogCursor> {
+        self.progress().primary_cursor
+    }
+
+    // how long ago the replica last had every record of the primary, none if
+    // it never had
+    pub fn staleness(&self) -> Option<Duration> {
+        self.progress().synced_at.map(|at| at.elapsed())
+    }
+
+    fn progress(&self) -> MutexGuard<'_, Progress> {
+        self.progress.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Replica keeps an engine in sync with a primary. It reconnects when the
+// connection breaks and resumes at the cursor saved after every message.
+pub struct Replica<E: KvsEngine> {
+    engine: E,
+    cursor_path: PathBuf,
+    state: Arc<ReplicaState>,
+}
+
+impl<E: KvsEngine> Replica<E> {
+    // the cursor is kept in dir, the data directory of the replica
+    pub fn new(engine: E, primary: String, dir: &Path) -> Result<Self> {
+        let cursor_path = dir.join(CURSOR_FILE);
+        let cursor = match fs::read(&cursor_path) {
+            Ok(bytes) => Some(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let progress = Progress {
+            cursor,
+            ..Default::default()
+        };
+        Ok(Replica {
+            engine,
+            cursor_path,
+            state: Arc::new(ReplicaState {
+                primary,
+                progress: Mutex::new(progress),
+            }),
+        })
+    }
+
+    pub fn cursor(&self) -> Option<LogCursor> {
+        self.state.cursor()
+    }
+
+    pub fn state(&self) -> Arc<ReplicaState> {
+        self.state.clone()
+    }
+
+    // Replicates until the future is dropped, the connection is opened
+    // again when it breaks. Returns when the replica cannot apply a message.
+    pub async fn run(&mut self) -> Result<()> {
+        let primary = self.state.primary.clone();
+        loop {
+            match self.sync().await {
+                Ok(()) => log::warn!("primary {} closed the replication", primary),
+                Err(e) if e.is::<ApplyFailed>() => {
+                    log::error!("replication from {} stopped: {:#}", primary, e);
+                    return Err(e);
+                }
+                Err(e) => log::error!("replication from {} has error {}", primary, e),
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    // applies the stream of one connection to the primary
+    pub async fn sync(&mut self) -> Result<()> {
+        let primary = self.state.primary.clone();
+        let mut conn = Connection::connect(&primary).await?;
+        conn.write(Request::Replicate {
+            cursor: self.cursor(),
+        })
+        .await?;
+        match conn.read::<Response>().await? {
+            Some(response) if response.status == Status::Ok => {}
+            Some(response) => return Err(anyhow!(response.response)),
+            None => return Err(anyhow!("connection closed by server")),
+        }
+        log::info!("replicating from {} at {:?}", primary, self.cursor());
+
+        // keys of the snapshot being received
+        let mut snapshot: Option<HashSet<String>> = None;
+        while let Some(message) = conn.read::<ReplicationMessage>().await? {
+            self.handle(message, &mut snapshot).context(ApplyFailed)?;
+        }
+        Ok(())
+    }
+
+    // applies a message of the stream, the cursor is saved after the records
+    // it carries
+    fn handle(
+        &mut self,
+        message: ReplicationMessage,
+        snapshot: &mut Option<HashSet<String>>,
+    ) -> Result<()> {
+        match message {
+            ReplicationMessage::Snapshot {
+                pairs,
+                last,
+                cursor,
+            } => {
+                let keys = snapshot.get_or_insert_with(HashSet::new);
+                keys.extend(pairs.iter().map(|(key, _)| key.clone()));
+                self.engine.set_many(pairs)?;
+                if last {
+                    let keys = snapshot.take().unwrap_or_default();
+                    remove_keys_except(&self.engine, &keys)?;
+                    self.save_cursor(cursor)?;
+                }
+            }
+            ReplicationMessage::Records {
+                transactions,
+                cursor,
+                end,
+            } => {
+                // a batch holds whole commits, whose records are applied
+                // together after their marker
+                let mut transactions = transactions.into_iter();
+                while let Some(transaction) = transactions.next() {
+                    match transaction {
+                        Transaction::Begin(len) => {
+                            let records: Vec<_> =
+                                transactions.by_ref().take(len as usize).collect();
+                            if records.len() < len as usize {
+                                return Err(anyhow!("commit of {} records is cut short", len));
+                            }
+                            self.commit(records)?;
+                        }
+                        transaction => self.apply(transaction)?,
+                    }
+                }
+                self.save_cursor(cursor)?;
+                if end {
+                    self.state.progress().synced_at = Some(Instant::now());
+                }
+            }
+            // the stream is in order, so every record before the
+            // heartbeat was applied
+            ReplicationMessage::Heartbeat { cursor } => {
+                if self.cursor() != Some(cursor) {
+                    self.save_cursor(cursor)?;
+                }
+                self.state.progress().synced_at = Some(Instant::now());
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&self, transaction: Transaction) -> Result<()> {
+        match transaction {
+            Transaction::Set(key, value) => self.engine.set(key, value).map(|_| ()),
+            // a record applied again after a restart removes a missing key
+            Transaction::Remove(key) => match self.engine.remove(key) {
+                Err(e) if !e.is::<KeyNotFound>() => Err(e),
+                _ => Ok(()),
+            },
+            Transaction::Begin(_) => Err(anyhow!("commit marker inside a commit")),
+        }
+    }
+
+    // the records of a commit of the primary, applied all or nothing
+    fn commit(&self, records: Vec<Transaction>) -> Result<()> {
+        let writes = records
+            .into_iter()
+            .map(|transaction| match transaction {
+                Transaction::Set(key, value) => Ok((key, Some(value))),
+                Transaction::Remove(key) => Ok((key, None)),
+                Transaction::Begin(_) => Err(anyhow!("commit marker inside a commit")),
+            })
+            .collect::<Result<_>>()?;
+        let start = self.engine.begin_transaction()?;
+        self.engine.commit_transaction(Commit {
+            start,
+            reads: Vec::new(),
+            writes,
+        })
+    }
+
+    // written to a temporary file first, so a crash keeps the old cursor
+    fn save_cursor(&mut self, cursor: LogCursor) -> Result<()> {
+        let tmp = self.cursor_path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&cursor)?)?;
+        fs::rename(&tmp, &self.cursor_path)?;
+
+        let mut progress = self.state.progress();
+        progress.cursor = Some(cursor);
+        progress.primary_cursor = progress.primary_cursor.max(Some(cursor));
+        Ok(())
+    }
+}
+
+// ReadOnlyEngine serves the reads of a replica from its engine. Writes fail
+// with NotLeader naming the primary, which servers turn into a redirect.
+#[derive(Clone)]
+pub struct ReadOnlyEngine<E: KvsEngine> {
+    engine: E,
+    state: Arc<ReplicaState>,
+}
+
+impl<E: KvsEngine> ReadOnlyEngine<E> {
+    pub fn new(engine: E, state: Arc<ReplicaState>) -> Self {
+        ReadOnlyEngine { engine, state }
+    }
+
+    fn redirect(&self) -> anyhow::Error {
+        NotLeader(Some(self.state.primary.clone())).into()
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for ReadOnlyEngine<E> {
+    fn get(&self, key: String) -> Result<String> {
+        self.engine.get(key)
+    }
+
+    fn set(&self, _key: String, _value: String) -> Result<Option<String>> {
+        Err(self.redirect())
+    }
+
+    fn remove(&self, _key: String) -> Result<()> {
+        Err(self.redirect())
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.engine.get_many(keys)
+    }
+
+    fn set_many(&self, _pairs: Vec<(String, String)>) -> Result<Vec<Option<String>>> {
+        Err(self.redirect())
+    }
+
+    fn remove_many(&self, _keys: Vec<String>) -> Result<Vec<bool>> {
+        Err(self.redirect())
+    }
+
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.engine.scan(prefix, after, limit)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        self.engine.stats()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.engine.compact()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn snapshot(&self, path: &Path) -> Result<()> {
+        self.engine.snapshot(path)
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        self.engine.watch(prefix)
+    }
+
+    fn read_view(&self) -> Result<Box<dyn ReadView>> {
+        self.engine.read_view()
+    }
+
+    fn read_log(&self, cursor: LogCursor, limit: usize) -> Result<(Vec<Transaction>, LogCursor)> {
+        self.engine.read_log(cursor, limit)
+    }
+
+    fn log_snapshot(&self) -> Result<(Vec<(String, String)>, LogCursor)> {
+        self.engine.log_snapshot()
+    }
+
+    // a read the replica is too stale for goes to the primary
+    fn ensure_fresh(&self, max_staleness: Duration) -> Result<()> {
+        match self.state.staleness() {
+            Some(staleness) if staleness <= max_staleness => Ok(()),
+            _ => Err(self.redirect()),
+        }
+    }
+
+    // the reads of a transaction are checked where it commits, on the primary
+    fn begin_transaction(&self) -> Result<u64> {
+        Err(self.redirect())
+    }
+
+    fn commit_transaction(&self, _commit: Commit) -> Result<()> {
+        Err(self.redirect())
+    }
+}
+
+// removes the keys of the engine that are not in a snapshot just applied
+pub(crate) fn remove_keys_except<E: KvsEngine>(engine: &E, keys: &HashSet<String>) -> Result<()> {
+    let mut after = None;
+    let mut others = Vec::new();
+    loop {
+        let pairs = engine.scan(String::new(), after.take(), SNAPSHOT_PAGE)?;
+        others.extend(
+            pairs
+                .iter()
+                .filter(|(key, _)| !keys.contains(key))
+                .map(|(key, _)| key.clone()),
+        );
+        match pairs.into_iter().last() {
+            Some((key, _)) => after = Some(key),
+            None => break,
+        }
+    }
+    engine.remove_many(others)?;
+    Ok(())
+}
+
+// splits the pairs of a snapshot into messages, an empty store is one empty
+// last page
+pub(crate) fn snapshot_pages(
+    pairs: Vec<(String, String)>,
+    cursor: LogCursor,
+) -> Vec<ReplicationMessage> {
+    let mut pages: Vec<ReplicationMessage> = Vec::new();
+    let mut pairs = pairs.into_iter().peekable();
+    loop {
+        let page: Vec<_> = pairs.by_ref().take(SNAPSHOT_PAGE).collect();
+        let last = pairs.peek().is_none();
+        pages.push(ReplicationMessage::Snapshot {
+            pairs: page,
+            last,
+            cursor,
+        });
+        if last {
+            return pages;
+        }
+    }
+}
@@ -1,34 +1,147 @@
-// use std::io::Write;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use tokio::net::TcpListener;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 
 use crate::connection::Connection;
-use crate::engine::KvsEngine;
-// use crate::thread_pool::{shared_queue::SharedQueueThreadPool, ThreadPool};
+use crate::engine::{Conflict, KeyNotFound, KvsEngine, NotLeader};
+use crate::listener::Listener;
+use crate::replication::{
+    snapshot_pages, CursorGone, LogCursor, ReplicationMessage, REPLICATION_BATCH,
+    REPLICATION_HEARTBEAT,
+};
+use crate::slowlog::SlowLog;
+use crate::stats::ServerStats;
+use crate::thread_pool::work_stealing::WorkStealingThreadPool;
+use crate::thread_pool::{job, Priority, ThreadPool};
+use crate::transaction::Commit;
+use crate::watch::WatchEvent;
+
+// pairs returned by one Scan at most, larger limits are capped
+pub const MAX_SCAN_LIMIT: usize = 10_000;
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    // address of the http endpoint serving prometheus metrics, disabled if none
+    pub metrics_addr: Option<String>,
+    // token required by admin requests, admin requests are rejected if none
+    pub admin_token: Option<String>,
+    // unix domain socket listened on in addition to the serve address
+    pub unix_socket: Option<PathBuf>,
+    // permissions of the unix socket files, e.g. 0o660
+    pub unix_socket_mode: Option<u32>,
+    // requests taking at least this long are kept in the slow log, disabled if none
+    pub slowlog_threshold: Option<Duration>,
+    // entries kept in the slow log before the oldest are dropped
+    pub slowlog_max_len: usize,
+    // emit a log line for every request
+    pub log_requests: bool,
+    // transactions open for longer are aborted, so one a client forgot does
+    // not keep the old versions in the engine, never if none
+    pub transaction_timeout: Option<Duration>,
+    // workers of the pool running the engine calls, which block on the disk
+    // or until a raft entry commits, so there are more than cores
+    pub threads: u32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            metrics_addr: None,
+            admin_token: None,
+            unix_socket: None,
+            unix_socket_mode: None,
+            slowlog_threshold: Some(Duration::from_millis(10)),
+            slowlog_max_len: 128,
+            log_requests: false,
+            transaction_timeout: Some(Duration::from_secs(60)),
+            threads: 16,
+        }
+    }
+}
 
 pub struct Server<E: KvsEngine> {
     engine: E,
-    // thread_pool: SharedQueueThreadPool,
+    shared: Arc<Shared>,
+}
+
+// state shared by the connections of a server
+struct Shared {
+    config: ServerConfig,
+    stats: Arc<ServerStats>,
+    slowlog: SlowLog,
+    pool: WorkStealingThreadPool,
 }
 
 impl<E: KvsEngine> Server<E> {
     pub fn new(engine: E) -> Result<Self> {
+        Self::with_config(engine, ServerConfig::default())
+    }
+
+    pub fn with_config(engine: E, config: ServerConfig) -> Result<Self> {
+        let slowlog = SlowLog::new(config.slowlog_threshold, config.slowlog_max_len);
+        let pool = WorkStealingThreadPool::builder()
+            .threads(config.threads)
+            .thread_name("kvs-engine")
+            .build()?;
+        let stats = Arc::new(ServerStats::new());
+        stats.register_pool("engine", pool.metrics());
         Ok(Self {
             engine,
-            //thread_pool: SharedQueueThreadPool::new(12).expect("create thread pool failed"),
+            shared: Arc::new(Shared {
+                config,
+                stats,
+                slowlog,
+                pool,
+            }),
         })
     }
 
+    pub fn stats(&self) -> Arc<ServerStats> {
+        self.shared.stats.clone()
+    }
+
     pub async fn serve(&mut self, addr: String) -> Result<()> {
-        let listener = TcpListener::bind(addr).await?;
+        let config = &self.shared.config;
+        if let Some(metrics_addr) = &config.metrics_addr {
+            let listener = TcpListener::bind(metrics_addr).await?;
+            let engine = self.engine.clone();
+            let stats = self.shared.stats.clone();
+            tokio::spawn(async move {
+                if let Err(e) = Self::serve_metrics(listener, engine, stats).await {
+                    log::error!("metrics endpoint has error {}", e);
+                }
+            });
+        }
+
+        // addr is either host:port or unix:///path/to/socket, socket files
+        // are removed when the future returned by serve is dropped.
+        let listener = Listener::bind(&addr, config.unix_socket_mode).await?;
+        let unix_listener = match &config.unix_socket {
+            Some(path) => Some(Listener::bind_unix(path, config.unix_socket_mode)?),
+            None => None,
+        };
+
         loop {
-            let (stream, _) = listener.accept().await?;
+            let (stream, peer) = match &unix_listener {
+                Some(unix_listener) => tokio::select! {
+                    res = listener.accept() => res?,
+                    res = unix_listener.accept() => res?,
+                },
+                None => listener.accept().await?,
+            };
             let conn = Connection::new(stream);
             let mut engine = self.engine.clone();
+            let shared = self.shared.clone();
             tokio::spawn(async move {
-                let res = Self::process_connection(&mut engine, conn).await;
+                shared.stats.connection_opened();
+                let res = Self::process_connection(&mut engine, &shared, &peer, conn).await;
+                shared.stats.connection_closed();
                 if let Err(e) = res {
                     log::error!("connection has error {}", e);
                 }
@@ -36,73 +149,772 @@ impl<E: KvsEngine> Server<E> {
         }
     }
 
-    async fn process_connection(engine: &mut E, mut conn: Connection) -> Result<()> {
+    async fn process_connection(
+        engine: &mut E,
+        shared: &Arc<Shared>,
+        peer: &str,
+        mut conn: Connection,
+    ) -> Result<()> {
+        let mut transactions = OpenTransactions {
+            engine: engine.clone(),
+            starts: Vec::new(),
+            timeout: shared.config.transaction_timeout,
+        };
         loop {
-            let req = conn.read::<Request>().await?;
+            let deadline = transactions.deadline();
+            let req = tokio::select! {
+                req = conn.read::<Request>() => match req? {
+                    Some(req) => req,
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now).into()),
+                    if deadline.is_some() =>
+                {
+                    transactions.abort_timed_out();
+                    continue;
+                }
+            };
+            if let Request::Watch(prefix) = req {
+                shared.stats.record("watch", Duration::ZERO);
+                return Self::process_watch(engine, conn, prefix).await;
+            }
+            if let Request::Replicate { cursor } = req {
+                shared.stats.record("replicate", Duration::ZERO);
+                log::info!("replica {} connected at {:?}", peer, cursor);
+                return Self::process_replication(engine, conn, cursor).await;
+            }
 
-            if let Some(r) = req {
-                let resp = Self::process_request(engine, &r)?;
-                conn.write(Response {
-                    response: String::from_utf8(resp)?,
-                })
-                .await?;
+            // Engine calls block, a raft write until its entry commits, so
+            // they run on the engine pool instead of holding up the other
+            // connections. Background work like a compaction queues behind
+            // the requests.
+            let start = Instant::now();
+            let (mut engine, pool_shared) = (engine.clone(), shared.clone());
+            let priority = req.priority();
+            let (job, handle) = job::with_handle(move || {
+                let shared = &pool_shared;
+                let resp = Self::process_transaction(&mut engine, shared, &mut transactions, &req);
+                (resp, req, transactions)
+            });
+            shared.pool.spawn_with_priority(priority, job);
+            let (resp, req, open) = handle.await?;
+            transactions = open;
+            let elapsed = start.elapsed();
+
+            let (name, key, size) = (req.name(), req.key().unwrap_or_default(), req.size());
+            shared.stats.record(name, elapsed);
+            shared.slowlog.record(name, key, size, peer, elapsed);
+            if shared.config.log_requests {
+                log::info!(
+                    target: "kvs::request",
+                    "command={} key={:?} size={} peer={} duration_us={}",
+                    name,
+                    key,
+                    size,
+                    peer,
+                    elapsed.as_micros()
+                );
             }
+
+            conn.write(resp).await?;
         }
+
+        Ok(())
     }
 
-    fn process_request(engine: &mut E, request: &Request) -> Result<Vec<u8>> {
-        let response = Self::process_transaction(engine, &request);
-        bson::ser::to_vec(&response).map_err(|e| anyhow!(e))
+    // The connection turns into a stream of WatchEvent after the Response
+    // acknowledging the watch, until either side closes it.
+    async fn process_watch(engine: &mut E, mut conn: Connection, prefix: String) -> Result<()> {
+        let mut watcher = match engine.watch(prefix) {
+            Ok(watcher) => watcher,
+            Err(e) => return conn.write(Response::error(e.to_string())).await,
+        };
+        conn.write(Response::ok(String::new())).await?;
+
+        loop {
+            let event: Option<WatchEvent> = tokio::select! {
+                event = watcher.next() => event,
+                req = conn.read::<Request>() => match req? {
+                    Some(_) => return Err(anyhow!("request received on a watching connection")),
+                    None => return Ok(()),
+                },
+            };
+
+            match event {
+                Some(event) => conn.write(event).await?,
+                None if watcher.lagged() => {
+                    let message = "watch lagged behind the writes, the keys have to be read again";
+                    return conn.write(Response::error(message.to_string())).await;
+                }
+                None => return Ok(()),
+            }
+        }
     }
 
-    fn process_transaction(engine: &mut E, request: &Request) -> Response {
-        match request {
+    // The connection turns into a stream of ReplicationMessage after the
+    // Response accepting the replication. A replica without a cursor or with
+    // a cursor that was compacted away gets a snapshot first.
+    async fn process_replication(
+        engine: &mut E,
+        mut conn: Connection,
+        cursor: Option<LogCursor>,
+    ) -> Result<()> {
+        // subscribed before reading the log, so no append is missed between
+        // a read and the wait
+        let mut watcher = match engine.watch(String::new()) {
+            Ok(watcher) => watcher,
+            Err(e) => return conn.write(Response::error(e.to_string())).await,
+        };
+        let cursor = match cursor {
+            Some(cursor) => engine.read_log(cursor, 0).map(|_| cursor),
+            None => Err(CursorGone.into()),
+        };
+        let mut cursor = match cursor {
+            Ok(cursor) => {
+                conn.write(Response::ok(String::new())).await?;
+                cursor
+            }
+            Err(e) if e.is::<CursorGone>() => {
+                let (pairs, cursor) = match engine.log_snapshot() {
+                    Ok(snapshot) => snapshot,
+                    Err(e) => return conn.write(Response::error(e.to_string())).await,
+                };
+                conn.write(Response::ok(String::new())).await?;
+                for page in snapshot_pages(pairs, cursor) {
+                    conn.write(page).await?;
+                }
+                cursor
+            }
+            Err(e) => return conn.write(Response::error(e.to_string())).await,
+        };
+
+        loop {
+            let (transactions, next) = match engine.read_log(cursor, REPLICATION_BATCH) {
+                Ok(records) => records,
+                Err(e) if e.is::<CursorGone>() => {
+                    let (pairs, next) = engine.log_snapshot()?;
+                    for page in snapshot_pages(pairs, next) {
+                        conn.write(page).await?;
+                    }
+                    cursor = next;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            cursor = next;
+            if !transactions.is_empty() {
+                // a read stops short of the batch only at the end of the log
+                let end = transactions.len() < REPLICATION_BATCH;
+                conn.write(ReplicationMessage::Records {
+                    transactions,
+                    cursor,
+                    end,
+                })
+                .await?;
+                continue;
+            }
+
+            // Compaction sends no event, so the log is also read after every
+            // heartbeat.
+            conn.write(ReplicationMessage::Heartbeat { cursor }).await?;
+            tokio::select! {
+                event = watcher.next() => if event.is_none() {
+                    // the events only wake the replication up, the log is
+                    // read from the cursor anyway
+                    if !watcher.lagged() {
+                        return Ok(());
+                    }
+                    watcher = engine.watch(String::new())?;
+                },
+                req = conn.read::<Request>() => match req? {
+                    Some(_) => return Err(anyhow!("request received on a replicating connection")),
+                    None => return Ok(()),
+                },
+                _ = tokio::time::sleep(REPLICATION_HEARTBEAT) => {}
+            }
+            while watcher.try_next().is_some() {}
+        }
+    }
+
+    fn process_transaction(
+        engine: &mut E,
+        shared: &Shared,
+        transactions: &mut OpenTransactions<E>,
+        request: &Request,
+    ) -> Reply {
+        let response = match request {
             Request::Get(key) => Self::get(engine, key),
             Request::Set(key, value) => Self::set(engine, key, value),
             Request::Remove(key) => Self::remove(engine, key),
-        }
+            Request::Info {} => Self::info(engine, &shared.stats),
+            Request::Admin { token, command } => {
+                Self::admin(engine, &shared.config, token, command)
+            }
+            Request::SlowLog { count, reset } => Self::slowlog(&shared.slowlog, *count, *reset),
+            Request::Watch(_) => Response::error("watch is not a transaction".to_string()),
+            Request::Replicate { .. } => {
+                Response::error("replicate is not a transaction".to_string())
+            }
+            Request::MGet(keys) => return Reply::Multi(Self::get_many(engine, keys)),
+            Request::MSet(pairs) => return Reply::Multi(Self::set_many(engine, pairs)),
+            Request::MDel(keys) => return Reply::Multi(Self::remove_many(engine, keys)),
+            Request::Scan {
+                prefix,
+                after,
+                limit,
+            } => return Reply::Multi(Self::scan(engine, prefix, after, *limit)),
+            Request::Begin {} => Self::begin(engine, transactions),
+            Request::Commit(commit) => Self::commit(engine, transactions, commit),
+            Request::Abort(start) => Self::abort(transactions, *start),
+            Request::WithStaleness {
+                max_staleness_ms,
+                request,
+            } => {
+                let max_staleness = Duration::from_millis(*max_staleness_ms);
+                return match engine.ensure_fresh(max_staleness) {
+                    Ok(()) => Self::process_transaction(engine, shared, transactions, request),
+                    Err(e) => Reply::error(request, e),
+                };
+            }
+        };
+        Reply::Single(response)
+    }
+
+    fn get_many(engine: &mut E, keys: &[String]) -> MultiResponse {
+        engine
+            .get_many(keys.to_vec())
+            .map_or_else(MultiResponse::from_error, |values| MultiResponse {
+                values,
+                ..Default::default()
+            })
+    }
+
+    fn set_many(engine: &mut E, pairs: &[(String, String)]) -> MultiResponse {
+        engine
+            .set_many(pairs.to_vec())
+            .map_or_else(MultiResponse::from_error, |values| MultiResponse {
+                values,
+                ..Default::default()
+            })
+    }
+
+    fn remove_many(engine: &mut E, keys: &[String]) -> MultiResponse {
+        engine
+            .remove_many(keys.to_vec())
+            .map_or_else(MultiResponse::from_error, |removed| MultiResponse {
+                removed,
+                ..Default::default()
+            })
     }
 
     fn get(engine: &mut E, key: &String) -> Response {
-        engine.get(key.to_owned()).map_or_else(
-            |_| Response {
-                response: "Key not found".to_string(),
-            },
-            |value| Response { response: value },
-        )
+        Response::from_result(engine.get(key.to_owned()))
     }
 
     fn set(engine: &mut E, key: &String, value: &String) -> Response {
-        engine.set(key.to_owned(), value.to_owned()).map_or_else(
-            |e| Response {
-                response: e.to_string(),
-            },
-            |_| Response {
-                response: "".to_string(),
-            },
+        Response::from_result(
+            engine
+                .set(key.to_owned(), value.to_owned())
+                .map(|_| String::new()),
         )
     }
 
     fn remove(engine: &mut E, key: &String) -> Response {
-        engine.remove(key.to_owned()).map_or_else(
-            |e| Response {
-                response: e.to_string(),
-            },
-            |_| Response {
-                response: "".to_string(),
-            },
+        Response::from_result(engine.remove(key.to_owned()).map(|_| String::new()))
+    }
+
+    fn scan(engine: &mut E, prefix: &str, after: &Option<String>, limit: u32) -> MultiResponse {
+        let limit = (limit as usize).min(MAX_SCAN_LIMIT);
+        engine
+            .scan(prefix.to_string(), after.clone(), limit)
+            .map_or_else(MultiResponse::from_error, |pairs| {
+                let (keys, values) = pairs.into_iter().map(|(k, v)| (k, Some(v))).unzip();
+                MultiResponse {
+                    keys,
+                    values,
+                    ..Default::default()
+                }
+            })
+    }
+
+    fn begin(engine: &mut E, transactions: &mut OpenTransactions<E>) -> Response {
+        Response::from_result(engine.begin_transaction().map(|start| {
+            transactions.begin(start);
+            start.to_string()
+        }))
+    }
+
+    // only the connection that began a transaction ends it
+    fn commit(engine: &mut E, transactions: &mut OpenTransactions<E>, commit: &Commit) -> Response {
+        if !transactions.end(commit.start) {
+            return Response::error(format!(
+                "no transaction began at {} or it timed out",
+                commit.start
+            ));
+        }
+        Response::from_result(
+            engine
+                .commit_transaction(commit.clone())
+                .map(|_| String::new()),
         )
     }
+
+    fn abort(transactions: &mut OpenTransactions<E>, start: u64) -> Response {
+        if !transactions.end(start) {
+            return Response::error(format!("no transaction began at {} or it timed out", start));
+        }
+        transactions.engine.abort_transaction(start);
+        Response::ok(String::new())
+    }
+
+    fn info(engine: &mut E, stats: &ServerStats) -> Response {
+        Response::from_result(
+            engine
+                .stats()
+                .map(|engine_stats| stats.render_info(&engine_stats)),
+        )
+    }
+
+    fn slowlog(slowlog: &SlowLog, count: Option<u32>, reset: bool) -> Response {
+        let response = slowlog.render(count.map_or(usize::MAX, |c| c as usize));
+        if reset {
+            slowlog.reset();
+        }
+        Response::ok(response)
+    }
+
+    fn admin(
+        engine: &mut E,
+        config: &ServerConfig,
+        token: &Option<String>,
+        command: &AdminCommand,
+    ) -> Response {
+        match &config.admin_token {
+            None => return Response::error("admin commands are disabled".to_string()),
+            Some(admin_token) if !token.as_ref().is_some_and(|t| token_eq(admin_token, t)) => {
+                return Response::error("admin token is invalid".to_string())
+            }
+            _ => {}
+        }
+
+        let res = match command {
+            AdminCommand::Compact => engine.compact().map(|_| "OK".to_string()),
+            AdminCommand::Flush => engine.flush().map(|_| "OK".to_string()),
+            AdminCommand::Snapshot(path) => {
+                engine.snapshot(Path::new(path)).map(|_| "OK".to_string())
+            }
+            AdminCommand::Stats => engine
+                .stats()
+                .map(|s| format!("keys:{}\ndisk_usage:{}\n", s.keys, s.disk_usage)),
+        };
+
+        Response::from_result(res)
+    }
+
+    // A minimal http endpoint, every request to /metrics is answered with the
+    // prometheus text format and the connection is closed.
+    async fn serve_metrics(
+        listener: TcpListener,
+        engine: E,
+        stats: Arc<ServerStats>,
+    ) -> Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let engine = engine.clone();
+            let stats = stats.clone();
+            tokio::spawn(async move {
+                if let Err(e) = Self::process_metrics(stream, engine, &stats).await {
+                    log::error!("metrics connection has error {}", e);
+                }
+            });
+        }
+    }
+
+    async fn process_metrics(mut stream: TcpStream, engine: E, stats: &ServerStats) -> Result<()> {
+        let mut buf = Vec::with_capacity(1024);
+        while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < 8 * 1024 {
+            if 0 == stream.read_buf(&mut buf).await? {
+                break;
+            }
+        }
+
+        let request = String::from_utf8_lossy(&buf);
+        let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+        let (status, body) = if path != "/metrics" {
+            ("404 Not Found", "not found\n".to_string())
+        } else {
+            match engine.stats() {
+                Ok(engine_stats) => ("200 OK", stats.render_prometheus(&engine_stats)),
+                Err(e) => ("500 Internal Server Error", format!("{}\n", e)),
+            }
+        };
+
+        let resp = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        stream.write_all(resp.as_bytes()).await?;
+        stream.shutdown().await?;
+
+        Ok(())
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
     Get(String),
     Set(String, String),
     Remove(String),
+    Info {},
+    Admin {
+        token: Option<String>,
+        command: AdminCommand,
+    },
+    Watch(String),
+    // the newest `count` entries of the slow log, all if none
+    SlowLog {
+        count: Option<u32>,
+        reset: bool,
+    },
+    // the value of every key
+    MGet(Vec<String>),
+    // the previous value of every key
+    MSet(Vec<(String, String)>),
+    // whether every key was removed
+    MDel(Vec<String>),
+    // a page of the pairs whose keys start with prefix, after the key `after`
+    Scan {
+        prefix: String,
+        after: Option<String>,
+        limit: u32,
+    },
+    // sent by a replica, the log after the cursor or a snapshot if none
+    Replicate {
+        cursor: Option<LogCursor>,
+    },
+    // starts a transaction, answered with the sequence number it began at
+    Begin {},
+    // the reads and writes of a transaction begun on the connection
+    Commit(Commit),
+    // ends a transaction begun on the connection without writing
+    Abort(u64),
+    // the request, redirected to the primary if the data of a replica may be
+    // more than max_staleness_ms behind it
+    WithStaleness {
+        max_staleness_ms: u64,
+        request: Box<Request>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum AdminCommand {
+    Compact,
+    Flush,
+    Snapshot(String),
+    Stats,
+}
+
+impl Request {
+    // command name used by the stats
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Get(_) => "get",
+            Request::Set(_, _) => "set",
+            Request::Remove(_) => "remove",
+            Request::Info {} => "info",
+            Request::Admin { .. } => "admin",
+            Request::Watch(_) => "watch",
+            Request::SlowLog { .. } => "slowlog",
+            Request::MGet(_) => "mget",
+            Request::MSet(_) => "mset",
+            Request::MDel(_) => "mdel",
+            Request::Scan { .. } => "scan",
+            Request::Replicate { .. } => "replicate",
+            Request::Begin {} => "begin",
+            Request::Commit(_) => "commit",
+            Request::Abort(_) => "abort",
+            Request::WithStaleness { request, .. } => request.name(),
+        }
+    }
+
+    // the priority of the request on the engine pool
+    fn priority(&self) -> Priority {
+        match self {
+            Request::Admin {
+                command: AdminCommand::Compact | AdminCommand::Snapshot(_),
+                ..
+            } => Priority::Low,
+            _ => Priority::Normal,
+        }
+    }
+
+    // requests that can be retried without changing the result
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Request::WithStaleness { request, .. } => request.is_idempotent(),
+            _ => matches!(
+                self,
+                Request::Get(_)
+                    | Request::MGet(_)
+                    | Request::Scan { .. }
+                    | Request::Info {}
+                    | Request::SlowLog { reset: false, .. }
+            ),
+        }
+    }
+
+    // requests answered with a MultiResponse
+    fn is_multi(&self) -> bool {
+        match self {
+            Request::WithStaleness { request, .. } => request.is_multi(),
+            _ => matches!(
+                self,
+                Request::MGet(_) | Request::MSet(_) | Request::MDel(_) | Request::Scan { .. }
+            ),
+        }
+    }
+
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Request::Get(key) | Request::Set(key, _) | Request::Remove(key) => Some(key),
+            Request::Watch(prefix) | Request::Scan { prefix, .. } => Some(prefix),
+            // the first key stands for the batch in the logs
+            Request::MGet(keys) | Request::MDel(keys) => keys.first().map(|key| key.as_str()),
+            Request::MSet(pairs) => pairs.first().map(|(key, _)| key.as_str()),
+            Request::Commit(commit) => commit.writes.first().map(|(key, _)| key.as_str()),
+            Request::WithStaleness { request, .. } => request.key(),
+            _ => None,
+        }
+    }
+
+    // bytes of the keys and values carried by the request
+    pub fn size(&self) -> usize {
+        match self {
+            Request::Set(key, value) => key.len() + value.len(),
+            Request::MGet(keys) | Request::MDel(keys) => keys.iter().map(|key| key.len()).sum(),
+            Request::MSet(pairs) => pairs.iter().map(|(k, v)| k.len() + v.len()).sum(),
+            Request::Commit(commit) => (commit.reads.iter().chain(&commit.writes))
+                .map(|(k, v)| k.len() + v.as_ref().map_or(0, |v| v.len()))
+                .sum(),
+            Request::WithStaleness { request, .. } => request.size(),
+            _ => self.key().map_or(0, |key| key.len()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Response {
     pub response: String,
+    // responses of older servers have no status and are taken as ok
+    #[serde(default)]
+    pub status: Status,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    #[default]
+    Ok,
+    NotFound,
+    Error,
+    // the request is for the server at the address in the response, which
+    // is empty if no such server is known
+    Redirect,
+    // a transaction read a key written since it began and was not applied
+    Conflict,
+}
+
+impl Response {
+    pub fn ok(response: String) -> Self {
+        Response {
+            response,
+            status: Status::Ok,
+        }
+    }
+
+    pub fn error(response: String) -> Self {
+        Response {
+            response,
+            status: Status::Error,
+        }
+    }
+
+    // a missing key keeps the text of the older servers
+    pub fn not_found() -> Self {
+        Response {
+            response: KeyNotFound.to_string(),
+            status: Status::NotFound,
+        }
+    }
+
+    pub fn redirect(addr: Option<String>) -> Self {
+        Response {
+            response: addr.unwrap_or_default(),
+            status: Status::Redirect,
+        }
+    }
+
+    fn from_result(res: Result<String>) -> Self {
+        match res {
+            Ok(response) => Response::ok(response),
+            Err(e) if e.is::<KeyNotFound>() => Response::not_found(),
+            Err(e) if e.is::<Conflict>() => Response {
+                response: e.to_string(),
+                status: Status::Conflict,
+            },
+            Err(e) if e.is::<NotLeader>() => match e.downcast::<NotLeader>() {
+                Ok(NotLeader(leader)) => Response::redirect(leader),
+                Err(e) => Response::error(e.to_string()),
+            },
+            Err(e) => Response::error(e.to_string()),
+        }
+    }
+}
+
+// MultiResponse answers the multi-key requests with one entry per key in
+// request order, error is set if the whole batch failed.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct MultiResponse {
+    // the keys of a scan, the values are in the same order
+    #[serde(default)]
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub values: Vec<Option<String>>,
+    #[serde(default)]
+    pub removed: Vec<bool>,
+    pub error: Option<String>,
+    // the address the batch is for, set with the error of a redirect
+    #[serde(default)]
+    pub redirect: Option<String>,
+}
+
+impl MultiResponse {
+    fn error(error: String) -> Self {
+        MultiResponse {
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+
+    fn from_error(e: anyhow::Error) -> Self {
+        let redirect = e.downcast_ref::<NotLeader>().and_then(|e| e.0.clone());
+        MultiResponse {
+            redirect,
+            ..MultiResponse::error(e.to_string())
+        }
+    }
+
+    // the pairs of a scan
+    pub(crate) fn into_pairs(self) -> Vec<(String, String)> {
+        self.keys
+            .into_iter()
+            .zip(self.values)
+            .map(|(key, value)| (key, value.unwrap_or_default()))
+            .collect()
+    }
+
+    pub(crate) fn into_result(self) -> anyhow::Result<Self> {
+        match self.error {
+            Some(error) => Err(ServerError(error).into()),
+            None => Ok(self),
+        }
+    }
+}
+
+// an error reported by the server, as opposed to a failure to reach it
+#[derive(Debug)]
+pub struct ServerError(pub String);
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+// the transactions begun on a connection and not ended yet, which are
+// aborted when the connection closes or they time out
+struct OpenTransactions<E: KvsEngine> {
+    engine: E,
+    // the start of every transaction and when it began
+    starts: Vec<(u64, Instant)>,
+    timeout: Option<Duration>,
+}
+
+impl<E: KvsEngine> OpenTransactions<E> {
+    fn begin(&mut self, start: u64) {
+        self.starts.push((start, Instant::now()));
+    }
+
+    // whether a transaction began at start was open
+    fn end(&mut self, start: u64) -> bool {
+        match self.starts.iter().position(|&(s, _)| s == start) {
+            Some(i) => {
+                self.starts.swap_remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // when the oldest transaction times out, none if it never does
+    fn deadline(&self) -> Option<Instant> {
+        let oldest = self.starts.iter().map(|&(_, began)| began).min()?;
+        Some(oldest + self.timeout?)
+    }
+
+    fn abort_timed_out(&mut self) {
+        let Some(timeout) = self.timeout else {
+            return;
+        };
+        let engine = &self.engine;
+        self.starts.retain(|&(start, began)| {
+            if began.elapsed() < timeout {
+                return true;
+            }
+            log::info!("transaction began at {} timed out", start);
+            engine.abort_transaction(start);
+            false
+        });
+    }
+}
+
+impl<E: KvsEngine> Drop for OpenTransactions<E> {
+    fn drop(&mut self) {
+        for (start, _) in self.starts.drain(..) {
+            self.engine.abort_transaction(start);
+        }
+    }
+}
+
+// every request is answered by one of these
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum Reply {
+    Single(Response),
+    Multi(MultiResponse),
+}
+
+impl Reply {
+    // the failure of a request in the type it is answered with
+    fn error(request: &Request, e: anyhow::Error) -> Self {
+        if request.is_multi() {
+            Reply::Multi(MultiResponse::from_error(e))
+        } else {
+            Reply::Single(Response::from_result(Err(e)))
+        }
+    }
+}
+
+// Compares the tokens in a time that does not depend on where they differ,
+// so a client cannot guess the admin token byte by byte.
+fn token_eq(expected: &str, given: &str) -> bool {
+    let (expected, given) = (expected.as_bytes(), given.as_bytes());
+    let diff = (0..expected.len()).fold(expected.len() ^ given.len(), |diff, i| {
+        diff | (expected[i] ^ given.get(i).copied().unwrap_or(0)) as usize
+    });
+    diff == 0
 }
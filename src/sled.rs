@@ -1,21 +1,98 @@
-use std::path::PathBuf;
+use std::collections::BTreeSet;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
 
 use anyhow::{anyhow, Ok, Result};
-use sled::Db;
+use sled::transaction::{abort, TransactionError};
+use sled::{Db, Event, IVec};
 
-use crate::engine::KvsEngine;
+use crate::engine::{Conflict, EngineStats, KeyNotFound, KvsEngine, ReadView};
+use crate::transaction::Commit;
+use crate::versions::Versions;
+use crate::watch::{WatchEvent, Watcher};
+
+#[derive(Debug, Clone, Default)]
+pub struct SledConfig {
+    // flush every write to the disk before it is answered. Otherwise sled
+    // flushes in the background and the newest writes are lost when the
+    // process is killed.
+    pub sync_writes: bool,
+}
 
 #[derive(Clone)]
 pub struct Sled {
     pub db: Db,
+    config: SledConfig,
+    // sled has no read views of its own, the writes are numbered under this
+    // lock and the values they replace kept for the live snapshots
+    writer: Arc<Mutex<()>>,
+    seq: Arc<AtomicU64>,
+    versions: Arc<Versions<IVec>>,
 }
 
 impl Sled {
     pub fn new(root_path: &PathBuf) -> Result<Self> {
+        Self::with_config(root_path, SledConfig::default())
+    }
+
+    pub fn with_config(root_path: &PathBuf, config: SledConfig) -> Result<Self> {
         Ok(Sled {
             db: sled::open(root_path)?,
+            config,
+            writer: Arc::new(Mutex::new(())),
+            seq: Arc::new(AtomicU64::new(0)),
+            versions: Arc::new(Versions::default()),
         })
     }
+
+    // A read view of the data as of now, like KVStore::snapshot. Writers are
+    // blocked while the sequence number is taken.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let _writer = self.writer()?;
+        let seq = self.seq.load(Ordering::SeqCst);
+        self.versions.pin(seq);
+        Ok(Snapshot {
+            sled: self.clone(),
+            seq,
+        })
+    }
+
+    // like KVStore::set_snapshot_max_age
+    pub fn set_snapshot_max_age(&self, max_age: Duration) {
+        self.versions.set_max_age(max_age);
+    }
+
+    fn writer(&self) -> Result<MutexGuard<'_, ()>> {
+        self.writer.lock().map_err(|e| anyhow!(e.to_string()))
+    }
+
+    // Numbers a write of the key, and keeps the value it replaces if a
+    // snapshot is alive. The value is kept before the write is visible.
+    fn record(&self, key: &str) -> Result<()> {
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.versions.is_pinned() {
+            self.versions.record(key, seq, self.db.get(key)?);
+        }
+        Ok(())
+    }
+
+    fn sync_write(&self) -> Result<()> {
+        if self.config.sync_writes {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    // the value of the key as of seq, the current value is read first
+    fn get_at(&self, key: &str, seq: u64) -> Result<Option<IVec>> {
+        let current = self.db.get(key)?;
+        Ok(self.versions.get(key, seq).unwrap_or(current))
+    }
 }
 
 impl KvsEngine for Sled {
@@ -25,12 +102,15 @@ impl KvsEngine for Sled {
             .map(|v| v.to_vec())
             .map(String::from_utf8)
             .transpose()?
-            .ok_or(anyhow!("Key not found"))
+            .ok_or_else(|| KeyNotFound.into())
     }
 
     fn set(&self, key: String, value: String) -> Result<Option<String>> {
-        self.db
-            .insert(key, value.as_bytes())?
+        let _writer = self.writer()?;
+        self.record(&key)?;
+        let old_value = self.db.insert(key, value.as_bytes())?;
+        self.sync_write()?;
+        old_value
             .map(|v| v.to_vec())
             .map(String::from_utf8)
             .transpose()
@@ -38,9 +118,248 @@ impl KvsEngine for Sled {
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        self.db
-            .remove(key)?
-            .ok_or_else(|| anyhow!("Key not found"))
-            .map(|_| ())
+        let _writer = self.writer()?;
+        if !self.db.contains_key(&key)? {
+            return Err(KeyNotFound.into());
+        }
+        self.record(&key)?;
+        let old_value = self.db.remove(key)?;
+        self.sync_write()?;
+        old_value.ok_or_else(|| KeyNotFound.into()).map(|_| ())
+    }
+
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after.into_bytes()),
+            _ => Bound::Included(prefix.clone().into_bytes()),
+        };
+
+        let mut pairs = Vec::new();
+        for kv in self.db.range::<Vec<u8>, _>((start, Bound::Unbounded)) {
+            let (k, v) = kv?;
+            if !k.starts_with(prefix.as_bytes()) || pairs.len() >= limit {
+                break;
+            }
+            pairs.push((
+                String::from_utf8(k.to_vec())?,
+                String::from_utf8(v.to_vec())?,
+            ));
+        }
+        Ok(pairs)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: self.db.len() as u64,
+            disk_usage: self.db.size_on_disk()?,
+        })
+    }
+
+    // sled reclaims space in the background, compaction only forces a flush
+    fn compact(&self) -> Result<()> {
+        self.flush()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn snapshot(&self, path: &Path) -> Result<()> {
+        if path.exists() && path.read_dir()?.next().is_some() {
+            return Err(anyhow!("snapshot target {} is not empty", path.display()));
+        }
+
+        let target = sled::open(path)?;
+        for kv in self.db.iter() {
+            let (k, v) = kv?;
+            target.insert(k, v)?;
+        }
+        target.flush()?;
+        Ok(())
+    }
+
+    // sled checks the reads itself, the number is that of the last write
+    fn begin_transaction(&self) -> Result<u64> {
+        Ok(self.seq.load(Ordering::SeqCst))
+    }
+
+    // The values read are compared with the current ones in a sled
+    // transaction, which applies the writes if they are all the same.
+    fn commit_transaction(&self, commit: Commit) -> Result<()> {
+        let _writer = self.writer()?;
+        for (key, _) in &commit.writes {
+            self.record(key)?;
+        }
+
+        let res = self.db.transaction(|tx| {
+            for (key, value) in &commit.reads {
+                let current = tx.get(key.as_bytes())?;
+                if current.as_deref() != value.as_ref().map(|v| v.as_bytes()) {
+                    return abort(Conflict);
+                }
+            }
+            for (key, value) in &commit.writes {
+                match value {
+                    Some(value) => tx.insert(key.as_bytes(), value.as_bytes())?,
+                    None => tx.remove(key.as_bytes())?,
+                };
+            }
+            std::result::Result::Ok(())
+        });
+        match res {
+            std::result::Result::Ok(()) => {}
+            Err(TransactionError::Abort(conflict)) => return Err(conflict.into()),
+            Err(TransactionError::Storage(e)) => return Err(e.into()),
+        }
+        self.sync_write()?;
+        Ok(())
+    }
+
+    fn read_view(&self) -> Result<Box<dyn ReadView>> {
+        Ok(Box::new(Sled::snapshot(self)?))
+    }
+
+    // Events of the sled subscriber are forwarded by a thread, which checks
+    // every second whether the watcher was dropped. Sequence numbers count
+    // the events of this watch.
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        let mut subscriber = self.db.watch_prefix(prefix.as_bytes());
+        let (sender, watcher) = Watcher::channel();
+
+        thread::spawn(move || {
+            let mut seq = 0;
+            loop {
+                let event = match subscriber.next_timeout(Duration::from_secs(1)) {
+                    std::result::Result::Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) if !sender.is_closed() => continue,
+                    Err(_) => break,
+                };
+
+                seq += 1;
+                let event = match event {
+                    Event::Insert { key, value } => WatchEvent::Set {
+                        seq,
+                        key: String::from_utf8_lossy(&key).to_string(),
+                        value: String::from_utf8_lossy(&value).to_string(),
+                    },
+                    Event::Remove { key } => WatchEvent::Remove {
+                        seq,
+                        key: String::from_utf8_lossy(&key).to_string(),
+                    },
+                };
+                if !sender.send(event) {
+                    break;
+                }
+            }
+        });
+
+        Ok(watcher)
+    }
+}
+
+// Snapshot is a read view of a Sled pinned to the sequence number of a write,
+// the values it sees are kept until it is dropped.
+pub struct Snapshot {
+    sled: Sled,
+    seq: u64,
+}
+
+impl Snapshot {
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn get(&self, key: String) -> Result<String> {
+        let value = self.sled.get_at(&key, self.seq)?;
+        self.sled.versions.check(self.seq)?;
+        value
+            .map(|v| v.to_vec())
+            .map(String::from_utf8)
+            .transpose()?
+            .ok_or_else(|| KeyNotFound.into())
+    }
+
+    // Pairs of the current keys are read in order until there are `limit`,
+    // then the keys removed since the snapshot are merged in.
+    pub fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let start = match &after {
+            Some(after) if *after >= prefix => Bound::Excluded(after.clone().into_bytes()),
+            _ => Bound::Included(prefix.clone().into_bytes()),
+        };
+
+        let mut keys = BTreeSet::new();
+        for k in self
+            .sled
+            .db
+            .range::<Vec<u8>, _>((start, Bound::Unbounded))
+            .keys()
+        {
+            let k = k?;
+            if !k.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            let key = String::from_utf8(k.to_vec())?;
+            if self.sled.get_at(&key, self.seq)?.is_some() {
+                keys.insert(key);
+                if keys.len() >= limit {
+                    break;
+                }
+            }
+        }
+
+        // the removed keys after the last page key do not fit in the page
+        let last = keys.last().cloned().filter(|_| keys.len() >= limit);
+        for key in self.sled.versions.keys(&prefix) {
+            if after.as_ref().is_some_and(|after| key <= *after)
+                || last.as_ref().is_some_and(|last| key > *last)
+            {
+                continue;
+            }
+            keys.insert(key);
+        }
+
+        let mut pairs = Vec::new();
+        for key in keys {
+            if pairs.len() >= limit {
+                break;
+            }
+            if let Some(value) = self.sled.get_at(&key, self.seq)? {
+                pairs.push((key, String::from_utf8(value.to_vec())?));
+            }
+        }
+        self.sled.versions.check(self.seq)?;
+        Ok(pairs)
+    }
+}
+
+impl ReadView for Snapshot {
+    fn get(&self, key: String) -> Result<String> {
+        Snapshot::get(self, key)
+    }
+
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        Snapshot::scan(self, prefix, after, limit)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.sled.versions.unpin(self.seq);
     }
 }
@@ -0,0 +1,107 @@
+// in-memory ring buffer of the requests slower than a threshold
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Clone)]
+pub struct SlowLogEntry {
+    pub id: u64,
+    // unix time in seconds when the request finished
+    pub timestamp: u64,
+    pub duration: Duration,
+    pub command: &'static str,
+    pub key: String,
+    // bytes of the keys and values in the request
+    pub size: usize,
+    pub peer: String,
+}
+
+pub struct SlowLog {
+    threshold: Option<Duration>,
+    max_len: usize,
+
+    next_id: AtomicU64,
+    entries: Mutex<VecDeque<SlowLogEntry>>,
+}
+
+impl SlowLog {
+    // nothing is logged if threshold is none
+    pub fn new(threshold: Option<Duration>, max_len: usize) -> Self {
+        SlowLog {
+            threshold,
+            max_len,
+            next_id: AtomicU64::new(0),
+            entries: Mutex::new(VecDeque::with_capacity(max_len)),
+        }
+    }
+
+    pub fn record(
+        &self,
+        command: &'static str,
+        key: &str,
+        size: usize,
+        peer: &str,
+        duration: Duration,
+    ) {
+        match self.threshold {
+            Some(threshold) if duration >= threshold && self.max_len > 0 => {}
+            _ => return,
+        }
+
+        let entry = SlowLogEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            duration,
+            command,
+            key: key.to_string(),
+            size,
+            peer: peer.to_string(),
+        };
+
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() == self.max_len {
+            entries.pop_back();
+        }
+        entries.push_front(entry);
+    }
+
+    // the newest `count` entries, newest first
+    pub fn entries(&self, count: usize) -> Vec<SlowLogEntry> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.iter().take(count).cloned().collect()
+    }
+
+    pub fn reset(&self) {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+
+    // one `field=value` line per entry, the format returned by the SlowLog request
+    pub fn render(&self, count: usize) -> String {
+        let mut out = String::new();
+        for entry in self.entries(count) {
+            let _ = writeln!(
+                out,
+                "id={} time={} duration_us={} command={} key={:?} size={} peer={}",
+                entry.id,
+                entry.timestamp,
+                entry.duration.as_micros(),
+                entry.command,
+                entry.key,
+                entry.size,
+                entry.peer
+            );
+        }
+        out
+    }
+}
@@ -0,0 +1,430 @@
+// server statistics, exposed by the Info request and the prometheus endpoint
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::engine::EngineStats;
+use crate::replication::{LogCursor, ReplicaState};
+use crate::thread_pool::PoolMetrics;
+
+// Upper bounds of the latency histogram buckets in microseconds, the last
+// bucket (+Inf) is implicit.
+const LATENCY_BUCKETS_MICROS: [u64; 12] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000,
+];
+
+pub struct ServerStats {
+    started: Instant,
+
+    connections_total: AtomicU64,
+    connections_active: AtomicU64,
+
+    commands: RwLock<BTreeMap<&'static str, Arc<CommandStats>>>,
+    // thread pools reported with the stats, by name
+    pools: RwLock<BTreeMap<&'static str, Arc<PoolMetrics>>>,
+    // the progress of a replica, none on a primary
+    replica: RwLock<Option<Arc<ReplicaState>>>,
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerStats {
+    pub fn new() -> Self {
+        ServerStats {
+            started: Instant::now(),
+            connections_total: AtomicU64::new(0),
+            connections_active: AtomicU64::new(0),
+            commands: RwLock::new(BTreeMap::new()),
+            pools: RwLock::new(BTreeMap::new()),
+            replica: RwLock::new(None),
+        }
+    }
+
+    // reports the server as a replica with its progress
+    pub fn register_replica(&self, state: Arc<ReplicaState>) {
+        let mut replica = self.replica.write().unwrap_or_else(|e| e.into_inner());
+        *replica = Some(state);
+    }
+
+    fn replica(&self) -> Option<Arc<ReplicaState>> {
+        self.replica.read().ok().and_then(|replica| replica.clone())
+    }
+
+    // reports the counters of a pool with the stats, a pool of the same name
+    // is replaced
+    pub fn register_pool(&self, name: &'static str, metrics: Arc<PoolMetrics>) {
+        let mut pools = self.pools.write().unwrap_or_else(|e| e.into_inner());
+        pools.insert(name, metrics);
+    }
+
+    fn pools(&self) -> Vec<(&'static str, Arc<PoolMetrics>)> {
+        self.pools
+            .read()
+            .map(|pools| pools.iter().map(|(k, v)| (*k, v.clone())).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn connections_active(&self) -> u64 {
+        self.connections_active.load(Ordering::Relaxed)
+    }
+
+    pub fn connections_total(&self) -> u64 {
+        self.connections_total.load(Ordering::Relaxed)
+    }
+
+    pub fn record(&self, command: &'static str, elapsed: Duration) {
+        self.command(command).record(elapsed)
+    }
+
+    // calls of the command, zero if it was never called
+    pub fn calls(&self, command: &str) -> u64 {
+        self.commands
+            .read()
+            .map(|commands| commands.get(command).map_or(0, |c| c.calls()))
+            .unwrap_or(0)
+    }
+
+    fn command(&self, command: &'static str) -> Arc<CommandStats> {
+        if let Ok(commands) = self.commands.read() {
+            if let Some(stats) = commands.get(command) {
+                return stats.clone();
+            }
+        }
+
+        let mut commands = self.commands.write().unwrap_or_else(|e| e.into_inner());
+        commands.entry(command).or_default().clone()
+    }
+
+    fn snapshot(&self) -> Vec<(&'static str, Arc<CommandStats>)> {
+        self.commands
+            .read()
+            .map(|commands| commands.iter().map(|(k, v)| (*k, v.clone())).collect())
+            .unwrap_or_default()
+    }
+
+    // Renders the stats as `field:value` lines grouped in sections, the
+    // format returned by the Info request.
+    pub fn render_info(&self, engine: &EngineStats) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# Server");
+        let _ = writeln!(out, "version:{}", env!("CARGO_PKG_VERSION"));
+        let _ = writeln!(
+            out,
+            "uptime_in_seconds:{}",
+            self.started.elapsed().as_secs()
+        );
+
+        let _ = writeln!(out, "\n# Clients");
+        let _ = writeln!(out, "connected_clients:{}", self.connections_active());
+        let _ = writeln!(
+            out,
+            "total_connections_received:{}",
+            self.connections_total()
+        );
+
+        let _ = writeln!(out, "\n# Commandstats");
+        for (name, stats) in self.snapshot() {
+            let calls = stats.calls();
+            let usec = stats.sum_micros();
+            let per_call = if calls == 0 {
+                0.0
+            } else {
+                usec as f64 / calls as f64
+            };
+            let _ = writeln!(
+                out,
+                "cmdstat_{}:calls={},usec={},usec_per_call={:.2}",
+                name, calls, usec, per_call
+            );
+        }
+
+        let pools = self.pools();
+        if !pools.is_empty() {
+            let _ = writeln!(out, "\n# Threadpools");
+        }
+        for (name, metrics) in pools {
+            let stats = metrics.stats();
+            let _ = writeln!(
+                out,
+                "pool_{}:submitted={},completed={},panicked={},active={},queued={},usec_per_job={}",
+                name,
+                stats.submitted,
+                stats.completed,
+                stats.panicked,
+                stats.active,
+                stats.queued,
+                stats.mean_latency().as_micros()
+            );
+        }
+
+        let _ = writeln!(out, "\n# Replication");
+        match self.replica() {
+            Some(replica) => {
+                let _ = writeln!(out, "role:replica");
+                let _ = writeln!(out, "primary:{}", replica.primary());
+                let _ = writeln!(out, "cursor:{}", render_cursor(replica.cursor()));
+                let _ = writeln!(
+                    out,
+                    "primary_cursor:{}",
+                    render_cursor(replica.primary_cursor())
+                );
+                // -1 until the replica first catches up
+                let staleness = replica
+                    .staleness()
+                    .map_or(-1, |staleness| staleness.as_millis() as i64);
+                let _ = writeln!(out, "staleness_ms:{}", staleness);
+            }
+            None => {
+                let _ = writeln!(out, "role:primary");
+            }
+        }
+
+        let _ = writeln!(out, "\n# Keyspace");
+        let _ = writeln!(out, "keys:{}", engine.keys);
+        let _ = writeln!(out, "disk_usage:{}", engine.disk_usage);
+
+        out
+    }
+
+    // Renders the stats in the prometheus text exposition format.
+    pub fn render_prometheus(&self, engine: &EngineStats) -> String {
+        let mut out = String::new();
+
+        write_metric(
+            &mut out,
+            "kvs_uptime_seconds",
+            "gauge",
+            "Seconds since the server started.",
+            self.started.elapsed().as_secs(),
+        );
+        write_metric(
+            &mut out,
+            "kvs_connections_total",
+            "counter",
+            "Connections accepted by the server.",
+            self.connections_total(),
+        );
+        write_metric(
+            &mut out,
+            "kvs_connections_active",
+            "gauge",
+            "Connections currently open.",
+            self.connections_active(),
+        );
+        write_metric(
+            &mut out,
+            "kvs_keys",
+            "gauge",
+            "Keys stored in the engine.",
+            engine.keys,
+        );
+        write_metric(
+            &mut out,
+            "kvs_disk_usage_bytes",
+            "gauge",
+            "Bytes used by the engine on disk.",
+            engine.disk_usage,
+        );
+
+        let commands = self.snapshot();
+
+        let _ = writeln!(
+            out,
+            "# HELP kvs_requests_total Requests handled per command."
+        );
+        let _ = writeln!(out, "# TYPE kvs_requests_total counter");
+        for (name, stats) in commands.iter() {
+            let _ = writeln!(
+                out,
+                "kvs_requests_total{{command=\"{}\"}} {}",
+                name,
+                stats.calls()
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP kvs_request_duration_seconds Request latency per command."
+        );
+        let _ = writeln!(out, "# TYPE kvs_request_duration_seconds histogram");
+        for (name, stats) in commands.iter() {
+            let mut cumulative = 0;
+            for (i, bound) in LATENCY_BUCKETS_MICROS.iter().enumerate() {
+                cumulative += stats.buckets[i].load(Ordering::Relaxed);
+                let _ = writeln!(
+                    out,
+                    "kvs_request_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                    name,
+                    *bound as f64 / 1_000_000.0,
+                    cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "kvs_request_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}",
+                name,
+                stats.calls()
+            );
+            let _ = writeln!(
+                out,
+                "kvs_request_duration_seconds_sum{{command=\"{}\"}} {}",
+                name,
+                stats.sum_micros() as f64 / 1_000_000.0
+            );
+            let _ = writeln!(
+                out,
+                "kvs_request_duration_seconds_count{{command=\"{}\"}} {}",
+                name,
+                stats.calls()
+            );
+        }
+
+        if let Some(staleness) = self.replica().and_then(|replica| replica.staleness()) {
+            let _ = writeln!(
+                out,
+                "# HELP kvs_replica_staleness_seconds Time since the replica last had every write of the primary."
+            );
+            let _ = writeln!(out, "# TYPE kvs_replica_staleness_seconds gauge");
+            let _ = writeln!(
+                out,
+                "kvs_replica_staleness_seconds {}",
+                staleness.as_secs_f64()
+            );
+        }
+
+        self.render_pools_prometheus(&mut out);
+        out
+    }
+
+    fn render_pools_prometheus(&self, out: &mut String) {
+        let pools: Vec<_> = self
+            .pools()
+            .into_iter()
+            .map(|(name, metrics)| (name, metrics.stats()))
+            .collect();
+        if pools.is_empty() {
+            return;
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP kvs_pool_jobs_total Jobs of the thread pools by outcome."
+        );
+        let _ = writeln!(out, "# TYPE kvs_pool_jobs_total counter");
+        for (name, stats) in pools.iter() {
+            for (outcome, value) in [
+                ("submitted", stats.submitted),
+                ("completed", stats.completed),
+                ("panicked", stats.panicked),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "kvs_pool_jobs_total{{pool=\"{}\",outcome=\"{}\"}} {}",
+                    name, outcome, value
+                );
+            }
+        }
+
+        write_pool_gauge(
+            out,
+            "kvs_pool_active_workers",
+            "Workers running a job.",
+            pools.iter().map(|(name, stats)| (*name, stats.active)),
+        );
+        write_pool_gauge(
+            out,
+            "kvs_pool_queued_jobs",
+            "Jobs waiting for a worker.",
+            pools.iter().map(|(name, stats)| (*name, stats.queued)),
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP kvs_pool_job_latency_seconds_total Time from submitting to the end of the finished jobs."
+        );
+        let _ = writeln!(out, "# TYPE kvs_pool_job_latency_seconds_total counter");
+        for (name, stats) in pools.iter() {
+            let _ = writeln!(
+                out,
+                "kvs_pool_job_latency_seconds_total{{pool=\"{}\"}} {}",
+                name,
+                stats.latency_micros as f64 / 1_000_000.0
+            );
+        }
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// log_id:offset, empty if none
+fn render_cursor(cursor: Option<LogCursor>) -> String {
+    cursor.map_or_else(String::new, |cursor| {
+        format!("{}:{}", cursor.log_id, cursor.offset)
+    })
+}
+
+fn write_pool_gauge<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    values: impl Iterator<Item = (&'a str, u64)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (pool, value) in values {
+        let _ = writeln!(out, "{}{{pool=\"{}\"}} {}", name, pool, value);
+    }
+}
+
+#[derive(Default)]
+struct CommandStats {
+    calls: AtomicU64,
+    sum_micros: AtomicU64,
+    // non-cumulative counts, the +Inf bucket is `calls` minus their sum
+    buckets: [AtomicU64; LATENCY_BUCKETS_MICROS.len()],
+}
+
+impl CommandStats {
+    fn record(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+
+        if let Some(i) = LATENCY_BUCKETS_MICROS.iter().position(|&b| micros <= b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn calls(&self) -> u64 {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    fn sum_micros(&self) -> u64 {
+        self.sum_micros.load(Ordering::Relaxed)
+    }
+}
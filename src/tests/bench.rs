@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::bench::{KeyGenerator, Latencies};
+
+#[test]
+fn bench_key_generators() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let n = 1000;
+
+    let uniform = KeyGenerator::uniform(n);
+    let mut counts = vec![0u32; n as usize];
+    for _ in 0..100_000 {
+        counts[uniform.next(&mut rng) as usize] += 1;
+    }
+    assert!(counts.iter().all(|&c| c > 0));
+
+    // the first keys of a skewed distribution get most of the requests
+    let zipfian = KeyGenerator::zipfian(n, 0.99);
+    let mut counts = vec![0u32; n as usize];
+    for _ in 0..100_000 {
+        counts[zipfian.next(&mut rng) as usize] += 1;
+    }
+    assert!(counts[0] > counts[1] && counts[1] > counts[10]);
+    assert!(counts[..10].iter().sum::<u32>() > 30_000);
+    assert_eq!(KeyGenerator::zipfian(1, 0.99).next(&mut rng), 0);
+}
+
+#[test]
+fn bench_latency_percentiles() {
+    let mut latencies = Latencies::default();
+    assert_eq!(latencies.percentile(99.0), Duration::ZERO);
+
+    for micros in (1..=1000).rev() {
+        latencies.record(Duration::from_micros(micros));
+    }
+    let summary = latencies.summary();
+    assert_eq!(summary.min, Duration::from_micros(1));
+    assert_eq!(summary.p50, Duration::from_micros(500));
+    assert_eq!(summary.p99, Duration::from_micros(990));
+    assert_eq!(summary.p999, Duration::from_micros(999));
+    assert_eq!(summary.max, Duration::from_micros(1000));
+}
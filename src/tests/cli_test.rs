@@ -1,29 +1,46 @@
+use assert_cmd::assert::Assert;
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
+use serde_json::{json, Value};
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
 
+// the single json object printed by --output json
+fn json_stdout(assert: &Assert) -> Value {
+    serde_json::from_slice(&assert.get_output().stdout).unwrap()
+}
+
+// the server is killed, so sled must not keep the writes in memory
+fn server_args<'a>(engine: &'a str, addr: &'a str) -> Vec<&'a str> {
+    let mut args = vec!["--engine", engine, "--listen-addr", addr];
+    if engine == "sled" {
+        args.push("--sync-writes");
+    }
+    args
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs_server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--listen-addr", addr])
+        .args(server_args(engine, addr))
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        let _ = child.wait();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs_client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key1", "value1"])
+        .args(["--addr", addr, "set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -31,7 +48,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs_client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -39,7 +56,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs_client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key1", "value2"])
+        .args(["--addr", addr, "set", "key1", "value2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -47,7 +64,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs_client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -55,23 +72,36 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs_client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key2"])
+        .args(["--addr", addr, "get", "key2"])
         .current_dir(&temp_dir)
         .assert()
-        .success()
-        .stdout(contains("Key not found"));
+        .code(1)
+        .stdout(is_empty())
+        .stderr(contains("Key not found"));
 
     Command::cargo_bin("kvs_client")
         .unwrap()
-        .args(&["--addr", addr, "rm", "key2"])
+        .args(["--addr", addr, "rm", "key2"])
         .current_dir(&temp_dir)
         .assert()
-        .success()
-        .stdout(contains("Key not found"));
+        .code(1)
+        .stdout(is_empty())
+        .stderr(contains("Key not found"));
+
+    let assert = Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "--output", "json", "get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1);
+    assert_eq!(
+        json_stdout(&assert),
+        json!({"status": "not_found", "key": "key2"})
+    );
 
     Command::cargo_bin("kvs_client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key2", "value3"])
+        .args(["--addr", addr, "set", "key2", "value3"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -79,12 +109,101 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs_client")
         .unwrap()
-        .args(&["--addr", addr, "rm", "key1"])
+        .args(["--addr", addr, "rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
+    let assert = Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key2", "--output", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert_eq!(
+        json_stdout(&assert),
+        json!({"status": "ok", "key": "key2", "value": "value3"})
+    );
+
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "--output", "raw", "get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value3");
+
+    // admin commands are disabled on this server
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "admin", "compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(4)
+        .stdout(is_empty())
+        .stderr(contains("admin commands are disabled"));
+
+    // a script on stdin runs on one connection and keeps quoted spaces
+    assert_cmd::Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "shell"])
+        .current_dir(&temp_dir)
+        .write_stdin("# script\nset key3 \"value with spaces\"\nget key3\nrm key3\n")
+        .assert()
+        .success()
+        .stdout("value with spaces\n");
+
+    assert_cmd::Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "shell"])
+        .current_dir(&temp_dir)
+        .write_stdin("get key2\nunknown key2\nget key2\n")
+        .assert()
+        .code(5)
+        .stdout("value3\n")
+        .stderr(contains("line 2"));
+
+    // an exported file restores the removed keys
+    let dump = temp_dir.path().join("dump.bin");
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "set", "export1", "value4"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args([
+            "--addr", addr, "export", "--format", "binary", "--prefix", "export",
+        ])
+        .arg(&dump)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("exported 1 keys"));
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "rm", "export1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "import", "--format", "binary"])
+        .arg(&dump)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("imported 1 keys"));
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "get", "export1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value4\n");
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -92,30 +211,31 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs_server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--listen-addr", addr])
+        .args(server_args(engine, addr))
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        let _ = child.wait();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs_client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key2"])
+        .args(["--addr", addr, "get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs_client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
-        .success()
-        .stdout(contains("Key not found"));
+        .code(1)
+        .stderr(contains("Key not found"));
     sender.send(()).unwrap();
     handle.join().unwrap();
 }
@@ -129,3 +249,46 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:10011");
 }
+
+#[test]
+fn cli_version_without_server() {
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["V"])
+        .assert()
+        .success()
+        .stdout(contains(env!("CARGO_PKG_VERSION")));
+
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["get", "key1"])
+        .assert()
+        .code(2)
+        .stderr(contains("--addr"));
+}
+
+#[test]
+fn cli_connection_error() {
+    // nothing listens on the port
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:10013", "get", "key1"])
+        .assert()
+        .code(3)
+        .stdout(is_empty())
+        .stderr(contains("error:"));
+
+    let assert = Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args([
+            "--addr",
+            "127.0.0.1:10013",
+            "--output",
+            "json",
+            "get",
+            "key1",
+        ])
+        .assert()
+        .code(3);
+    assert_eq!(json_stdout(&assert)["status"], "connection_error");
+}
@@ -0,0 +1,372 @@
+use std::io::{Cursor, Write};
+use std::time::Duration;
+
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+
+use crate::client::dump::{DumpFormat, DumpReader, DumpWriter};
+use crate::client::sharded::{fnv1a, HashRing};
+use crate::client::{blocking, Client, ClientPool, PoolConfig, ShardedClient};
+use crate::engine::Conflict;
+use crate::kvs::KVStore;
+use crate::server::{Request, Response, Server};
+use crate::sled::Sled;
+
+fn spawn_server(addr: &str, dir: &TempDir) {
+    let mut server = Server::new(KVStore::new(dir.path()).unwrap()).unwrap();
+    let addr = addr.to_string();
+    tokio::spawn(async move { server.serve(addr).await });
+}
+
+#[tokio::test]
+async fn pool_concurrent_requests() {
+    let addr = "127.0.0.1:12401";
+    let dir = TempDir::new().unwrap();
+    spawn_server(addr, &dir);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let pool = ClientPool::new(
+        addr,
+        PoolConfig {
+            size: 4,
+            ..Default::default()
+        },
+    );
+
+    let mut handles = Vec::new();
+    for i in 0..32 {
+        let pool = pool.clone();
+        handles.push(tokio::spawn(async move {
+            pool.set(format!("key-{}", i), format!("value-{}", i))
+                .await
+                .unwrap();
+            pool.get(format!("key-{}", i)).await.unwrap()
+        }));
+    }
+
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.await.unwrap(), format!("value-{}", i));
+    }
+    assert!(pool.idle() <= 4);
+}
+
+#[tokio::test]
+async fn pool_reconnects_with_backoff() {
+    let addr = "127.0.0.1:12402";
+    let dir = TempDir::new().unwrap();
+    let pool = ClientPool::new(
+        addr,
+        PoolConfig {
+            max_retries: 8,
+            backoff_base: Duration::from_millis(20),
+            backoff_max: Duration::from_millis(200),
+            ..Default::default()
+        },
+    );
+
+    // the server comes up while the pool is retrying
+    let server_addr = addr.to_string();
+    let server_dir = dir.path().to_path_buf();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let mut server = Server::new(KVStore::new(&server_dir).unwrap()).unwrap();
+        server.serve(server_addr).await
+    });
+
+    assert_eq!(pool.get("key1".into()).await.unwrap(), "Key not found");
+}
+
+#[tokio::test]
+async fn pool_request_timeout() {
+    let addr = "127.0.0.1:12403";
+    // accepts connections but never answers
+    let listener = TcpListener::bind(addr).await.unwrap();
+    tokio::spawn(async move {
+        let mut streams = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            streams.push(stream);
+        }
+    });
+
+    let pool = ClientPool::new(
+        addr,
+        PoolConfig {
+            timeout: Duration::from_millis(100),
+            max_retries: 1,
+            backoff_base: Duration::from_millis(10),
+            ..Default::default()
+        },
+    );
+
+    let err = pool.get("key1".into()).await.unwrap_err();
+    assert_eq!(err.to_string(), "request timed out");
+}
+
+#[test]
+fn blocking_client_requests() {
+    let addr = "127.0.0.1:12404";
+    let dir = TempDir::new().unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut server = Server::new(KVStore::new(dir.path()).unwrap()).unwrap();
+    runtime.spawn(async move { server.serve(addr.to_string()).await });
+    std::thread::sleep(Duration::from_millis(200));
+
+    let mut client = blocking::Client::connect(addr).unwrap();
+    client.set_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    assert_eq!(client.set("key1".into(), "value1".into()).unwrap(), "");
+    assert_eq!(client.get("key1".into()).unwrap(), "value1");
+    assert_eq!(client.remove("key1".into()).unwrap(), "");
+    assert_eq!(client.get("key1".into()).unwrap(), "Key not found");
+    assert!(client.info().unwrap().contains("cmdstat_get:calls=2,"));
+}
+
+#[test]
+fn blocking_client_reconnects_after_a_timeout() {
+    let addr = "127.0.0.1:12410";
+    let listener = std::net::TcpListener::bind(addr).unwrap();
+    // the rest of the first response arrives after the client gave up on it
+    let server = std::thread::spawn(move || {
+        let (mut first, _) = listener.accept().unwrap();
+        bson::from_reader::<_, bson::Document>(&mut first).unwrap();
+        let bytes = bson::to_vec(&Response::ok("1".to_string())).unwrap();
+        first.write_all(&bytes[..5]).unwrap();
+
+        let (mut second, _) = listener.accept().unwrap();
+        first.write_all(&bytes[5..]).unwrap();
+        bson::from_reader::<_, bson::Document>(&mut second).unwrap();
+        let bytes = bson::to_vec(&Response::ok("2".to_string())).unwrap();
+        second.write_all(&bytes).unwrap();
+    });
+
+    let mut client = blocking::Client::connect(addr).unwrap();
+    client
+        .set_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    assert!(client.get("key1".into()).is_err());
+    // the rest of the first response is not read as the second one
+    assert_eq!(client.get("key1".into()).unwrap(), "2");
+    drop(client);
+    server.join().unwrap();
+}
+
+#[tokio::test]
+async fn client_multi_key_requests() {
+    let addr = "127.0.0.1:12405";
+    let dir = TempDir::new().unwrap();
+    let mut server = Server::new(Sled::new(&dir.path().to_path_buf()).unwrap()).unwrap();
+    tokio::spawn(async move { server.serve(addr.to_string()).await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut client = Client::connect(addr).await.unwrap();
+    let old = client
+        .set_many(vec![
+            ("key1".to_string(), "value1".to_string()),
+            ("key2".to_string(), "value2".to_string()),
+            ("key1".to_string(), "value3".to_string()),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(old, vec![None, None, Some("value1".to_string())]);
+
+    let values = client
+        .get_many(vec!["key1".into(), "key2".into(), "key3".into()])
+        .await
+        .unwrap();
+    assert_eq!(
+        values,
+        vec![Some("value3".to_string()), Some("value2".to_string()), None]
+    );
+
+    let removed = client
+        .remove_many(vec!["key2".into(), "key3".into()])
+        .await
+        .unwrap();
+    assert_eq!(removed, vec![true, false]);
+    assert_eq!(client.get("key2".into()).await.unwrap(), "Key not found");
+    assert!(client
+        .info()
+        .await
+        .unwrap()
+        .contains("cmdstat_mget:calls=1,"));
+}
+
+#[test]
+fn dump_formats_round_trip() {
+    let pairs = vec![
+        ("key1".to_string(), "value1".to_string()),
+        ("key \"2\"".to_string(), "line\nbreak".to_string()),
+        ("".to_string(), "".to_string()),
+    ];
+
+    for format in [DumpFormat::Json, DumpFormat::Binary] {
+        let mut writer = DumpWriter::new(Vec::new(), format).unwrap();
+        for (key, value) in pairs.iter() {
+            writer.write(key, value).unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+
+        let reader = DumpReader::new(Cursor::new(&bytes), format).unwrap();
+        let read: Vec<_> = reader.collect::<anyhow::Result<_>>().unwrap();
+        assert_eq!(read, pairs);
+
+        // a truncated dump is an error rather than a shorter one
+        let reader = DumpReader::new(Cursor::new(&bytes[..bytes.len() - 3]), format).unwrap();
+        assert!(reader.collect::<anyhow::Result<Vec<_>>>().is_err());
+    }
+}
+
+#[test]
+fn hash_ring_moves_keys_to_added_node() {
+    assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+    assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+
+    let mut ring = HashRing::new(160);
+    for node in ["node1", "node2", "node3"] {
+        ring.add(node);
+    }
+    let keys: Vec<String> = (0..10_000).map(|i| format!("key-{}", i)).collect();
+    let before: Vec<String> = keys
+        .iter()
+        .map(|key| ring.node(key).unwrap().to_string())
+        .collect();
+    for node in ["node1", "node2", "node3"] {
+        let count = before.iter().filter(|n| *n == node).count();
+        assert!((2000..4700).contains(&count), "{} has {} keys", node, count);
+    }
+
+    ring.add("node4");
+    let mut moved = 0;
+    for (key, old) in keys.iter().zip(before.iter()) {
+        let new = ring.node(key).unwrap();
+        if new != old {
+            assert_eq!(new, "node4");
+            moved += 1;
+        }
+    }
+    assert!((1500..3500).contains(&moved), "{} keys moved", moved);
+}
+
+#[tokio::test]
+async fn sharded_client_routes_and_migrates() {
+    let addrs = ["127.0.0.1:12406", "127.0.0.1:12407", "127.0.0.1:12408"];
+    let dirs: Vec<TempDir> = addrs.iter().map(|_| TempDir::new().unwrap()).collect();
+    for (addr, dir) in addrs.iter().zip(dirs.iter()) {
+        spawn_server(addr, dir);
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut sharded = ShardedClient::connect(&addrs[..2]).await.unwrap();
+    let pairs: Vec<(String, String)> = (0..100)
+        .map(|i| (format!("key-{:03}", i), format!("value-{}", i)))
+        .collect();
+    sharded.set_many(pairs.clone()).await.unwrap();
+    let keys: Vec<String> = pairs.iter().map(|(key, _)| key.clone()).collect();
+    let values = sharded.get_many(keys.clone()).await.unwrap();
+    assert_eq!(
+        values,
+        pairs
+            .iter()
+            .map(|(_, v)| Some(v.clone()))
+            .collect::<Vec<_>>()
+    );
+
+    // a scan over all servers pages in key order
+    let page = sharded.scan("key-".into(), None, 10).await.unwrap();
+    assert_eq!(page, pairs[..10].to_vec());
+    let page = sharded
+        .scan("key-".into(), Some("key-094".into()), 10)
+        .await
+        .unwrap();
+    assert_eq!(page, pairs[95..].to_vec());
+
+    let migrations = sharded.add_node(addrs[2]).await.unwrap();
+    assert!(!migrations.is_empty());
+    assert!(migrations.iter().all(|m| m.to == addrs[2]));
+    for migration in migrations.iter() {
+        assert_eq!(sharded.node_for(&migration.key), addrs[2]);
+    }
+    sharded.migrate(&migrations).await.unwrap();
+
+    // every key is only on the server it is routed to
+    assert_eq!(
+        sharded.get_many(keys.clone()).await.unwrap(),
+        pairs
+            .iter()
+            .map(|(_, v)| Some(v.clone()))
+            .collect::<Vec<_>>()
+    );
+    let mut stored = 0;
+    for addr in addrs {
+        let mut client = Client::connect(addr).await.unwrap();
+        let on_node = client.scan(String::new(), None, 1000).await.unwrap();
+        assert!(on_node.iter().all(|(key, _)| sharded.node_for(key) == addr));
+        stored += on_node.len();
+    }
+    assert_eq!(stored, 100);
+
+    assert_eq!(
+        sharded
+            .remove_many(vec!["key-001".into(), "missing".into()])
+            .await
+            .unwrap(),
+        vec![true, false]
+    );
+}
+
+#[tokio::test]
+async fn client_transactions_commit_and_conflict() {
+    let addr = "127.0.0.1:12409";
+    let dir = TempDir::new().unwrap();
+    spawn_server(addr, &dir);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut client = Client::connect(addr).await.unwrap();
+    let mut other = Client::connect(addr).await.unwrap();
+    client.set("a".into(), "10".into()).await.unwrap();
+
+    let mut txn = client.begin().await.unwrap();
+    assert_eq!(txn.get("a".into()).await.unwrap(), Some("10".into()));
+    assert_eq!(txn.get("b".into()).await.unwrap(), None);
+    txn.set("a".into(), "7".into());
+    txn.set("b".into(), "3".into());
+    assert_eq!(txn.get("b".into()).await.unwrap(), Some("3".into()));
+    txn.commit().await.unwrap();
+    assert_eq!(
+        client.get_many(vec!["a".into(), "b".into()]).await.unwrap(),
+        vec![Some("7".into()), Some("3".into())]
+    );
+
+    // a key read by the transaction is written by another client
+    let mut txn = client.begin().await.unwrap();
+    txn.get("a".into()).await.unwrap();
+    txn.remove("b".into());
+    other.set("a".into(), "8".into()).await.unwrap();
+    let e = txn.commit().await.unwrap_err();
+    assert!(e.is::<Conflict>(), "{}", e);
+    assert_eq!(client.get("b".into()).await.unwrap(), "3");
+
+    // another connection cannot end the transaction
+    let txn = client.begin().await.unwrap();
+    let start = txn.start();
+    assert!(other
+        .call(Request::Abort(start))
+        .await
+        .unwrap()
+        .response
+        .contains("no transaction"));
+    txn.abort().await.unwrap();
+
+    // a transaction dropped while open is aborted before the next request
+    let mut txn = client.begin().await.unwrap();
+    txn.set("c".into(), "1".into());
+    let start = txn.start();
+    drop(txn);
+    assert_eq!(client.get("c".into()).await.unwrap(), "Key not found");
+    assert!(client
+        .call(Request::Abort(start))
+        .await
+        .unwrap()
+        .response
+        .contains("no transaction"));
+}
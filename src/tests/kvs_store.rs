@@ -1,17 +1,25 @@
 use std::fs;
 use std::thread;
+use std::time::Duration;
 
 use anyhow::Result;
 use crossbeam::sync::WaitGroup;
 use tempfile::TempDir;
 
-use crate::{engine::KvsEngine, kvs::KVStore};
+use crate::{
+    engine::{Conflict, KvsEngine, SnapshotExpired},
+    kvs::{self, KVStore},
+    replication::{CursorGone, LogCursor},
+    sled::Sled,
+    transaction::Transaction,
+    watch::{WatchEvent, WATCH_BUFFER},
+};
 
 #[test]
 fn kvs_engine_new_write_log() {
     let tmp_dir = TempDir::new().unwrap();
     let path = tmp_dir.path();
-    let kv_store = KVStore::new(&path.to_path_buf()).unwrap();
+    let kv_store = KVStore::new(path).unwrap();
     let mut key_id = 1;
 
     loop {
@@ -20,7 +28,7 @@ fn kvs_engine_new_write_log() {
             .set(key_id.to_string(), (key_id * 20).to_string())
             .unwrap();
 
-        let mut files = fs::read_dir(&path.join("db"))
+        let mut files = fs::read_dir(path.join("db"))
             .unwrap()
             .map(|e| e.map(|e| e.path()))
             .collect::<Result<Vec<_>, std::io::Error>>()
@@ -42,7 +50,7 @@ fn kvs_engine_new_write_log() {
 fn kvs_engine_compress() {
     let tmp_dir = TempDir::new().unwrap();
     let path = tmp_dir.path();
-    let mut kv_store = KVStore::new(&path.to_path_buf()).unwrap();
+    let kv_store = KVStore::new(path).unwrap();
     let mut key_id = 1;
     loop {
         key_id += 1;
@@ -50,7 +58,7 @@ fn kvs_engine_compress() {
             .set(key_id.to_string(), (key_id * 20).to_string())
             .unwrap();
 
-        let mut files = fs::read_dir(&path.join("db"))
+        let mut files = fs::read_dir(path.join("db"))
             .unwrap()
             .map(|e| e.map(|e| e.path()))
             .collect::<Result<Vec<_>, std::io::Error>>()
@@ -64,7 +72,7 @@ fn kvs_engine_compress() {
     }
 
     kv_store.compress_by_index().unwrap();
-    let mut files = fs::read_dir(&path.join("db"))
+    let mut files = fs::read_dir(path.join("db"))
         .unwrap()
         .map(|e| e.map(|e| e.path()))
         .collect::<Result<Vec<_>, std::io::Error>>()
@@ -83,7 +91,7 @@ fn kvs_engine_compress() {
 fn kvs_concurrent() {
     let tmp_dir = TempDir::new().unwrap();
     let path = tmp_dir.path();
-    let kv_store = KVStore::new(&path.to_path_buf()).unwrap();
+    let kv_store = KVStore::new(path).unwrap();
 
     let wg = WaitGroup::new();
     for i in 0..100 {
@@ -111,3 +119,537 @@ fn kvs_concurrent() {
 
     wg.wait()
 }
+
+#[test]
+fn kvs_compress_then_write_and_reopen() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+    let kv_store = KVStore::new(path).unwrap();
+
+    kv_store.set("key1".into(), "value1".into()).unwrap();
+    kv_store.set("key2".into(), "value2".into()).unwrap();
+    kv_store.compress_by_index().unwrap();
+
+    kv_store.set("key1".into(), "value3".into()).unwrap();
+    kv_store.remove("key2".into()).unwrap();
+    kv_store.compress_by_index().unwrap();
+    kv_store.set("key4".into(), "value4".into()).unwrap();
+    drop(kv_store);
+
+    let kv_store = KVStore::new(path).unwrap();
+    assert_eq!(kv_store.get("key1".into()).unwrap(), "value3");
+    assert!(kv_store.get("key2".into()).is_err());
+    assert_eq!(kv_store.get("key4".into()).unwrap(), "value4");
+
+    kv_store.set("key5".into(), "value5".into()).unwrap();
+    assert_eq!(kv_store.get("key5".into()).unwrap(), "value5");
+    assert_eq!(kv_store.get("key1".into()).unwrap(), "value3");
+}
+
+fn log_files(path: &std::path::Path) -> Vec<String> {
+    let mut files: Vec<String> = fs::read_dir(path.join("db"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn kvs_compress_seals_the_active_log() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+    let kv_store = KVStore::new(path).unwrap();
+
+    kv_store.set("key1".into(), "value1".into()).unwrap();
+    kv_store.set("key2".into(), "value2".into()).unwrap();
+    assert_eq!(log_files(path), vec!["0.log"]);
+
+    // the active log stays, the compressed one gets the next id and the
+    // write after it goes to a new log
+    kv_store.compress_by_index().unwrap();
+    assert_eq!(log_files(path), vec!["0.log", "1.log"]);
+    kv_store.set("key1".into(), "value3".into()).unwrap();
+    assert_eq!(log_files(path), vec!["0.log", "1.log", "2.log"]);
+
+    // without writes in between, the newest log is the compacted one and the
+    // next compaction keeps it instead of the sealed active log
+    kv_store.compress_by_index().unwrap();
+    kv_store.compress_by_index().unwrap();
+    assert_eq!(log_files(path), vec!["3.log", "4.log"]);
+    kv_store.remove("key2".into()).unwrap();
+    assert_eq!(log_files(path), vec!["3.log", "4.log", "5.log"]);
+    drop(kv_store);
+
+    let kv_store = KVStore::new(path).unwrap();
+    assert_eq!(kv_store.get("key1".into()).unwrap(), "value3");
+    assert!(kv_store.get("key2".into()).is_err());
+    kv_store.set("key3".into(), "value3".into()).unwrap();
+    assert_eq!(log_files(path).last().unwrap(), "5.log");
+}
+
+#[test]
+fn kvs_cursor_reads_on_across_compaction() {
+    let tmp_dir = TempDir::new().unwrap();
+    let kv_store = KVStore::new(tmp_dir.path()).unwrap();
+    kv_store.set("key1".into(), "value1".into()).unwrap();
+    kv_store.set("key1".into(), "value2".into()).unwrap();
+    let start = LogCursor {
+        log_id: 0,
+        offset: 0,
+    };
+    let (_, behind) = kv_store.read_log(start, 1).unwrap();
+    let (transactions, caught_up) = kv_store.read_log(start, 10).unwrap();
+    assert_eq!(transactions.len(), 2);
+
+    // the sealed log is kept, a cursor at its end skips the compacted log
+    // and one in it reads the rest first
+    kv_store.compress_by_index().unwrap();
+    kv_store.set("key2".into(), "value3".into()).unwrap();
+    let (transactions, cursor) = kv_store.read_log(caught_up, 10).unwrap();
+    assert!(matches!(
+        transactions.as_slice(),
+        [kvs::Transaction::Set(key, _)] if key == "key2"
+    ));
+    assert_eq!(cursor.log_id, 2);
+    let (transactions, _) = kv_store.read_log(behind, 10).unwrap();
+    assert_eq!(transactions.len(), 2);
+
+    // without writes in between, the next compaction keeps the end of the
+    // sealed log
+    kv_store.compress_by_index().unwrap();
+    kv_store.compress_by_index().unwrap();
+    kv_store.set("key3".into(), "value4".into()).unwrap();
+    let (transactions, _) = kv_store.read_log(cursor, 10).unwrap();
+    assert_eq!(transactions.len(), 1);
+    drop(kv_store);
+
+    // the cursors of the merged logs are gone, also after a restart
+    let kv_store = KVStore::new(tmp_dir.path()).unwrap();
+    assert!(kv_store
+        .read_log(behind, 10)
+        .unwrap_err()
+        .downcast_ref::<CursorGone>()
+        .is_some());
+    let (transactions, _) = kv_store.read_log(cursor, 10).unwrap();
+    assert_eq!(transactions.len(), 1);
+}
+
+#[test]
+fn kvs_compress_while_writing() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+    let kv_store = KVStore::new(path).unwrap();
+
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let kvs = kv_store.clone();
+            thread::spawn(move || {
+                for i in 0..200 {
+                    kvs.set(format!("key-{}", t), format!("value-{}", i))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for _ in 0..20 {
+        kv_store.compress_by_index().unwrap();
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    drop(kv_store);
+
+    // the last write of every key is replayed last
+    let kv_store = KVStore::new(path).unwrap();
+    for t in 0..4 {
+        assert_eq!(kv_store.get(format!("key-{}", t)).unwrap(), "value-199");
+    }
+}
+
+#[test]
+fn kvs_watch_prefix() {
+    let tmp_dir = TempDir::new().unwrap();
+    let kv_store = KVStore::new(tmp_dir.path()).unwrap();
+    let mut watcher = kv_store.watch("user/".to_string()).unwrap();
+
+    kv_store.set("user/1".into(), "a".into()).unwrap();
+    kv_store.set("order/1".into(), "b".into()).unwrap();
+    kv_store.remove("user/1".into()).unwrap();
+
+    assert_eq!(
+        watcher.blocking_next(),
+        Some(WatchEvent::Set {
+            seq: 1,
+            key: "user/1".into(),
+            value: "a".into()
+        })
+    );
+    assert_eq!(
+        watcher.blocking_next(),
+        Some(WatchEvent::Remove {
+            seq: 3,
+            key: "user/1".into()
+        })
+    );
+}
+
+#[test]
+fn kvs_watch_closes_lagging_watcher() {
+    let tmp_dir = TempDir::new().unwrap();
+    let kv_store = KVStore::new(tmp_dir.path()).unwrap();
+    let mut watcher = kv_store.watch("user/".to_string()).unwrap();
+
+    for i in 0..WATCH_BUFFER + 1 {
+        kv_store.set(format!("user/{}", i), i.to_string()).unwrap();
+    }
+
+    // the buffered events are still received before the end
+    let mut received = 0;
+    while let Some(event) = watcher.blocking_next() {
+        assert_eq!(event.seq(), received as u64 + 1);
+        received += 1;
+    }
+    assert_eq!(received, WATCH_BUFFER);
+    assert!(watcher.lagged());
+
+    // a watcher that keeps up is not affected
+    let mut watcher = kv_store.watch("user/".to_string()).unwrap();
+    kv_store.set("user/1".into(), "a".into()).unwrap();
+    assert_eq!(watcher.blocking_next().unwrap().key(), "user/1");
+    assert!(!watcher.lagged());
+}
+
+#[test]
+fn kvs_multi_key_requests() {
+    let tmp_dir = TempDir::new().unwrap();
+    let kv_store = KVStore::new(tmp_dir.path()).unwrap();
+    kv_store.set("a".to_string(), "1".to_string()).unwrap();
+
+    let old = kv_store
+        .set_many(vec![
+            ("a".to_string(), "2".to_string()),
+            ("b".to_string(), "3".to_string()),
+            ("b".to_string(), "4".to_string()),
+        ])
+        .unwrap();
+    assert_eq!(
+        old,
+        vec![Some("1".to_string()), None, Some("3".to_string())]
+    );
+
+    let values = kv_store
+        .get_many(vec!["a".to_string(), "b".to_string(), "c".to_string()])
+        .unwrap();
+    assert_eq!(
+        values,
+        vec![Some("2".to_string()), Some("4".to_string()), None]
+    );
+
+    let mut watcher = kv_store.watch(String::new()).unwrap();
+    let removed = kv_store
+        .remove_many(vec!["a".to_string(), "c".to_string()])
+        .unwrap();
+    assert_eq!(removed, vec![true, false]);
+    assert_eq!(
+        watcher.try_next(),
+        Some(WatchEvent::Remove {
+            seq: 5,
+            key: "a".into()
+        })
+    );
+    assert_eq!(watcher.try_next(), None);
+
+    drop(kv_store);
+    let kv_store = KVStore::new(tmp_dir.path()).unwrap();
+    let values = kv_store
+        .get_many(vec!["a".to_string(), "b".to_string()])
+        .unwrap();
+    assert_eq!(values, vec![None, Some("4".to_string())]);
+}
+
+#[test]
+fn kvs_scan_pages_by_prefix() {
+    let tmp_dir = TempDir::new().unwrap();
+    let kv_store = KVStore::new(tmp_dir.path()).unwrap();
+    for i in 0..10 {
+        kv_store.set(format!("a{}", i), i.to_string()).unwrap();
+        kv_store.set(format!("b{}", i), i.to_string()).unwrap();
+    }
+    kv_store.remove("a5".to_string()).unwrap();
+
+    let mut keys = Vec::new();
+    let mut after = None;
+    loop {
+        let page = kv_store.scan("a".to_string(), after.take(), 4).unwrap();
+        assert!(page.len() <= 4);
+        match page.last() {
+            Some((key, _)) => after = Some(key.clone()),
+            None => break,
+        }
+        keys.extend(page.into_iter().map(|(key, _)| key));
+    }
+    assert_eq!(
+        keys,
+        vec!["a0", "a1", "a2", "a3", "a4", "a6", "a7", "a8", "a9"]
+    );
+}
+
+fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[test]
+fn kvs_snapshot_survives_writes_and_compaction() {
+    let tmp_dir = TempDir::new().unwrap();
+    let kv_store = KVStore::new(tmp_dir.path()).unwrap();
+    kv_store.set("a".into(), "1".into()).unwrap();
+    kv_store.set("b".into(), "1".into()).unwrap();
+
+    let snapshot = kv_store.snapshot().unwrap();
+    assert_eq!(snapshot.seq(), 2);
+    kv_store.set("a".into(), "2".into()).unwrap();
+    kv_store.remove("b".into()).unwrap();
+    kv_store.set("c".into(), "1".into()).unwrap();
+    kv_store.compress_by_index().unwrap();
+    kv_store.set("a".into(), "3".into()).unwrap();
+    kv_store.compress_by_index().unwrap();
+
+    assert_eq!(snapshot.get("a".into()).unwrap(), "1");
+    assert_eq!(snapshot.get("b".into()).unwrap(), "1");
+    assert!(snapshot.get("c".into()).is_err());
+    assert_eq!(
+        snapshot.scan("".into(), None, 10).unwrap(),
+        pairs(&[("a", "1"), ("b", "1")])
+    );
+    assert_eq!(
+        snapshot.scan("".into(), Some("a".into()), 1).unwrap(),
+        pairs(&[("b", "1")])
+    );
+    assert_eq!(
+        kv_store.scan("".into(), None, 10).unwrap(),
+        pairs(&[("a", "3"), ("c", "1")])
+    );
+
+    // the older values kept in the compacted log are not replayed over the
+    // newer ones
+    drop(snapshot);
+    drop(kv_store);
+    let kv_store = KVStore::new(tmp_dir.path()).unwrap();
+    assert_eq!(
+        kv_store.scan("".into(), None, 10).unwrap(),
+        pairs(&[("a", "3"), ("c", "1")])
+    );
+}
+
+#[test]
+fn sled_snapshot_survives_writes() {
+    let tmp_dir = TempDir::new().unwrap();
+    let sled = Sled::new(&tmp_dir.path().to_path_buf()).unwrap();
+    sled.set("a".into(), "1".into()).unwrap();
+    sled.set("b".into(), "1".into()).unwrap();
+    sled.set("d".into(), "1".into()).unwrap();
+
+    let snapshot = sled.snapshot().unwrap();
+    sled.set("a".into(), "2".into()).unwrap();
+    sled.remove("b".into()).unwrap();
+    sled.set("c".into(), "1".into()).unwrap();
+
+    assert_eq!(snapshot.get("a".into()).unwrap(), "1");
+    assert_eq!(snapshot.get("b".into()).unwrap(), "1");
+    assert!(snapshot.get("c".into()).is_err());
+    assert_eq!(
+        snapshot.scan("".into(), None, 2).unwrap(),
+        pairs(&[("a", "1"), ("b", "1")])
+    );
+    assert_eq!(
+        snapshot.scan("".into(), Some("a".into()), 10).unwrap(),
+        pairs(&[("b", "1"), ("d", "1")])
+    );
+
+    drop(snapshot);
+    let snapshot = sled.snapshot().unwrap();
+    assert!(snapshot.get("b".into()).is_err());
+    assert_eq!(snapshot.get("c".into()).unwrap(), "1");
+}
+
+#[test]
+fn snapshots_expire_after_max_age() {
+    let (kvs_dir, sled_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let kv_store = KVStore::new(kvs_dir.path()).unwrap();
+    kv_store.set_snapshot_max_age(Duration::from_millis(100));
+    let sled = Sled::new(&sled_dir.path().to_path_buf()).unwrap();
+    sled.set_snapshot_max_age(Duration::from_millis(100));
+    for (key, value) in [("a", "1"), ("b", "1")] {
+        kv_store.set(key.into(), value.into()).unwrap();
+        sled.set(key.into(), value.into()).unwrap();
+    }
+
+    let kvs_snapshot = kv_store.snapshot().unwrap();
+    let sled_snapshot = sled.snapshot().unwrap();
+    let mut txn = Transaction::begin(&kv_store).unwrap();
+    txn.set("c".into(), "1".into());
+    kv_store.set("a".into(), "2".into()).unwrap();
+    sled.set("a".into(), "2".into()).unwrap();
+    assert_eq!(kvs_snapshot.get("a".into()).unwrap(), "1");
+    assert_eq!(sled_snapshot.get("a".into()).unwrap(), "1");
+
+    // the first write after the max age drops the versions of the old views
+    thread::sleep(Duration::from_millis(150));
+    kv_store.remove("b".into()).unwrap();
+    sled.remove("b".into()).unwrap();
+    for e in [
+        kvs_snapshot.get("a".into()).unwrap_err(),
+        kvs_snapshot.get("b".into()).unwrap_err(),
+        kvs_snapshot.scan("".into(), None, 10).unwrap_err(),
+        sled_snapshot.get("b".into()).unwrap_err(),
+        sled_snapshot.scan("".into(), None, 10).unwrap_err(),
+        txn.commit().unwrap_err(),
+    ] {
+        assert!(e.is::<SnapshotExpired>(), "{}", e);
+    }
+    assert!(kv_store.get("c".into()).is_err());
+
+    // a view taken now sees the versions again
+    drop(kvs_snapshot);
+    let kvs_snapshot = kv_store.snapshot().unwrap();
+    kv_store.set("a".into(), "3".into()).unwrap();
+    assert_eq!(kvs_snapshot.get("a".into()).unwrap(), "2");
+}
+
+// moves one from a to b in a transaction, again after a conflict
+fn transfer<E: KvsEngine>(engine: &E) {
+    loop {
+        let mut txn = Transaction::begin(engine).unwrap();
+        let a: u32 = txn.get("a".into()).unwrap().unwrap().parse().unwrap();
+        let b: u32 = txn
+            .get("b".into())
+            .unwrap()
+            .map_or(0, |b| b.parse().unwrap());
+        txn.set("a".into(), (a - 1).to_string());
+        txn.set("b".into(), (b + 1).to_string());
+        match txn.commit() {
+            Ok(()) => return,
+            Err(e) if e.is::<Conflict>() => continue,
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
+
+fn concurrent_transfers<E: KvsEngine + Sync>(engine: &E) {
+    engine.set("a".into(), "100".into()).unwrap();
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..10 {
+                    transfer(engine);
+                }
+            });
+        }
+    });
+    assert_eq!(engine.get("a".into()).unwrap(), "60");
+    assert_eq!(engine.get("b".into()).unwrap(), "40");
+}
+
+fn conflicting_transactions<E: KvsEngine>(engine: &E) {
+    engine.set("x".into(), "1".into()).unwrap();
+    let mut first = Transaction::begin(engine).unwrap();
+    let mut second = Transaction::begin(engine).unwrap();
+    assert_eq!(first.get("x".into()).unwrap(), Some("1".into()));
+    assert_eq!(second.get("y".into()).unwrap(), None);
+    first.set("y".into(), "1".into());
+    first.remove("x".into());
+    second.set("x".into(), "2".into());
+    first.commit().unwrap();
+
+    // second read y, which first wrote
+    let e = second.commit().unwrap_err();
+    assert!(e.is::<Conflict>(), "{}", e);
+    assert!(engine.get("x".into()).is_err());
+    assert_eq!(engine.get("y".into()).unwrap(), "1");
+
+    // a blind write does not conflict
+    let mut third = Transaction::begin(engine).unwrap();
+    third.set("y".into(), "3".into());
+    engine.set("y".into(), "2".into()).unwrap();
+    third.commit().unwrap();
+    assert_eq!(engine.get("y".into()).unwrap(), "3");
+}
+
+#[test]
+fn kvs_transactions() {
+    let tmp_dir = TempDir::new().unwrap();
+    let kv_store = KVStore::new(tmp_dir.path()).unwrap();
+    conflicting_transactions(&kv_store);
+    concurrent_transfers(&kv_store);
+
+    drop(kv_store);
+    let kv_store = KVStore::new(tmp_dir.path()).unwrap();
+    assert_eq!(kv_store.get("a".into()).unwrap(), "60");
+    assert_eq!(kv_store.get("y".into()).unwrap(), "3");
+}
+
+#[test]
+fn kvs_commit_torn_by_a_crash_is_dropped() {
+    let tmp_dir = TempDir::new().unwrap();
+    let kv_store = KVStore::new(tmp_dir.path()).unwrap();
+    kv_store.set("a".into(), "1".into()).unwrap();
+    let mut txn = Transaction::begin(&kv_store).unwrap();
+    for key in ["a", "b", "c"] {
+        txn.set(key.into(), "2".into());
+    }
+    txn.commit().unwrap();
+
+    // the records of a commit are read together with their marker, past the
+    // limit
+    let start = LogCursor {
+        log_id: 0,
+        offset: 0,
+    };
+    let (transactions, end) = kv_store.read_log(start, 2).unwrap();
+    assert!(matches!(
+        transactions.as_slice(),
+        [kvs::Transaction::Set(..), kvs::Transaction::Begin(3), ..]
+    ));
+    assert_eq!(transactions.len(), 5);
+
+    // the crash tears the last write of the commit
+    assert_eq!(log_files(tmp_dir.path()), ["0.log"]);
+    let log = tmp_dir.path().join("db").join("0.log");
+    let len = fs::metadata(&log).unwrap().len();
+    assert_eq!(len, end.offset as u64);
+    let file = fs::File::options().write(true).open(&log).unwrap();
+    file.set_len(len - 3).unwrap();
+    let (transactions, cursor) = kv_store.read_log(start, 10).unwrap();
+    assert!(matches!(
+        transactions.as_slice(),
+        [kvs::Transaction::Set(..)]
+    ));
+    drop(kv_store);
+
+    let kv_store = KVStore::new(tmp_dir.path()).unwrap();
+    assert_eq!(kv_store.get("a".into()).unwrap(), "1");
+    assert!(kv_store.get("b".into()).is_err());
+    assert!(kv_store.get("c".into()).is_err());
+    assert_eq!(fs::metadata(&log).unwrap().len(), cursor.offset as u64);
+
+    // a write after the torn commit is not taken for a part of it
+    kv_store.set("d".into(), "1".into()).unwrap();
+    drop(kv_store);
+    let kv_store = KVStore::new(tmp_dir.path()).unwrap();
+    assert_eq!(
+        kv_store.scan("".into(), None, 10).unwrap(),
+        pairs(&[("a", "1"), ("d", "1")])
+    );
+}
+
+#[test]
+fn sled_transactions() {
+    let tmp_dir = TempDir::new().unwrap();
+    let sled = Sled::new(&tmp_dir.path().to_path_buf()).unwrap();
+    conflicting_transactions(&sled);
+    concurrent_transfers(&sled);
+}
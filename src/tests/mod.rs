@@ -1,3 +1,8 @@
+pub mod bench;
 pub mod cli_test;
+pub mod client;
 pub mod kvs_store;
+pub mod raft;
+pub mod replication;
+pub mod server;
 pub mod thread_pool;
@@ -0,0 +1,396 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tempfile::TempDir;
+use tokio::net::{TcpListener, TcpStream};
+
+use super::replication::{connect, ServerProcess};
+use crate::client::Client;
+use crate::engine::KvsEngine;
+use crate::kvs::KVStore;
+use crate::raft::message::{Command, Entry, RaftMessage, RaftReply};
+use crate::raft::node::Node;
+use crate::raft::storage::{HardState, Storage};
+use crate::raft::{RaftConfig, RaftEngine};
+use crate::server::{Request, Status};
+
+const NODES: usize = 3;
+
+fn client_addr(node: usize) -> String {
+    format!("127.0.0.1:{}", 12511 + node)
+}
+
+fn raft_addr(node: usize) -> String {
+    format!("127.0.0.1:{}", 12521 + node)
+}
+
+fn start_node(node: usize, dir: &TempDir) -> ServerProcess {
+    let peers: Vec<String> = (0..NODES).filter(|&n| n != node).map(raft_addr).collect();
+    let (client, raft, peers) = (client_addr(node), raft_addr(node), peers.join(","));
+    ServerProcess::start(
+        dir.path(),
+        &[
+            "--listen-addr",
+            &client,
+            "--raft-addr",
+            &raft,
+            "--raft-peers",
+            &peers,
+            "--raft-snapshot-entries",
+            "10",
+        ],
+    )
+}
+
+// sets the key through the node until a leader accepts it, returns the
+// address of the leader
+async fn set_through(node: usize, key: &str, value: &str) -> String {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    let mut client = connect(&client_addr(node)).await;
+    loop {
+        let request = Request::Set(key.to_string(), value.to_string());
+        match client.call(request).await {
+            Ok(response) if response.status == Status::Ok => return client.addr().to_string(),
+            // no leader is elected yet or the connection broke
+            _ => client = connect(&client_addr(node)).await,
+        }
+        assert!(Instant::now() < deadline, "no leader accepted {}", key);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// the keys of the engine of the node itself, not of the leader
+async fn local_keys(node: usize) -> u64 {
+    let info = connect(&client_addr(node)).await.info().await.unwrap();
+    info.lines()
+        .find_map(|line| line.strip_prefix("keys:"))
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn raft_group_replicates_and_fails_over() {
+    let dirs: Vec<TempDir> = (0..NODES).map(|_| TempDir::new().unwrap()).collect();
+    let mut nodes: Vec<Option<ServerProcess>> = (0..NODES)
+        .map(|node| Some(start_node(node, &dirs[node])))
+        .collect();
+
+    let leader_addr = set_through(0, "a", "1").await;
+    let leader = (0..NODES).find(|&n| client_addr(n) == leader_addr).unwrap();
+    let follower = (leader + 1) % NODES;
+
+    // a follower redirects its clients to the leader
+    let mut client = Client::connect(&client_addr(follower)).await.unwrap();
+    assert_eq!(client.get("a".to_string()).await.unwrap(), "1");
+    assert_eq!(client.addr(), leader_addr);
+
+    // a node down while the log is compacted catches up from a snapshot
+    nodes[follower] = None;
+    for i in 0..30 {
+        set_through(leader, &format!("k{}", i), &i.to_string()).await;
+    }
+    nodes[follower] = Some(start_node(follower, &dirs[follower]));
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while local_keys(follower).await != 31 {
+        assert!(Instant::now() < deadline, "{} did not catch up", follower);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    // the other nodes elect a new leader that has every write
+    nodes[leader] = None;
+    let new_leader = set_through(follower, "b", "2").await;
+    assert_ne!(new_leader, leader_addr);
+    let mut client = Client::connect(&new_leader).await.unwrap();
+    match client.begin().await {
+        Ok(_) => panic!("a transaction began in raft mode"),
+        Err(e) => assert_eq!(e.to_string(), "transactions are not supported in raft mode"),
+    }
+    let keys = vec!["a".to_string(), "k29".to_string(), "b".to_string()];
+    assert_eq!(
+        client.get_many(keys).await.unwrap(),
+        vec![
+            Some("1".to_string()),
+            Some("29".to_string()),
+            Some("2".to_string())
+        ]
+    );
+}
+
+// Forwards the connections made to addr to target. Once the returned flag
+// is set the open connections are dropped and new ones are refused.
+async fn link(addr: String, target: String) -> Arc<AtomicBool> {
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let cut = Arc::new(AtomicBool::new(false));
+    let link_cut = cut.clone();
+    tokio::spawn(async move {
+        while let Ok((mut inbound, _)) = listener.accept().await {
+            if link_cut.load(Ordering::SeqCst) {
+                continue;
+            }
+            let (cut, target) = (link_cut.clone(), target.clone());
+            tokio::spawn(async move {
+                let Ok(mut outbound) = TcpStream::connect(target).await else {
+                    return;
+                };
+                let cut = async {
+                    while !cut.load(Ordering::SeqCst) {
+                        tokio::time::sleep(Duration::from_millis(2)).await;
+                    }
+                };
+                tokio::select! {
+                    _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound) => {}
+                    _ = cut => {}
+                }
+            });
+        }
+    });
+    cut
+}
+
+// sets the key on the first node that takes it as the leader, returns the
+// node and when the write that succeeded was sent
+async fn set_on_leader(
+    engines: &[RaftEngine<KVStore>],
+    nodes: &[usize],
+    key: &str,
+    value: &str,
+) -> (usize, Instant) {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    loop {
+        for &node in nodes {
+            let (engine, key, value) = (engines[node].clone(), key.to_string(), value.to_string());
+            let sent = Instant::now();
+            let res = tokio::task::spawn_blocking(move || engine.set(key, value))
+                .await
+                .unwrap();
+            if res.is_ok() {
+                return (node, sent);
+            }
+        }
+        assert!(Instant::now() < deadline, "no leader accepted {}", key);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+// every node reaches the others through links that the test cuts
+#[tokio::test(flavor = "multi_thread")]
+async fn raft_partitioned_leader_stops_reads_before_a_new_leader_commits() {
+    let node_addr = |node: usize| format!("127.0.0.1:{}", 12531 + node);
+    let link_addr = |from: usize, to: usize| format!("127.0.0.1:{}", 12540 + from * NODES + to);
+    let mut links = Vec::new();
+    for from in 0..NODES {
+        for to in (0..NODES).filter(|&to| to != from) {
+            links.push((from, to, link(link_addr(from, to), node_addr(to)).await));
+        }
+    }
+
+    let dirs: Vec<TempDir> = (0..NODES).map(|_| TempDir::new().unwrap()).collect();
+    let engines: Vec<RaftEngine<KVStore>> = (0..NODES)
+        .map(|node| {
+            let config = RaftConfig {
+                addr: node_addr(node),
+                peers: (0..NODES)
+                    .filter(|&to| to != node)
+                    .map(|to| link_addr(node, to))
+                    .collect(),
+                client_addr: format!("node-{}", node),
+                dir: dirs[node].path().to_path_buf(),
+                heartbeat: Duration::from_millis(50),
+                election_timeout: Duration::from_millis(500),
+                ..Default::default()
+            };
+            let engine = KVStore::new(dirs[node].path()).unwrap();
+            RaftEngine::start(engine, config).unwrap()
+        })
+        .collect();
+
+    let all: Vec<usize> = (0..NODES).collect();
+    let (leader, _) = set_on_leader(&engines, &all, "a", "1").await;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while engines[leader].get("a".to_string()).is_err() {
+        assert!(Instant::now() < deadline, "the leader serves no reads");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    for (from, to, cut) in &links {
+        if *from == leader || *to == leader {
+            cut.store(true, Ordering::SeqCst);
+        }
+    }
+    let old_leader = engines[leader].clone();
+    let stopped = tokio::task::spawn_blocking(move || loop {
+        if old_leader.get("a".to_string()).is_err() {
+            return Instant::now();
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    });
+
+    let others: Vec<usize> = all.into_iter().filter(|&node| node != leader).collect();
+    let (new_leader, sent) = set_on_leader(&engines, &others, "a", "2").await;
+    let stopped = stopped.await.unwrap();
+    assert!(
+        stopped < sent,
+        "the old leader read until {:?}",
+        sent - stopped
+    );
+    assert!(engines[leader].get("a".to_string()).is_err());
+    assert_eq!(engines[new_leader].get("a".to_string()).unwrap(), "2");
+}
+
+fn open_node(node: usize, dir: &TempDir) -> Node<KVStore> {
+    let config = RaftConfig {
+        addr: raft_addr(node),
+        peers: (0..NODES).filter(|&n| n != node).map(raft_addr).collect(),
+        client_addr: client_addr(node),
+        dir: dir.path().to_path_buf(),
+        election_timeout: Duration::from_millis(100),
+        ..Default::default()
+    };
+    Node::open(KVStore::new(dir.path()).unwrap(), config).unwrap()
+}
+
+#[test]
+fn raft_lease_counts_from_the_send_of_the_heartbeat() {
+    let dir = TempDir::new().unwrap();
+    let mut node = open_node(0, &dir);
+    let peer = raft_addr(1);
+    std::thread::sleep(Duration::from_millis(250));
+    assert!(node.tick().unwrap());
+    let vote = RaftReply::Vote {
+        term: 1,
+        granted: true,
+    };
+    node.handle_reply(&peer, vote, Instant::now()).unwrap();
+    assert!(node.is_leader());
+
+    // the reply to a heartbeat sent longer ago than the lease arrives now
+    let sent = Instant::now();
+    std::thread::sleep(Duration::from_millis(90));
+    let ack = || RaftReply::Append {
+        term: 1,
+        success: true,
+        next: 2,
+    };
+    node.handle_reply(&peer, ack(), sent).unwrap();
+    assert!(!node.can_read());
+    node.handle_reply(&peer, ack(), Instant::now()).unwrap();
+    assert!(node.can_read());
+}
+
+#[test]
+fn raft_follower_refuses_votes_while_it_hears_from_a_leader() {
+    let dir = TempDir::new().unwrap();
+    let mut node = open_node(1, &dir);
+    let append = RaftMessage::AppendEntries {
+        term: 1,
+        leader: raft_addr(0),
+        leader_client: client_addr(0),
+        prev_index: 0,
+        prev_term: 0,
+        entries: Vec::new(),
+        commit: 0,
+    };
+    node.handle(append).unwrap();
+    let vote = || RaftMessage::RequestVote {
+        term: 2,
+        candidate: raft_addr(2),
+        last_index: 10,
+        last_term: 1,
+    };
+    match node.handle(vote()).unwrap() {
+        RaftReply::Vote { term, granted } => assert_eq!((term, granted), (1, false)),
+        reply => panic!("{:?}", reply),
+    }
+
+    // the leader may have lost its lease once an election timeout passed
+    std::thread::sleep(Duration::from_millis(100));
+    match node.handle(vote()).unwrap() {
+        RaftReply::Vote { term, granted } => assert_eq!((term, granted), (2, true)),
+        reply => panic!("{:?}", reply),
+    }
+}
+
+fn entries(term: u64, indexes: std::ops::RangeInclusive<u64>) -> Vec<Entry> {
+    indexes
+        .map(|index| Entry {
+            index,
+            term,
+            command: Command::Set(format!("k{}", index), "v".to_string()),
+        })
+        .collect()
+}
+
+#[test]
+fn raft_storage_appends_truncates_and_recovers() {
+    let dir = TempDir::new().unwrap();
+    let (mut storage, state, logged) = Storage::open(dir.path()).unwrap();
+    assert_eq!(state, HardState::default());
+    assert!(logged.is_empty());
+
+    let state = HardState {
+        term: 2,
+        voted_for: Some("node".to_string()),
+        ..Default::default()
+    };
+    storage.save_state(&state).unwrap();
+    storage.append(&entries(1, 1..=4)).unwrap();
+    // a conflicting suffix is replaced
+    storage.write_from(2, &entries(2, 3..=5)).unwrap();
+    drop(storage);
+
+    let mut expected = entries(1, 1..=2);
+    expected.extend(entries(2, 3..=5));
+    let (mut storage, reopened, logged) = Storage::open(dir.path()).unwrap();
+    assert_eq!(reopened, state);
+    assert_eq!(logged, expected);
+
+    // an entry torn by a crash is dropped, the next one follows the last
+    // whole entry
+    drop(storage);
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(dir.path().join("raft.log"))
+        .unwrap();
+    log.write_all(b"{\"index\":6,\"te").unwrap();
+    let (_, _, logged) = Storage::open(dir.path()).unwrap();
+    assert_eq!(logged, expected);
+    (storage, _, _) = Storage::open(dir.path()).unwrap();
+    storage.append(&entries(2, 6..=6)).unwrap();
+    expected.extend(entries(2, 6..=6));
+    let (_, _, logged) = Storage::open(dir.path()).unwrap();
+    assert_eq!(logged, expected);
+}
+
+#[test]
+fn raft_storage_drops_entries_of_the_snapshot() {
+    let dir = TempDir::new().unwrap();
+    let (mut storage, _, _) = Storage::open(dir.path()).unwrap();
+    storage.append(&entries(1, 1..=4)).unwrap();
+
+    // the state names the snapshot before the log is written again, a
+    // crash in between leaves the entries it replaced in the log
+    let mut state = HardState {
+        term: 1,
+        snapshot_index: 2,
+        snapshot_term: 1,
+        ..Default::default()
+    };
+    storage.save_state(&state).unwrap();
+    let (storage, _, logged) = Storage::open(dir.path()).unwrap();
+    assert_eq!(logged, entries(1, 3..=4));
+
+    // a snapshot of another leader replaces the log it disagrees with
+    state.snapshot_index = 3;
+    state.snapshot_term = 2;
+    storage.save_state(&state).unwrap();
+    let (mut storage, _, logged) = Storage::open(dir.path()).unwrap();
+    assert!(logged.is_empty());
+
+    storage.append(&entries(2, 4..=5)).unwrap();
+    let (_, _, logged) = Storage::open(dir.path()).unwrap();
+    assert_eq!(logged, entries(2, 4..=5));
+}
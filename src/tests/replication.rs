@@ -0,0 +1,383 @@
+use std::path::Path;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+
+use assert_cmd::prelude::*;
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+
+use crate::client::{blocking, Client, ClientPool, PoolConfig, ReplicatedClient};
+use crate::connection::Connection;
+use crate::engine::KvsEngine;
+use crate::kvs::{KVStore, Transaction};
+use crate::replication::{
+    ApplyFailed, LogCursor, Replica, ReplicationMessage, REPLICATION_HEARTBEAT,
+};
+use crate::server::{AdminCommand, Request, Response, Status};
+use crate::watch::WatchEvent;
+
+// a kvs_server process, killed when dropped
+pub(super) struct ServerProcess(Child);
+
+impl ServerProcess {
+    pub(super) fn start(dir: &Path, args: &[&str]) -> ServerProcess {
+        let child = Command::cargo_bin("kvs_server")
+            .unwrap()
+            .args(args)
+            .current_dir(dir)
+            .spawn()
+            .unwrap();
+        ServerProcess(child)
+    }
+}
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+pub(super) async fn connect(addr: &str) -> Client {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        match Client::connect(addr).await {
+            Ok(client) => return client,
+            Err(e) if Instant::now() > deadline => panic!("cannot connect {}: {}", addr, e),
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    }
+}
+
+// polls the replica until the keys have the values
+async fn wait_for(replica: &mut Client, keys: &[&str], values: Vec<Option<String>>) {
+    let keys: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let got = replica.get_many(keys.clone()).await.unwrap();
+        if got == values {
+            return;
+        }
+        assert!(Instant::now() < deadline, "replica has {:?}", got);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+fn some(value: &str) -> Option<String> {
+    Some(value.to_string())
+}
+
+async fn compact(primary: &mut Client) {
+    let response = primary
+        .call(Request::Admin {
+            token: Some("secret".into()),
+            command: AdminCommand::Compact,
+        })
+        .await
+        .unwrap();
+    assert_eq!(response.status, Status::Ok);
+}
+
+#[tokio::test]
+async fn replica_follows_primary_and_catches_up_after_compaction() {
+    let (primary_addr, replica_addr) = ("127.0.0.1:12501", "127.0.0.1:12502");
+    let (primary_dir, replica_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let replica_args = ["--listen-addr", replica_addr, "--replica-of", primary_addr];
+
+    let _primary = ServerProcess::start(
+        primary_dir.path(),
+        &["--listen-addr", primary_addr, "--admin-token", "secret"],
+    );
+    let mut primary = connect(primary_addr).await;
+    primary.set("a".into(), "1".into()).await.unwrap();
+    primary.set("b".into(), "1".into()).await.unwrap();
+
+    // a new replica starts from a snapshot, then follows the log
+    let replica_process = ServerProcess::start(replica_dir.path(), &replica_args);
+    let mut replica = connect(replica_addr).await;
+    wait_for(&mut replica, &["a", "b"], vec![some("1"), some("1")]).await;
+    primary.set("a".into(), "2".into()).await.unwrap();
+    primary.remove("b".into()).await.unwrap();
+    wait_for(&mut replica, &["a", "b"], vec![some("2"), None]).await;
+
+    // a write to the replica is redirected to the primary
+    let mut writer = connect(replica_addr).await;
+    let response = writer
+        .call(Request::Set("c".into(), "1".into()))
+        .await
+        .unwrap();
+    assert_eq!(response.status, Status::Ok);
+    assert_eq!(writer.addr(), primary_addr);
+    wait_for(&mut replica, &["c"], vec![some("1")]).await;
+
+    // A compaction while the replica follows the active log copies the
+    // value a transaction still sees before the current one, the replica
+    // must not apply it again.
+    let mut events = connect(replica_addr)
+        .await
+        .watch("a".to_string())
+        .await
+        .unwrap();
+    compact(&mut primary).await;
+    let mut pinning = connect(primary_addr).await;
+    let transaction = pinning.begin().await.unwrap();
+    primary.set("a".into(), "3".into()).await.unwrap();
+    compact(&mut primary).await;
+    primary.set("a".into(), "4".into()).await.unwrap();
+    let mut values = Vec::new();
+    while values.last().map(String::as_str) != Some("4") {
+        match events.next().await.unwrap() {
+            Some(WatchEvent::Set { value, .. }) => values.push(value),
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+    assert!(
+        values.windows(2).all(|pair| pair[0] <= pair[1]),
+        "{:?}",
+        values
+    );
+    transaction.abort().await.unwrap();
+
+    // the log at the cursor of the stopped replica is compacted away
+    drop(replica);
+    drop(replica_process);
+    for i in 0..2 {
+        primary.set("c".into(), i.to_string()).await.unwrap();
+        compact(&mut primary).await;
+    }
+    primary.remove("a".into()).await.unwrap();
+
+    let _replica_process = ServerProcess::start(replica_dir.path(), &replica_args);
+    let mut replica = connect(replica_addr).await;
+    wait_for(&mut replica, &["a", "b", "c"], vec![None, None, some("1")]).await;
+    primary.set("d".into(), "1".into()).await.unwrap();
+    wait_for(&mut replica, &["a", "d"], vec![None, some("1")]).await;
+}
+
+#[tokio::test]
+async fn replicated_client_bounds_staleness_of_replica_reads() {
+    let (primary_addr, replica_addr) = ("127.0.0.1:12503", "127.0.0.1:12504");
+    let (primary_dir, replica_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+
+    let primary_process =
+        ServerProcess::start(primary_dir.path(), &["--listen-addr", primary_addr]);
+    connect(primary_addr).await;
+    let _replica_process = ServerProcess::start(
+        replica_dir.path(),
+        &["--listen-addr", replica_addr, "--replica-of", primary_addr],
+    );
+    let mut replica = connect(replica_addr).await;
+
+    let mut client = ReplicatedClient::connect(primary_addr, &[replica_addr])
+        .await
+        .unwrap();
+    client.set_max_staleness(Some(Duration::from_millis(1000)));
+    client.set("a".into(), "1".into()).await.unwrap();
+    wait_for(&mut replica, &["a"], vec![some("1")]).await;
+    assert_eq!(client.get("a".into()).await.unwrap(), "1");
+    assert_eq!(
+        client.get_many(vec!["a".into(), "b".into()]).await.unwrap(),
+        vec![some("1"), None]
+    );
+
+    let info = replica.call(Request::Info {}).await.unwrap().response;
+    assert!(info.contains("role:replica"), "{}", info);
+    assert!(
+        info.contains(&format!("primary:{}", primary_addr)),
+        "{}",
+        info
+    );
+
+    // without its primary the replica falls behind the bound, the bounded
+    // reads are redirected to the stopped primary
+    drop(primary_process);
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(client.get("a".into()).await.is_err());
+    client.set_max_staleness(None);
+    assert_eq!(client.get("a".into()).await.unwrap(), "1");
+}
+
+#[tokio::test]
+async fn pool_and_blocking_client_follow_redirects_of_replica() {
+    let (primary_addr, replica_addr) = ("127.0.0.1:12505", "127.0.0.1:12506");
+    let (primary_dir, replica_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+
+    let _primary_process =
+        ServerProcess::start(primary_dir.path(), &["--listen-addr", primary_addr]);
+    connect(primary_addr).await;
+    let _replica_process = ServerProcess::start(
+        replica_dir.path(),
+        &["--listen-addr", replica_addr, "--replica-of", primary_addr],
+    );
+    let mut replica = connect(replica_addr).await;
+
+    // the write follows the redirect, its client is not put back
+    let pool = ClientPool::new(replica_addr, PoolConfig::default());
+    pool.set("a".into(), "1".into()).await.unwrap();
+    assert_eq!(pool.idle(), 0);
+
+    // the reads are still served by the replica
+    wait_for(&mut replica, &["a"], vec![some("1")]).await;
+    assert_eq!(pool.get("a".into()).await.unwrap(), "1");
+    assert_eq!(pool.idle(), 1);
+
+    // the blocking client follows the redirect like the async one
+    let replica_addr = replica_addr.to_string();
+    let addr = tokio::task::spawn_blocking(move || {
+        let mut client = blocking::Client::connect(&replica_addr).unwrap();
+        client.set_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(client.get("a".into()).unwrap(), "1");
+        assert_eq!(client.addr(), replica_addr);
+        assert_eq!(client.set("b".into(), "1".into()).unwrap(), "");
+        assert_eq!(client.get_many(vec!["b".into()]).unwrap(), vec![some("1")]);
+        client.addr().to_string()
+    })
+    .await
+    .unwrap();
+    assert_eq!(addr, primary_addr);
+}
+
+#[tokio::test]
+async fn replica_stays_fresh_while_primary_is_written() {
+    let primary_addr = "127.0.0.1:12507";
+    let dir = TempDir::new().unwrap();
+
+    // a primary written all the time, every read of its log returns records
+    // up to the end and no heartbeat is ever sent
+    let listener = TcpListener::bind(primary_addr).await.unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = Connection::new(stream);
+        conn.read::<Request>().await.unwrap();
+        conn.write(Response::ok(String::new())).await.unwrap();
+        for offset in 1.. {
+            let records = ReplicationMessage::Records {
+                transactions: vec![Transaction::Set("a".into(), offset.to_string())],
+                cursor: LogCursor { log_id: 1, offset },
+                end: true,
+            };
+            if conn.write(records).await.is_err() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    });
+
+    let engine = KVStore::new(dir.path()).unwrap();
+    let mut replica = Replica::new(engine, primary_addr.to_string(), dir.path()).unwrap();
+    let state = replica.state();
+    let sync = tokio::spawn(async move { replica.sync().await });
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while Instant::now() < deadline {
+        let staleness = state.staleness();
+        assert!(
+            staleness.is_some_and(|staleness| staleness < REPLICATION_HEARTBEAT),
+            "{:?}",
+            staleness
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    sync.abort();
+}
+
+#[tokio::test]
+async fn replica_applies_the_records_of_a_commit_together() {
+    let primary_addr = "127.0.0.1:12508";
+    let dir = TempDir::new().unwrap();
+
+    let listener = TcpListener::bind(primary_addr).await.unwrap();
+    let primary = tokio::spawn(async move {
+        let batches = [
+            vec![
+                Transaction::Set("x".into(), "1".into()),
+                Transaction::Begin(2),
+                Transaction::Set("a".into(), "1".into()),
+                Transaction::Remove("x".into()),
+            ],
+            // a batch cut in the middle of a commit
+            vec![
+                Transaction::Begin(2),
+                Transaction::Set("b".into(), "1".into()),
+            ],
+        ];
+        for (offset, transactions) in (1..).zip(batches) {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = Connection::new(stream);
+            conn.read::<Request>().await.unwrap();
+            conn.write(Response::ok(String::new())).await.unwrap();
+            let records = ReplicationMessage::Records {
+                transactions,
+                cursor: LogCursor { log_id: 1, offset },
+                end: true,
+            };
+            conn.write(records).await.unwrap();
+        }
+    });
+
+    let engine = KVStore::new(dir.path()).unwrap();
+    let mut replica = Replica::new(engine.clone(), primary_addr.to_string(), dir.path()).unwrap();
+    replica.sync().await.unwrap();
+    let cursor = LogCursor {
+        log_id: 1,
+        offset: 1,
+    };
+    assert_eq!(replica.cursor(), Some(cursor));
+
+    // the commit is written with its marker, a crash keeps all of it or none
+    let start = LogCursor {
+        log_id: 0,
+        offset: 0,
+    };
+    let (transactions, _) = engine.read_log(start, 10).unwrap();
+    assert!(matches!(
+        transactions.as_slice(),
+        [
+            Transaction::Set(..),
+            Transaction::Begin(2),
+            Transaction::Set(..),
+            Transaction::Remove(..)
+        ]
+    ));
+
+    assert!(replica.sync().await.is_err());
+    assert_eq!(replica.cursor(), Some(cursor));
+    assert!(engine.get("b".into()).is_err());
+    primary.await.unwrap();
+}
+
+#[tokio::test]
+async fn replica_stops_when_it_cannot_apply_the_stream() {
+    let primary_addr = "127.0.0.1:12509";
+    let dir = TempDir::new().unwrap();
+
+    // a primary that breaks the first connection, then sends a broken batch
+    let listener = TcpListener::bind(primary_addr).await.unwrap();
+    tokio::spawn(async move {
+        drop(listener.accept().await.unwrap());
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = Connection::new(stream);
+        conn.read::<Request>().await.unwrap();
+        conn.write(Response::ok(String::new())).await.unwrap();
+        let records = ReplicationMessage::Records {
+            transactions: vec![Transaction::Begin(2)],
+            cursor: LogCursor {
+                log_id: 1,
+                offset: 1,
+            },
+            end: true,
+        };
+        conn.write(records).await.unwrap();
+        std::future::pending::<()>().await;
+    });
+
+    let engine = KVStore::new(dir.path()).unwrap();
+    let mut replica = Replica::new(engine, primary_addr.to_string(), dir.path()).unwrap();
+    let err = tokio::time::timeout(Duration::from_secs(5), replica.run())
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert!(err.is::<ApplyFailed>(), "{:#}", err);
+    assert_eq!(replica.cursor(), None);
+}
@@ -0,0 +1,370 @@
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::client::Client;
+use crate::engine::{EngineStats, KvsEngine};
+use crate::kvs::KVStore;
+use crate::server::{AdminCommand, Server, ServerConfig};
+use crate::sled::Sled;
+use crate::watch::{WatchEvent, Watcher, WATCH_BUFFER};
+
+async fn start_server(addr: &str, config: ServerConfig) -> TempDir {
+    let tmp_dir = TempDir::new().unwrap();
+    let engine = KVStore::new(tmp_dir.path()).unwrap();
+    let mut server = Server::with_config(engine, config).unwrap();
+    let addr = addr.to_string();
+    tokio::spawn(async move { server.serve(addr).await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    tmp_dir
+}
+
+#[tokio::test]
+async fn server_info_and_metrics() {
+    let addr = "127.0.0.1:12301".to_string();
+    let config = ServerConfig {
+        metrics_addr: Some("127.0.0.1:12302".to_string()),
+        ..Default::default()
+    };
+    let dir = start_server(&addr, config).await;
+
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.set("key1".into(), "value1".into()).await.unwrap();
+    client.get("key1".into()).await.unwrap();
+    client.get("key2".into()).await.unwrap();
+
+    let info = client.info().await.unwrap();
+    assert!(info.contains("cmdstat_set:calls=1,"));
+    assert!(info.contains("cmdstat_get:calls=2,"));
+    assert!(info.contains("connected_clients:1"));
+    assert!(info.contains("keys:1"));
+    // the engine calls run on the pool of the server, the info one too
+    assert!(
+        info.contains("pool_engine:submitted=4,completed=3,"),
+        "{}",
+        info
+    );
+
+    let mut stream = TcpStream::connect("127.0.0.1:12302").await.unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut body = String::new();
+    stream.read_to_string(&mut body).await.unwrap();
+
+    assert!(body.starts_with("HTTP/1.1 200 OK"));
+    assert!(body.contains("kvs_requests_total{command=\"get\"} 2"));
+    assert!(body.contains("kvs_request_duration_seconds_count{command=\"set\"} 1"));
+    assert!(body.contains("kvs_keys 1"));
+    assert!(body.contains("kvs_pool_jobs_total{pool=\"engine\",outcome=\"completed\"}"));
+
+    // the engine cannot report its disk usage without its directory
+    std::fs::remove_dir_all(dir.path().join("db")).unwrap();
+    let mut stream = TcpStream::connect("127.0.0.1:12302").await.unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut body = String::new();
+    stream.read_to_string(&mut body).await.unwrap();
+    assert!(
+        body.starts_with("HTTP/1.1 500 Internal Server Error"),
+        "{}",
+        body
+    );
+}
+
+#[tokio::test]
+async fn server_admin_commands() {
+    let addr = "127.0.0.1:12303".to_string();
+    let config = ServerConfig {
+        admin_token: Some("secret".to_string()),
+        ..Default::default()
+    };
+    let _dir = start_server(&addr, config).await;
+    let snapshot_dir = TempDir::new().unwrap();
+
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.set("key1".into(), "value1".into()).await.unwrap();
+
+    let resp = client.admin(None, AdminCommand::Compact).await.unwrap();
+    assert_eq!(resp, "admin token is invalid");
+    for wrong in ["secreT", "secre", "secret1", ""] {
+        let resp = client.admin(Some(wrong.to_string()), AdminCommand::Compact);
+        assert_eq!(resp.await.unwrap(), "admin token is invalid");
+    }
+
+    let token = Some("secret".to_string());
+    let resp = client.admin(token.clone(), AdminCommand::Compact).await;
+    assert_eq!(resp.unwrap(), "OK");
+    let resp = client.admin(token.clone(), AdminCommand::Flush).await;
+    assert_eq!(resp.unwrap(), "OK");
+    let resp = client.admin(token.clone(), AdminCommand::Stats).await;
+    assert!(resp.unwrap().contains("keys:1"));
+
+    let path = snapshot_dir.path().join("snapshot");
+    let resp = client
+        .admin(token, AdminCommand::Snapshot(path.display().to_string()))
+        .await;
+    assert_eq!(resp.unwrap(), "OK");
+
+    let snapshot = KVStore::new(&path).unwrap();
+    assert_eq!(snapshot.get("key1".to_string()).unwrap(), "value1");
+}
+
+#[tokio::test]
+async fn server_watch_sled_engine() {
+    let addr = "127.0.0.1:12304".to_string();
+    let tmp_dir = TempDir::new().unwrap();
+    let mut server = Server::new(Sled::new(&tmp_dir.path().to_path_buf()).unwrap()).unwrap();
+    let server_addr = addr.clone();
+    tokio::spawn(async move { server.serve(server_addr).await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut stream = Client::connect(&addr)
+        .await
+        .unwrap()
+        .watch("user/".to_string())
+        .await
+        .unwrap();
+
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.set("order/1".into(), "b".into()).await.unwrap();
+    client.set("user/1".into(), "a".into()).await.unwrap();
+    client.remove("user/1".into()).await.unwrap();
+
+    assert_eq!(
+        stream.next().await.unwrap(),
+        Some(WatchEvent::Set {
+            seq: 1,
+            key: "user/1".into(),
+            value: "a".into()
+        })
+    );
+    assert_eq!(
+        stream.next().await.unwrap(),
+        Some(WatchEvent::Remove {
+            seq: 2,
+            key: "user/1".into()
+        })
+    );
+}
+
+#[tokio::test]
+async fn server_unix_socket() {
+    let socket_dir = TempDir::new().unwrap();
+    let socket = socket_dir.path().join("kvs.sock");
+    let tmp_dir = TempDir::new().unwrap();
+    let config = ServerConfig {
+        unix_socket: Some(socket.clone()),
+        unix_socket_mode: Some(0o600),
+        ..Default::default()
+    };
+    let mut server = Server::with_config(KVStore::new(tmp_dir.path()).unwrap(), config).unwrap();
+    let handle = tokio::spawn(async move { server.serve("127.0.0.1:12305".to_string()).await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let unix_addr = format!("unix://{}", socket.display());
+    let mut client = Client::connect(&unix_addr).await.unwrap();
+    client.set("key1".into(), "value1".into()).await.unwrap();
+
+    let mut client = Client::connect("127.0.0.1:12305").await.unwrap();
+    assert_eq!(client.get("key1".into()).await.unwrap(), "value1");
+
+    handle.abort();
+    let _ = handle.await;
+    assert!(!socket.exists());
+}
+
+#[tokio::test]
+async fn server_slowlog() {
+    let addr = "127.0.0.1:12306".to_string();
+    let config = ServerConfig {
+        slowlog_threshold: Some(Duration::ZERO),
+        slowlog_max_len: 2,
+        ..Default::default()
+    };
+    let _dir = start_server(&addr, config).await;
+
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.set("key1".into(), "value1".into()).await.unwrap();
+    client.get("key1".into()).await.unwrap();
+    client.get("key2".into()).await.unwrap();
+
+    let slowlog = client.slowlog(None, true).await.unwrap();
+    let entries: Vec<&str> = slowlog.lines().collect();
+    assert_eq!(entries.len(), 2);
+    assert!(entries[0].starts_with("id=2 "));
+    assert!(entries[0].contains("command=get key=\"key2\" size=4 peer=127.0.0.1:"));
+    assert!(entries[1].contains("command=get key=\"key1\""));
+
+    let slowlog = client.slowlog(Some(10), false).await.unwrap();
+    assert!(slowlog.contains("command=slowlog"));
+    assert_eq!(slowlog.lines().count(), 1);
+}
+
+#[tokio::test]
+async fn server_large_values_and_closed_connections() {
+    let addr = "127.0.0.1:12307".to_string();
+    let _dir = start_server(&addr, ServerConfig::default()).await;
+
+    // the response does not fit in a single write of the socket
+    let value = "v".repeat(64 * 1024);
+    let mut client = Client::connect(&addr).await.unwrap();
+    client.set("key1".into(), value.clone()).await.unwrap();
+    assert_eq!(client.get("key1".into()).await.unwrap(), value);
+
+    // a closed connection ends its task instead of reading it forever
+    drop(client);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut client = Client::connect(&addr).await.unwrap();
+    let info = client.info().await.unwrap();
+    assert!(info.contains("connected_clients:1"), "{}", info);
+    assert!(info.contains("total_connections_received:2"), "{}", info);
+}
+
+#[tokio::test]
+async fn server_watch_ends_when_lagging() {
+    let addr = "127.0.0.1:12308".to_string();
+    let _dir = start_server(&addr, ServerConfig::default()).await;
+
+    let mut stream = Client::connect(&addr)
+        .await
+        .unwrap()
+        .watch("user/".to_string())
+        .await
+        .unwrap();
+
+    // the stream is not read while the writes are made, the socket buffers
+    // fill up and then the watcher
+    let mut client = Client::connect(&addr).await.unwrap();
+    let value = "v".repeat(1024);
+    for i in 0..WATCH_BUFFER * 8 {
+        client
+            .set(format!("user/{}", i), value.clone())
+            .await
+            .unwrap();
+    }
+
+    let mut last = 0;
+    let err = loop {
+        match stream.next().await {
+            Ok(Some(event)) => {
+                assert_eq!(event.seq(), last + 1);
+                last = event.seq();
+            }
+            Ok(None) => panic!("watch ended without an error"),
+            Err(e) => break e,
+        }
+    };
+    assert!(err.to_string().contains("lagged"), "{}", err);
+    assert!(last < (WATCH_BUFFER * 8) as u64);
+}
+
+// a store whose sets take as long as a raft write waiting for its commit
+#[derive(Clone)]
+struct SlowSets(KVStore);
+
+impl KvsEngine for SlowSets {
+    fn get(&self, key: String) -> Result<String> {
+        self.0.get(key)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<Option<String>> {
+        std::thread::sleep(Duration::from_millis(1000));
+        self.0.set(key, value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.0.scan(prefix, after, limit)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        self.0.stats()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.0.compact()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.0.flush()
+    }
+
+    fn snapshot(&self, path: &Path) -> Result<()> {
+        KvsEngine::snapshot(&self.0, path)
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        self.0.watch(prefix)
+    }
+}
+
+// the test runtime has a single thread, which a blocked engine call would hold
+#[tokio::test]
+async fn server_serves_reads_while_a_write_blocks() {
+    let addr = "127.0.0.1:12309".to_string();
+    let tmp_dir = TempDir::new().unwrap();
+    let engine = SlowSets(KVStore::new(tmp_dir.path()).unwrap());
+    let mut server = Server::new(engine).unwrap();
+    let server_addr = addr.clone();
+    tokio::spawn(async move { server.serve(server_addr).await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut writer = Client::connect(&addr).await.unwrap();
+    let write = tokio::spawn(async move { writer.set("key1".into(), "value1".into()).await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let start = Instant::now();
+    let mut reader = Client::connect(&addr).await.unwrap();
+    assert_eq!(reader.get("key1".into()).await.unwrap(), "Key not found");
+    assert!(start.elapsed() < Duration::from_millis(500));
+
+    write.await.unwrap().unwrap();
+    assert_eq!(reader.get("key1".into()).await.unwrap(), "value1");
+}
+
+#[tokio::test]
+async fn server_aborts_timed_out_transactions() {
+    let addr = "127.0.0.1:12310".to_string();
+    let config = ServerConfig {
+        transaction_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let _dir = start_server(&addr, config).await;
+
+    let mut client = Client::connect(&addr).await.unwrap();
+    let mut other = Client::connect(&addr).await.unwrap();
+    let mut txn = client.begin().await.unwrap();
+    txn.set("key1".into(), "value1".into());
+
+    // the transaction times out while its connection sends nothing
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let e = txn.commit().await.unwrap_err();
+    assert!(e.to_string().contains("timed out"), "{}", e);
+    assert_eq!(other.get("key1".into()).await.unwrap(), "Key not found");
+
+    let mut txn = client.begin().await.unwrap();
+    txn.set("key1".into(), "value2".into());
+    txn.commit().await.unwrap();
+    assert_eq!(other.get("key1".into()).await.unwrap(), "value2");
+}
@@ -1,14 +1,21 @@
 use std::sync::{
     atomic::{AtomicU32, Ordering},
-    Arc,
+    mpsc, Arc, Barrier,
 };
+use std::thread;
+use std::time::Duration;
 
 use crossbeam::sync::WaitGroup;
 
+use crate::engine::EngineStats;
+use crate::stats::ServerStats;
 use crate::thread_pool::native::NativeThreadPool;
 use crate::thread_pool::rayon::RayonThreadPool;
-use crate::thread_pool::shared_queue::SharedQueueThreadPool;
-use crate::thread_pool::ThreadPool;
+use crate::thread_pool::shared_queue::{
+    FullPolicy, SharedQueueConfig, SharedQueueThreadPool, ShutdownPolicy,
+};
+use crate::thread_pool::work_stealing::WorkStealingThreadPool;
+use crate::thread_pool::{PoolStats, Priority, ThreadPool};
 
 #[test]
 fn native_thread_pool_run_jobs() {
@@ -28,6 +35,431 @@ fn shared_queue_pool_run_panic_jobs() {
     panic_jobs(thread_pool, 12)
 }
 
+#[test]
+fn shared_queue_pool_drains_on_drop() {
+    let thread_pool = SharedQueueThreadPool::new(2).unwrap();
+    let value = Arc::new(AtomicU32::new(0));
+    for i in 0..20 {
+        let v1 = value.clone();
+        thread_pool.spawn(move || {
+            if i == 3 {
+                panic_control::disable_hook_in_current_thread();
+                panic!("panic jobs");
+            }
+            thread::sleep(Duration::from_millis(5));
+            v1.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    // every queued job has run once the workers are joined
+    drop(thread_pool);
+    assert_eq!(value.load(Ordering::Relaxed), 19);
+    assert_eq!(Arc::strong_count(&value), 1);
+}
+
+#[test]
+fn shared_queue_pool_cancels_on_shutdown() {
+    let thread_pool =
+        SharedQueueThreadPool::with_shutdown_policy(1, ShutdownPolicy::Cancel).unwrap();
+    let value = Arc::new(AtomicU32::new(0));
+
+    // the only worker is busy until the release
+    let (started, running) = mpsc::channel::<()>();
+    let (release, wait) = mpsc::channel::<()>();
+    let v1 = value.clone();
+    thread_pool.spawn(move || {
+        started.send(()).unwrap();
+        let _ = wait.recv();
+        v1.fetch_add(1, Ordering::Relaxed);
+    });
+    running.recv().unwrap();
+    for _ in 0..10 {
+        let v1 = value.clone();
+        thread_pool.spawn(move || {
+            v1.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    let releaser = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        release.send(()).unwrap();
+    });
+    thread_pool.shutdown();
+    releaser.join().unwrap();
+
+    // the running job finished, the queued ones were dropped
+    assert_eq!(value.load(Ordering::Relaxed), 1);
+    let v1 = value.clone();
+    thread_pool.spawn(move || {
+        v1.fetch_add(1, Ordering::Relaxed);
+    });
+    assert_eq!(Arc::strong_count(&value), 1);
+}
+
+#[test]
+fn job_handles_of_every_pool() {
+    job_handles(NativeThreadPool::new(4).unwrap());
+    job_handles(SharedQueueThreadPool::new(4).unwrap());
+    job_handles(RayonThreadPool::new(4).unwrap());
+    job_handles(WorkStealingThreadPool::new(4).unwrap());
+}
+
+#[tokio::test]
+async fn job_handle_is_awaited() {
+    let thread_pool = SharedQueueThreadPool::new(2).unwrap();
+    let handle = thread_pool.spawn_with_handle(|| {
+        thread::sleep(Duration::from_millis(50));
+        "done".to_string()
+    });
+    assert_eq!(handle.await.unwrap(), "done");
+
+    // a job cancelled by the shutdown never runs
+    let thread_pool =
+        SharedQueueThreadPool::with_shutdown_policy(1, ShutdownPolicy::Cancel).unwrap();
+    let (started, running) = mpsc::channel::<()>();
+    let (release, wait) = mpsc::channel::<()>();
+    let job = thread_pool.spawn_with_handle(move || {
+        started.send(()).unwrap();
+        wait.recv().is_ok()
+    });
+    let queued = thread_pool.spawn_with_handle(|| 1);
+    running.recv().unwrap();
+    let releaser = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        release.send(()).unwrap();
+    });
+    drop(thread_pool);
+    releaser.join().unwrap();
+    assert!(job.await.unwrap());
+    let err = queued.await.unwrap_err();
+    assert!(err.to_string().contains("cancelled"), "{}", err);
+}
+
+#[test]
+fn shared_queue_pool_full_policies() {
+    // Reject: the busy worker and a queue of 2 take 3 jobs
+    let (pool, release) = blocked_pool(FullPolicy::Reject, 2);
+    pool.try_spawn(|| {}).unwrap();
+    pool.try_spawn(|| {}).unwrap();
+    assert_eq!(pool.queue_len(), 2);
+    assert!(pool.try_spawn(|| {}).is_err());
+    release.send(()).unwrap();
+
+    // CallerRuns: the third job runs on this thread
+    let (pool, release) = blocked_pool(FullPolicy::CallerRuns, 1);
+    pool.try_spawn(|| {}).unwrap();
+    let caller = thread::current().id();
+    let ran_on = Arc::new(std::sync::Mutex::new(None));
+    let r1 = ran_on.clone();
+    pool.try_spawn(move || *r1.lock().unwrap() = Some(thread::current().id()))
+        .unwrap();
+    assert_eq!(*ran_on.lock().unwrap(), Some(caller));
+    release.send(()).unwrap();
+
+    // DropOldest: the newest jobs are kept
+    let (pool, release) = blocked_pool(FullPolicy::DropOldest, 2);
+    let handles: Vec<_> = (0..5).map(|i| pool.spawn_with_handle(move || i)).collect();
+    assert_eq!(pool.queue_len(), 2);
+    release.send(()).unwrap();
+    let results: Vec<_> = handles.into_iter().map(|h| h.join().ok()).collect();
+    assert_eq!(results, vec![None, None, None, Some(3), Some(4)]);
+
+    // Block: the caller waits for the worker
+    let (pool, release) = blocked_pool(FullPolicy::Block, 1);
+    pool.try_spawn(|| {}).unwrap();
+    let releaser = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        release.send(()).unwrap();
+    });
+    let handle = pool.spawn_with_handle(|| 1);
+    assert_eq!(handle.join().unwrap(), 1);
+    releaser.join().unwrap();
+}
+
+#[test]
+fn shared_queue_pool_grows_and_reaps_idle_workers() {
+    let pool = SharedQueueThreadPool::with_config(SharedQueueConfig {
+        min_threads: 1,
+        max_threads: Some(4),
+        keep_alive: Duration::from_millis(100),
+        ..Default::default()
+    })
+    .unwrap();
+    assert_eq!(pool.threads(), 1);
+
+    // four jobs waiting for each other only finish on four workers
+    let barrier = Arc::new(Barrier::new(4));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let barrier = barrier.clone();
+            pool.spawn_with_handle(move || {
+                barrier.wait();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(pool.threads(), 4);
+
+    // the workers above the minimum exit after the keep-alive
+    wait_until(|| pool.threads() == 1);
+    assert_eq!(pool.spawn_with_handle(|| 1).join().unwrap(), 1);
+}
+
+#[test]
+fn shared_queue_pool_resizes() {
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    pool.resize(4).unwrap();
+    assert_eq!(pool.threads(), 4);
+
+    let barrier = Arc::new(Barrier::new(4));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let barrier = barrier.clone();
+            pool.spawn_with_handle(move || {
+                barrier.wait();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    pool.resize(1).unwrap();
+    wait_until(|| pool.threads() == 1);
+    wait_until(|| pool.idle_threads() == 1);
+    assert_eq!(pool.spawn_with_handle(|| 1).join().unwrap(), 1);
+    assert_eq!(pool.threads(), 1);
+
+    pool.shutdown();
+    assert!(pool.resize(2).is_err());
+}
+
+#[test]
+fn work_stealing_pool_run_jobs() {
+    let thread_pool = WorkStealingThreadPool::new(4).unwrap();
+    multi_thread_jobs(thread_pool, 32)
+}
+
+#[test]
+fn work_stealing_pool_run_panic_jobs() {
+    let thread_pool = WorkStealingThreadPool::new(4).unwrap();
+    panic_jobs(thread_pool, 12)
+}
+
+#[test]
+fn work_stealing_pool_runs_nested_jobs() {
+    // the jobs spawned by a job go to the deque of its worker, the idle
+    // workers steal them
+    let pool = Arc::new(WorkStealingThreadPool::new(4).unwrap());
+    let value = Arc::new(AtomicU32::new(0));
+    let wg = WaitGroup::new();
+    for _ in 0..4 {
+        let (p1, v1, wg1) = (pool.clone(), value.clone(), wg.clone());
+        pool.spawn(move || {
+            for _ in 0..50 {
+                let (v2, wg2) = (v1.clone(), wg1.clone());
+                p1.spawn(move || {
+                    v2.fetch_add(1, Ordering::Relaxed);
+                    drop(wg2)
+                });
+            }
+            drop(wg1)
+        });
+    }
+    wg.wait();
+    assert_eq!(value.load(Ordering::Relaxed), 200);
+
+    // queued jobs still run when the pool is dropped
+    let pool = WorkStealingThreadPool::new(2).unwrap();
+    let v1 = value.clone();
+    for _ in 0..20 {
+        let v1 = v1.clone();
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(1));
+            v1.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    drop(pool);
+    assert_eq!(value.load(Ordering::Relaxed), 220);
+}
+
+#[test]
+fn pool_builders_name_workers_and_count_jobs() {
+    let panics = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let p1 = panics.clone();
+    let pool = SharedQueueThreadPool::builder()
+        .min_threads(2)
+        .thread_name("kvs-shared")
+        .stack_size(256 * 1024)
+        .panic_handler(move |message| p1.lock().unwrap().push(message.to_string()))
+        .build()
+        .unwrap();
+    counted_jobs(&pool, || pool.stats(), "kvs-shared-");
+    assert_eq!(*panics.lock().unwrap(), vec!["job failed".to_string()]);
+
+    let stats = ServerStats::new();
+    stats.register_pool("shared", pool.metrics());
+    let info = stats.render_info(&EngineStats::default());
+    assert!(
+        info.contains("pool_shared:submitted=7,completed=6,panicked=1,active=0,queued=0"),
+        "{}",
+        info
+    );
+    let metrics = stats.render_prometheus(&EngineStats::default());
+    assert!(
+        metrics.contains("kvs_pool_jobs_total{pool=\"shared\",outcome=\"panicked\"} 1"),
+        "{}",
+        metrics
+    );
+
+    let panics = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let p1 = panics.clone();
+    let pool = WorkStealingThreadPool::builder()
+        .threads(2)
+        .thread_name("kvs-stealing")
+        .panic_handler(move |message| p1.lock().unwrap().push(message.to_string()))
+        .build()
+        .unwrap();
+    counted_jobs(&pool, || pool.stats(), "kvs-stealing-");
+    assert_eq!(*panics.lock().unwrap(), vec!["job failed".to_string()]);
+}
+
+#[test]
+fn shared_queue_pool_runs_jobs_by_priority() {
+    let (pool, release) = blocked_pool(FullPolicy::Block, 8);
+    let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+    for priority in [Priority::Low, Priority::Normal, Priority::High] {
+        for i in 0..2 {
+            let o1 = order.clone();
+            pool.spawn_with_priority(priority, move || o1.lock().unwrap().push((priority, i)));
+        }
+    }
+    release.send(()).unwrap();
+    pool.shutdown();
+
+    assert_eq!(
+        *order.lock().unwrap(),
+        vec![
+            (Priority::High, 0),
+            (Priority::High, 1),
+            (Priority::Normal, 0),
+            (Priority::Normal, 1),
+            (Priority::Low, 0),
+            (Priority::Low, 1),
+        ]
+    );
+}
+
+#[test]
+fn work_stealing_pool_runs_jobs_by_priority() {
+    let pool = WorkStealingThreadPool::new(1).unwrap();
+    let (started_tx, started) = mpsc::channel();
+    let (release, blocked) = mpsc::channel::<()>();
+    pool.spawn(move || {
+        started_tx.send(()).unwrap();
+        blocked.recv().unwrap();
+    });
+    started.recv().unwrap();
+
+    let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+    for priority in [Priority::Low, Priority::Normal, Priority::High] {
+        for i in 0..2 {
+            let o1 = order.clone();
+            pool.spawn_with_priority(priority, move || o1.lock().unwrap().push((priority, i)));
+        }
+    }
+    release.send(()).unwrap();
+    drop(pool);
+
+    assert_eq!(
+        *order.lock().unwrap(),
+        vec![
+            (Priority::High, 0),
+            (Priority::High, 1),
+            (Priority::Normal, 0),
+            (Priority::Normal, 1),
+            (Priority::Low, 0),
+            (Priority::Low, 1),
+        ]
+    );
+}
+
+#[test]
+fn scoped_jobs_of_every_pool() {
+    scoped_jobs(&NativeThreadPool::new(4).unwrap());
+    scoped_jobs(&SharedQueueThreadPool::new(4).unwrap());
+    scoped_jobs(&RayonThreadPool::new(4).unwrap());
+    scoped_jobs(&WorkStealingThreadPool::new(4).unwrap());
+}
+
+#[test]
+fn scope_waits_for_dropped_and_panicked_jobs() {
+    // two of the three jobs are dropped from the queue
+    let (pool, release) = blocked_pool(FullPolicy::DropOldest, 1);
+    let ran = AtomicU32::new(0);
+    pool.scope(|scope| {
+        for _ in 0..3 {
+            scope.spawn(|| {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        release.send(()).unwrap();
+    });
+    assert_eq!(ran.load(Ordering::SeqCst), 1);
+
+    let pool = SharedQueueThreadPool::builder()
+        .min_threads(2)
+        .panic_handler(|_| {})
+        .build()
+        .unwrap();
+    let ran = AtomicU32::new(0);
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        pool.scope(|scope| {
+            scope.spawn(|| {
+                panic_control::disable_hook_in_current_thread();
+                panic!("job failed");
+            });
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        })
+    }));
+    assert!(res.is_err());
+    assert_eq!(ran.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn scope_raises_the_panic_of_a_job_on_the_caller() {
+    // rayon aborts the process on a panic that unwinds its worker
+    let pool = RayonThreadPool::new(2).unwrap();
+    let ran = AtomicU32::new(0);
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        pool.scope(|scope| {
+            scope.spawn(|| {
+                panic_control::disable_hook_in_current_thread();
+                panic!("job failed");
+            });
+            scope.spawn(|| {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        })
+    }));
+    let payload = res.unwrap_err();
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"job failed"));
+    assert_eq!(ran.load(Ordering::SeqCst), 1);
+
+    // the workers are still there
+    pool.scope(|scope| {
+        scope.spawn(|| {
+            ran.fetch_add(1, Ordering::SeqCst);
+        })
+    });
+    assert_eq!(ran.load(Ordering::SeqCst), 2);
+}
+
 #[test]
 fn rayon_thread_pool_run_jobs() {
     let thread_pool = RayonThreadPool::new(16).unwrap();
@@ -78,3 +510,101 @@ fn panic_jobs<T: ThreadPool>(pool: T, jobs: u32) {
     wg.wait();
     assert_eq!(value.load(Ordering::Relaxed), 10 + (jobs / 2) * 10);
 }
+
+fn job_handles<T: ThreadPool>(pool: T) {
+    let handles: Vec<_> = (0..8u32)
+        .map(|i| pool.spawn_with_handle(move || i * 2))
+        .collect();
+    let results: Vec<u32> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(results, (0..8).map(|i| i * 2).collect::<Vec<_>>());
+
+    let handle = pool.spawn_with_handle(|| -> u32 {
+        panic_control::disable_hook_in_current_thread();
+        panic!("job failed")
+    });
+    let err = handle.join().unwrap_err();
+    assert!(err.to_string().contains("job failed"), "{}", err);
+
+    // the pool still runs jobs after the panic
+    assert_eq!(pool.spawn_with_handle(|| 7).join().unwrap(), 7);
+}
+
+// jobs of a scope write to a borrowed buffer and counter
+fn scoped_jobs<T: ThreadPool>(pool: &T) {
+    let mut data = vec![0u32; 64];
+    let sum = AtomicU32::new(0);
+    pool.scope(|scope| {
+        for (i, chunk) in data.chunks_mut(8).enumerate() {
+            let sum = &sum;
+            scope.spawn_with_priority(Priority::High, move || {
+                for value in chunk.iter_mut() {
+                    *value = i as u32;
+                }
+                sum.fetch_add(i as u32, Ordering::SeqCst);
+            });
+        }
+    });
+    assert_eq!(sum.load(Ordering::SeqCst), (0..8).sum::<u32>());
+    assert!(data
+        .chunks(8)
+        .enumerate()
+        .all(|(i, chunk)| chunk.iter().all(|v| *v == i as u32)));
+}
+
+// runs five jobs, one panicking job and one returning the worker name
+fn counted_jobs<T: ThreadPool>(pool: &T, stats: impl Fn() -> PoolStats, name_prefix: &str) {
+    let name = pool
+        .spawn_with_handle(|| thread::current().name().map(String::from))
+        .join()
+        .unwrap()
+        .unwrap();
+    assert!(name.starts_with(name_prefix), "{}", name);
+
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!("job failed");
+    });
+    for _ in 0..5 {
+        pool.spawn(|| thread::sleep(Duration::from_millis(1)));
+    }
+    wait_until(|| stats().finished() == 7);
+
+    let stats = stats();
+    assert_eq!(stats.submitted, 7);
+    assert_eq!(stats.completed, 6);
+    assert_eq!(stats.panicked, 1);
+    assert_eq!(stats.active, 0);
+    assert_eq!(stats.queued, 0);
+}
+
+// polls the condition for up to five seconds
+fn wait_until(condition: impl Fn() -> bool) {
+    for _ in 0..500 {
+        if condition() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("condition not met in time");
+}
+
+// a pool of one worker busy until the sender is used
+fn blocked_pool(policy: FullPolicy, capacity: usize) -> (SharedQueueThreadPool, mpsc::Sender<()>) {
+    let pool = SharedQueueThreadPool::with_config(SharedQueueConfig {
+        min_threads: 1,
+        capacity: Some(capacity),
+        full_policy: policy,
+        ..Default::default()
+    })
+    .unwrap();
+    assert_eq!(pool.capacity(), Some(capacity));
+
+    let (started, running) = mpsc::channel::<()>();
+    let (release, wait) = mpsc::channel::<()>();
+    pool.spawn(move || {
+        started.send(()).unwrap();
+        let _ = wait.recv();
+    });
+    running.recv().unwrap();
+    (pool, release)
+}
@@ -0,0 +1,136 @@
+use std::any::Any;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+use anyhow::{anyhow, Result};
+
+// JobHandle is the result of a job spawned with spawn_with_handle. It is
+// joined from a thread or awaited from a task, a panic of the job is an error.
+pub struct JobHandle<T> {
+    state: Arc<JobState<T>>,
+}
+
+struct JobState<T> {
+    slot: Mutex<JobSlot<T>>,
+    done: Condvar,
+}
+
+struct JobSlot<T> {
+    result: Option<Result<T>>,
+    waker: Option<Waker>,
+}
+
+impl<T> JobHandle<T> {
+    // blocks until the job finished
+    pub fn join(self) -> Result<T> {
+        let mut slot = self.state.lock();
+        loop {
+            if let Some(result) = slot.result.take() {
+                return result;
+            }
+            slot = self
+                .state
+                .done
+                .wait(slot)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().result.is_some()
+    }
+}
+
+impl<T> Future for JobHandle<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.state.lock();
+        match slot.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> JobState<T> {
+    fn lock(&self) -> MutexGuard<'_, JobSlot<T>> {
+        self.slot.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// JobCompleter is moved into the job and stores its result. A job dropped
+// without running, e.g. cancelled by a shutdown, completes with an error.
+struct JobCompleter<T> {
+    state: Option<Arc<JobState<T>>>,
+}
+
+impl<T> JobCompleter<T> {
+    fn complete(&mut self, result: Result<T>) {
+        if let Some(state) = self.state.take() {
+            let waker = {
+                let mut slot = state.lock();
+                slot.result = Some(result);
+                slot.waker.take()
+            };
+            state.done.notify_all();
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> Drop for JobCompleter<T> {
+    fn drop(&mut self) {
+        self.complete(Err(anyhow!("job was cancelled")));
+    }
+}
+
+fn job_handle<T>() -> (JobHandle<T>, JobCompleter<T>) {
+    let state = Arc::new(JobState {
+        slot: Mutex::new(JobSlot {
+            result: None,
+            waker: None,
+        }),
+        done: Condvar::new(),
+    });
+    (
+        JobHandle {
+            state: state.clone(),
+        },
+        JobCompleter { state: Some(state) },
+    )
+}
+
+// wraps a job so that its result or panic goes to the handle, the panic does
+// not reach the worker thread
+pub(crate) fn with_handle<F, T>(job: F) -> (impl FnOnce() + Send + 'static, JobHandle<T>)
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (handle, mut completer) = job_handle();
+    let job = move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(job))
+            .map_err(|payload| anyhow!("job panicked: {}", panic_message(payload.as_ref())));
+        completer.complete(result);
+    };
+    (job, handle)
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
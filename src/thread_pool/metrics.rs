@@ -0,0 +1,114 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::thread_pool::job::panic_message;
+use crate::thread_pool::worker::WorkerOptions;
+
+// PoolMetrics counts the jobs of a pool. It is shared, so the server stats can
+// report a pool without holding it.
+#[derive(Debug, Default)]
+pub struct PoolMetrics {
+    submitted: AtomicU64,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+    active: AtomicU64,
+    queued: AtomicU64,
+    latency_micros: AtomicU64,
+}
+
+// a point-in-time view of PoolMetrics
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    // jobs given to the pool, including the rejected and dropped ones
+    pub submitted: u64,
+    pub completed: u64,
+    pub panicked: u64,
+    // workers running a job
+    pub active: u64,
+    // jobs waiting for a worker
+    pub queued: u64,
+    // sum of the time from submitting to the end of every finished job
+    pub latency_micros: u64,
+}
+
+impl PoolStats {
+    pub fn finished(&self) -> u64 {
+        self.completed + self.panicked
+    }
+
+    pub fn mean_latency(&self) -> Duration {
+        match self.finished() {
+            0 => Duration::ZERO,
+            finished => Duration::from_micros(self.latency_micros / finished),
+        }
+    }
+}
+
+impl PoolMetrics {
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            submitted: self.submitted.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            panicked: self.panicked.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+            queued: self.queued.load(Ordering::Relaxed),
+            latency_micros: self.latency_micros.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// a job that has not started, dropping it without running takes it out of
+// the queue gauge
+struct Queued {
+    metrics: Arc<PoolMetrics>,
+    submitted: Instant,
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Wraps a job to count it. A panic of the job is caught and reported to the
+// panic handler, so the worker keeps running.
+pub(crate) fn metered<F>(
+    metrics: &Arc<PoolMetrics>,
+    options: &WorkerOptions,
+    job: F,
+) -> impl FnOnce() + Send + 'static
+where
+    F: FnOnce() + Send + 'static,
+{
+    metrics.submitted.fetch_add(1, Ordering::Relaxed);
+    metrics.queued.fetch_add(1, Ordering::Relaxed);
+    let queued = Queued {
+        metrics: metrics.clone(),
+        submitted: Instant::now(),
+    };
+    let options = options.clone();
+
+    move || {
+        let (metrics, submitted) = (queued.metrics.clone(), queued.submitted);
+        drop(queued);
+
+        metrics.active.fetch_add(1, Ordering::Relaxed);
+        let result = panic::catch_unwind(AssertUnwindSafe(job));
+        metrics.active.fetch_sub(1, Ordering::Relaxed);
+        metrics
+            .latency_micros
+            .fetch_add(submitted.elapsed().as_micros() as u64, Ordering::Relaxed);
+
+        match result {
+            Ok(()) => {
+                metrics.completed.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(payload) => {
+                metrics.panicked.fetch_add(1, Ordering::Relaxed);
+                options.report_panic(panic_message(payload.as_ref()));
+            }
+        }
+    }
+}
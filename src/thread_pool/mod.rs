@@ -1,8 +1,36 @@
 use anyhow::Result;
 
+pub mod job;
+pub mod metrics;
 pub mod native;
 pub mod rayon;
+pub mod scope;
 pub mod shared_queue;
+pub mod work_stealing;
+pub mod worker;
+
+pub use job::JobHandle;
+pub use metrics::{PoolMetrics, PoolStats};
+pub use scope::Scope;
+pub use worker::{PanicHandler, WorkerOptions};
+
+// Priority orders the queued jobs, e.g. foreground requests before
+// background compaction. A job runs after the queued jobs of its priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    pub(crate) fn index(self) -> usize {
+        self as usize
+    }
+}
 
 pub trait ThreadPool {
     fn new(num: u32) -> Result<Self>
@@ -12,4 +40,37 @@ pub trait ThreadPool {
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    // runs the job like spawn and returns a handle to its result, a panic of
+    // the job is returned as an error instead of unwinding the worker
+    fn spawn_with_handle<F, T>(&self, job: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = job::with_handle(job);
+        self.spawn(job);
+        handle
+    }
+
+    // Pools without priorities run the job like spawn, the native and rayon
+    // pools. The shared queue and work-stealing pools run it by priority.
+    fn spawn_with_priority<F>(&self, priority: Priority, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let _ = priority;
+        self.spawn(job)
+    }
+
+    // Runs f with a scope whose jobs may borrow from the caller, and returns
+    // after every job of the scope finished. Calling it from a job of the same
+    // pool can deadlock when every worker waits for a scope.
+    fn scope<'env, F, R>(&'env self, f: F) -> R
+    where
+        F: FnOnce(&Scope<'env, Self>) -> R,
+        Self: Sized,
+    {
+        scope::scope(self, f)
+    }
 }
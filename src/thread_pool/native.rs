@@ -15,6 +15,6 @@ impl ThreadPool for NativeThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(move || job());
+        thread::spawn(job);
     }
 }
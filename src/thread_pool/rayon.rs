@@ -2,6 +2,8 @@ use anyhow::{anyhow, Result};
 
 use crate::thread_pool::ThreadPool;
 
+// RayonThreadPool has no priorities, spawn_with_priority runs the jobs in the
+// order rayon picks like spawn.
 pub struct RayonThreadPool(rayon::ThreadPool);
 
 impl ThreadPool for RayonThreadPool {
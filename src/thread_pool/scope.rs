@@ -0,0 +1,126 @@
+use std::any::Any;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::thread_pool::{Priority, ThreadPool};
+
+type Job<'env> = Box<dyn FnOnce() + Send + 'env>;
+
+// Scope spawns jobs that borrow data living for 'env, see ThreadPool::scope.
+pub struct Scope<'env, P: ThreadPool> {
+    pool: &'env P,
+    state: Arc<ScopeState>,
+    // invariant, a scope cannot be turned into one of a shorter 'env
+    _env: PhantomData<&'env mut &'env ()>,
+}
+
+struct ScopeState {
+    // jobs neither finished nor dropped
+    pending: Mutex<usize>,
+    done: Condvar,
+    // the first panic of a job, raised again by the scope on its caller
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl<'env, P: ThreadPool> Scope<'env, P> {
+    pub fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'env,
+    {
+        self.spawn_with_priority(Priority::Normal, job)
+    }
+
+    pub fn spawn_with_priority<F>(&self, priority: Priority, job: F)
+    where
+        F: FnOnce() + Send + 'env,
+    {
+        *self.state.lock() += 1;
+        let job: Job<'env> = Box::new(job);
+        // SAFETY: the scope does not return before the job ran or was
+        // dropped, see ScopedJob, so what the job borrows outlives it.
+        let job: Job<'static> = unsafe { mem::transmute::<Job<'env>, Job<'static>>(job) };
+        let mut scoped = ScopedJob {
+            job: Some(job),
+            state: self.state.clone(),
+        };
+        self.pool
+            .spawn_with_priority(priority, move || scoped.run());
+    }
+}
+
+impl ScopeState {
+    fn lock(&self) -> std::sync::MutexGuard<'_, usize> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn wait(&self) {
+        let mut pending = self.lock();
+        while *pending > 0 {
+            pending = self.done.wait(pending).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+// ScopedJob leaves the scope when it is dropped, after the job ran or when a
+// pool drops it without running, e.g. on a cancelling shutdown.
+struct ScopedJob {
+    job: Option<Job<'static>>,
+    state: Arc<ScopeState>,
+}
+
+impl ScopedJob {
+    fn run(&mut self) {
+        if let Some(job) = self.job.take() {
+            // A panic does not unwind the worker, some pools abort on it,
+            // e.g. rayon without a panic handler.
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                let mut panic = self.state.panic.lock().unwrap_or_else(|e| e.into_inner());
+                panic.get_or_insert(payload);
+            }
+        }
+    }
+}
+
+impl Drop for ScopedJob {
+    fn drop(&mut self) {
+        // the borrows of the job end before the scope may return
+        drop(self.job.take());
+        let mut pending = self.state.lock();
+        *pending -= 1;
+        if *pending == 0 {
+            self.state.done.notify_all();
+        }
+    }
+}
+
+pub(crate) fn scope<'env, P, F, R>(pool: &'env P, f: F) -> R
+where
+    P: ThreadPool,
+    F: FnOnce(&Scope<'env, P>) -> R,
+{
+    let scope = Scope {
+        pool,
+        state: Arc::new(ScopeState {
+            pending: Mutex::new(0),
+            done: Condvar::new(),
+            panic: Mutex::new(None),
+        }),
+        _env: PhantomData,
+    };
+    // the jobs are waited for even if f panics, they may borrow from its caller
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+    scope.state.wait();
+
+    let job_panic = scope
+        .state
+        .panic
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take();
+    match (result, job_panic) {
+        (Err(payload), _) | (Ok(_), Some(payload)) => panic::resume_unwind(payload),
+        (Ok(value), None) => value,
+    }
+}
@@ -1,60 +1,573 @@
-use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use crossbeam::channel::{unbounded, Receiver, Sender};
+use anyhow::{anyhow, Result};
+use crossbeam::channel::{
+    bounded, unbounded, Receiver, RecvTimeoutError, Select, Sender, TryRecvError, TrySendError,
+};
 
-use crate::thread_pool::ThreadPool;
+use crate::thread_pool::metrics::{metered, PoolMetrics, PoolStats};
+use crate::thread_pool::{PanicHandler, Priority, ThreadPool, WorkerOptions};
 
 pub struct SharedQueueThreadPool {
-    sender: Sender<ThreadPoolMessage>,
+    // a queue per priority, in the order of Priority::ALL
+    senders: Vec<Sender<ThreadPoolMessage>>,
+    state: Arc<PoolState>,
+    config: SharedQueueConfig,
+}
+
+#[derive(Debug, Clone)]
+pub struct SharedQueueConfig {
+    // workers kept even when they are idle
+    pub min_threads: u32,
+    // workers started while the queue backs up, fixed at min_threads if none
+    pub max_threads: Option<u32>,
+    // a worker above min_threads idle this long exits
+    pub keep_alive: Duration,
+    // jobs of one priority waiting for a worker at most, unbounded if none
+    pub capacity: Option<usize>,
+    // what spawning does when the queue is full
+    pub full_policy: FullPolicy,
+    pub shutdown_policy: ShutdownPolicy,
+}
+
+impl Default for SharedQueueConfig {
+    fn default() -> Self {
+        SharedQueueConfig {
+            min_threads: 4,
+            max_threads: None,
+            keep_alive: Duration::from_secs(60),
+            capacity: None,
+            full_policy: FullPolicy::default(),
+            shutdown_policy: ShutdownPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FullPolicy {
+    // the caller waits until a worker takes a job
+    #[default]
+    Block,
+    // try_spawn returns an error
+    Reject,
+    // the job runs on the calling thread, which slows down the producer
+    CallerRuns,
+    // the oldest queued job is dropped to make room
+    DropOldest,
 }
 
 pub enum ThreadPoolMessage {
     RunJob(Box<dyn FnOnce() + Send + 'static>),
+    // stops a worker on shutdown, otherwise retires one above the pool size.
+    // It is queued behind the jobs of every priority.
     Shutdown,
 }
 
-impl ThreadPool for SharedQueueThreadPool {
-    fn new(num: u32) -> anyhow::Result<Self>
+// what happens to the queued jobs when the pool shuts down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShutdownPolicy {
+    // the workers run every queued job before they exit
+    #[default]
+    Drain,
+    // the queued jobs are dropped, the running ones still finish
+    Cancel,
+}
+
+// PoolState is shared by the pool and its workers. A worker replacing a
+// panicked one adds its handle before the panicked thread exits, a retired
+// worker removes its own handle.
+struct PoolState {
+    receivers: Vec<Receiver<ThreadPoolMessage>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    // workers started and not exited, a replacement takes over the count of
+    // the panicked worker
+    live: AtomicU32,
+    // workers waiting for a message
+    idle: AtomicU32,
+    min_threads: AtomicU32,
+    max_threads: AtomicU32,
+    keep_alive: Duration,
+    shutdown: AtomicBool,
+    options: WorkerOptions,
+    metrics: Arc<PoolMetrics>,
+    // numbers the worker threads
+    spawned: AtomicU64,
+}
+
+// SharedQueueBuilder sets the config of the pool and the options of its
+// workers.
+#[derive(Debug, Default)]
+pub struct SharedQueueBuilder {
+    config: SharedQueueConfig,
+    options: WorkerOptions,
+}
+
+impl SharedQueueBuilder {
+    pub fn config(mut self, config: SharedQueueConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn min_threads(mut self, threads: u32) -> Self {
+        self.config.min_threads = threads;
+        self
+    }
+
+    pub fn max_threads(mut self, threads: u32) -> Self {
+        self.config.max_threads = Some(threads);
+        self
+    }
+
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.config.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.config.capacity = Some(capacity);
+        self
+    }
+
+    pub fn full_policy(mut self, policy: FullPolicy) -> Self {
+        self.config.full_policy = policy;
+        self
+    }
+
+    pub fn shutdown_policy(mut self, policy: ShutdownPolicy) -> Self {
+        self.config.shutdown_policy = policy;
+        self
+    }
+
+    pub fn thread_name(mut self, prefix: impl Into<String>) -> Self {
+        self.options.thread_name = Some(prefix.into());
+        self
+    }
+
+    pub fn stack_size(mut self, bytes: usize) -> Self {
+        self.options.stack_size = Some(bytes);
+        self
+    }
+
+    pub fn panic_handler<F>(mut self, handler: F) -> Self
     where
-        Self: Sized,
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.options.panic_handler = Some(Arc::new(handler) as PanicHandler);
+        self
+    }
+
+    pub fn build(self) -> Result<SharedQueueThreadPool> {
+        SharedQueueThreadPool::build(self.config, self.options)
+    }
+}
+
+impl SharedQueueThreadPool {
+    pub fn with_shutdown_policy(num: u32, policy: ShutdownPolicy) -> Result<Self> {
+        Self::with_config(SharedQueueConfig {
+            min_threads: num,
+            shutdown_policy: policy,
+            ..Default::default()
+        })
+    }
+
+    pub fn with_config(config: SharedQueueConfig) -> Result<Self> {
+        Self::build(config, WorkerOptions::default())
+    }
+
+    pub fn builder() -> SharedQueueBuilder {
+        SharedQueueBuilder::default()
+    }
+
+    fn build(config: SharedQueueConfig, options: WorkerOptions) -> Result<Self> {
+        let (senders, receivers) = Priority::ALL
+            .iter()
+            .map(|_| match config.capacity {
+                Some(capacity) => bounded::<ThreadPoolMessage>(capacity),
+                None => unbounded::<ThreadPoolMessage>(),
+            })
+            .unzip();
+        let max_threads = config
+            .max_threads
+            .unwrap_or(config.min_threads)
+            .max(config.min_threads);
+        let state = Arc::new(PoolState {
+            receivers,
+            workers: Mutex::new(Vec::with_capacity(max_threads as usize)),
+            live: AtomicU32::new(0),
+            idle: AtomicU32::new(0),
+            min_threads: AtomicU32::new(config.min_threads),
+            max_threads: AtomicU32::new(max_threads),
+            keep_alive: config.keep_alive,
+            shutdown: AtomicBool::new(false),
+            options,
+            metrics: Arc::new(PoolMetrics::default()),
+            spawned: AtomicU64::new(0),
+        });
+
+        for _ in 0..config.min_threads {
+            spawn_worker(&state, true)?;
+        }
+
+        Ok(SharedQueueThreadPool {
+            senders,
+            state,
+            config,
+        })
+    }
+
+    pub fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.try_spawn_with_priority(Priority::Normal, job)
+    }
+
+    // Queues the job behind the jobs of its priority, or applies the full
+    // policy when that queue is at its capacity. Fails after shutdown and when
+    // the Reject policy rejects it.
+    pub fn try_spawn_with_priority<F>(&self, priority: Priority, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
     {
-        let (sender, receiver) = unbounded::<ThreadPoolMessage>();
+        let sender = &self.senders[priority.index()];
+        if self.state.shutdown.load(Ordering::SeqCst) {
+            return Err(anyhow!("thread pool is shut down"));
+        }
+
+        let job = metered(&self.state.metrics, &self.state.options, job);
+        let mut message = ThreadPoolMessage::RunJob(Box::new(job));
+        loop {
+            message = match sender.try_send(message) {
+                Ok(()) => {
+                    grow_if_backed_up(&self.state);
+                    return Ok(());
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    return Err(anyhow!("thread pool is shut down"))
+                }
+                Err(TrySendError::Full(message)) => message,
+            };
+            grow_if_backed_up(&self.state);
+
+            match self.config.full_policy {
+                FullPolicy::Block => {
+                    return sender
+                        .send(message)
+                        .map_err(|_| anyhow!("thread pool is shut down"))
+                }
+                FullPolicy::Reject => return Err(anyhow!("thread pool queue is full")),
+                FullPolicy::CallerRuns => {
+                    if let ThreadPoolMessage::RunJob(job) = message {
+                        job();
+                    }
+                    return Ok(());
+                }
+                FullPolicy::DropOldest => match self.state.receivers[priority.index()].try_recv() {
+                    Ok(ThreadPoolMessage::RunJob(oldest)) => drop(oldest),
+                    // a shutdown started meanwhile, its message goes back. A
+                    // lost retirement only waits until the next job finished.
+                    Ok(ThreadPoolMessage::Shutdown) => {
+                        if self.state.shutdown.load(Ordering::SeqCst) {
+                            let _ = sender.send(ThreadPoolMessage::Shutdown);
+                            return Err(anyhow!("thread pool is shut down"));
+                        }
+                    }
+                    Err(_) => {}
+                },
+            }
+        }
+    }
 
-        for _ in 0..num {
-            let recv = receiver.clone();
-            let job_receiver = JobReceiver(recv);
-            thread::spawn(move || run_job(&job_receiver));
+    // Sets the pool to exactly n workers, at least one. New workers start at
+    // once, workers above n exit after their current job.
+    pub fn resize(&self, n: u32) -> Result<()> {
+        if self.state.shutdown.load(Ordering::SeqCst) {
+            return Err(anyhow!("thread pool is shut down"));
         }
+        let n = n.max(1);
+        let state = &self.state;
+        state.min_threads.store(n, Ordering::SeqCst);
+        state.max_threads.store(n, Ordering::SeqCst);
 
-        Ok(SharedQueueThreadPool { sender })
+        let live = state.live.load(Ordering::SeqCst);
+        for _ in live..n {
+            spawn_worker(state, true)?;
+        }
+        // wakes the idle workers above n, the busy ones see the new size when
+        // their job finished
+        for _ in n..live {
+            if self
+                .control()
+                .try_send(ThreadPoolMessage::Shutdown)
+                .is_err()
+            {
+                break;
+            }
+        }
+        Ok(())
     }
 
+    // the queue of the lowest priority, a Shutdown in it comes after every job
+    fn control(&self) -> &Sender<ThreadPoolMessage> {
+        &self.senders[Priority::Low.index()]
+    }
+
+    // workers running now
+    pub fn threads(&self) -> u32 {
+        self.state.live.load(Ordering::SeqCst)
+    }
+
+    // workers waiting for a job
+    pub fn idle_threads(&self) -> u32 {
+        self.state.idle.load(Ordering::SeqCst)
+    }
+
+    // the counters of the pool, they outlive it
+    pub fn metrics(&self) -> Arc<PoolMetrics> {
+        self.state.metrics.clone()
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.state.metrics.stats()
+    }
+
+    // jobs waiting for a worker
+    pub fn queue_len(&self) -> usize {
+        self.state.queue_len()
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        self.config.capacity
+    }
+
+    // Stops every worker and waits for the threads to exit, what happens to
+    // the queued jobs depends on the policy. Jobs spawned afterwards are
+    // dropped. Calling it again does nothing.
+    pub fn shutdown(&self) {
+        let state = &self.state;
+        if state.shutdown.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        if self.config.shutdown_policy == ShutdownPolicy::Cancel {
+            for receiver in state.receivers.iter() {
+                while receiver.try_recv().is_ok() {}
+            }
+        }
+        // no worker starts once the flag is set, so the count under the lock
+        // is the final one. A worker exits at the first Shutdown, so every
+        // worker gets one.
+        let live = {
+            let _workers = state.workers.lock().unwrap_or_else(|e| e.into_inner());
+            state.live.load(Ordering::SeqCst)
+        };
+        for _ in 0..live {
+            let _ = self.control().send(ThreadPoolMessage::Shutdown);
+        }
+
+        loop {
+            let worker = state
+                .workers
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .pop();
+            let Some(worker) = worker else { break };
+            // a job dropping the pool runs on one of the workers
+            if worker.thread().id() != thread::current().id() {
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(num: u32) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::with_config(SharedQueueConfig {
+            min_threads: num,
+            ..Default::default()
+        })
+    }
+
+    // a job that cannot be queued is dropped, try_spawn reports why
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let _ = self.sender.send(ThreadPoolMessage::RunJob(Box::new(job)));
+        if let Err(e) = self.try_spawn(job) {
+            log::warn!("job is dropped: {}", e);
+        }
+    }
+
+    fn spawn_with_priority<F>(&self, priority: Priority, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(e) = self.try_spawn_with_priority(priority, job) {
+            log::warn!("job is dropped: {}", e);
+        }
+    }
+}
+
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl PoolState {
+    fn queue_len(&self) -> usize {
+        self.receivers.iter().map(|receiver| receiver.len()).sum()
+    }
+
+    // the first message of the highest priority queue that has one
+    fn recv_timeout(&self, timeout: Duration) -> Result<ThreadPoolMessage, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut disconnected = 0;
+            for receiver in self.receivers.iter() {
+                match receiver.try_recv() {
+                    Ok(message) => return Ok(message),
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => disconnected += 1,
+                }
+            }
+            if disconnected == self.receivers.len() {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+
+            // another worker may take the message first, the loop checks
+            // every queue again
+            let mut select = Select::new();
+            for receiver in self.receivers.iter() {
+                select.recv(receiver);
+            }
+            if select.ready_deadline(deadline).is_err() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+        }
+    }
+}
+
+// Starts one more worker if more jobs are queued than workers are idle. The
+// spawner checks after queuing and a worker after taking a job, so whichever
+// comes last sees the backlog.
+fn grow_if_backed_up(state: &Arc<PoolState>) {
+    if state.queue_len() > state.idle.load(Ordering::SeqCst) as usize
+        && state.live.load(Ordering::SeqCst) < state.max_threads.load(Ordering::SeqCst)
+    {
+        if let Err(e) = spawn_worker(state, true) {
+            log::warn!("failed to grow the thread pool: {}", e);
+        }
+    }
+}
+
+// Starts a worker, a new one is counted and is not started beyond the
+// maximum or after shutdown. A replacement of a panicked worker always starts,
+// it may have to drain the queue.
+fn spawn_worker(state: &Arc<PoolState>, new: bool) -> std::io::Result<()> {
+    // the lock is held until the handle is stored, so shutdown cannot miss it
+    let mut workers = state.workers.lock().unwrap_or_else(|e| e.into_inner());
+    if new {
+        if state.shutdown.load(Ordering::SeqCst)
+            || state.live.load(Ordering::SeqCst) >= state.max_threads.load(Ordering::SeqCst)
+        {
+            return Ok(());
+        }
+        state.live.fetch_add(1, Ordering::SeqCst);
+    }
+    state.idle.fetch_add(1, Ordering::SeqCst);
+
+    let job_receiver = JobReceiver {
+        state: state.clone(),
+    };
+    let n = state.spawned.fetch_add(1, Ordering::Relaxed);
+    match state
+        .options
+        .thread(n)
+        .spawn(move || run_job(&job_receiver))
+    {
+        Ok(handle) => {
+            workers.push(handle);
+            Ok(())
+        }
+        Err(e) => {
+            if new {
+                state.live.fetch_sub(1, Ordering::SeqCst);
+            }
+            state.idle.fetch_sub(1, Ordering::SeqCst);
+            Err(e)
+        }
     }
 }
 
-struct JobReceiver(Receiver<ThreadPoolMessage>);
+struct JobReceiver {
+    state: Arc<PoolState>,
+}
 
 impl Drop for JobReceiver {
     fn drop(&mut self) {
+        // jobs catch their panics, so the panic handler panicked. Replace this
+        // worker with a fresh thread, running the loop inside drop would abort
+        // the process on the next panic.
         if thread::panicking() {
-            run_job(self)
+            if let Err(e) = spawn_worker(&self.state, false) {
+                self.state.live.fetch_sub(1, Ordering::SeqCst);
+                log::error!("failed to replace a panicked worker: {}", e);
+            }
         }
     }
 }
 
 fn run_job(job_receiver: &JobReceiver) {
+    let state = &job_receiver.state;
     loop {
-        match job_receiver.0.recv() {
-            Ok(msg) => match msg {
-                ThreadPoolMessage::RunJob(job) => job(),
-                ThreadPoolMessage::Shutdown => break,
-            },
-            Err(e) => log::error!("job receiver has error: {}", e),
+        let exit = match state.recv_timeout(state.keep_alive) {
+            Ok(ThreadPoolMessage::RunJob(job)) => {
+                state.idle.fetch_sub(1, Ordering::SeqCst);
+                grow_if_backed_up(state);
+                job();
+                state.idle.fetch_add(1, Ordering::SeqCst);
+                retire(state, &state.max_threads)
+            }
+            Ok(ThreadPoolMessage::Shutdown) if state.shutdown.load(Ordering::SeqCst) => {
+                state.live.fetch_sub(1, Ordering::SeqCst);
+                break;
+            }
+            Ok(ThreadPoolMessage::Shutdown) => retire(state, &state.max_threads),
+            Err(RecvTimeoutError::Timeout) => retire(state, &state.min_threads),
+            // every sender is gone, no job can arrive any more
+            Err(RecvTimeoutError::Disconnected) => {
+                state.live.fetch_sub(1, Ordering::SeqCst);
+                break;
+            }
+        };
+        if exit {
+            // the handle is joined by nobody, the thread detaches
+            let id = thread::current().id();
+            state
+                .workers
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .retain(|worker| worker.thread().id() != id);
+            break;
         }
     }
+    state.idle.fetch_sub(1, Ordering::SeqCst);
+}
+
+// takes this worker out of the count if the pool has more workers than the
+// limit, the caller exits then
+fn retire(state: &PoolState, limit: &AtomicU32) -> bool {
+    state
+        .live
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+            (live > limit.load(Ordering::SeqCst)).then(|| live - 1)
+        })
+        .is_ok()
 }
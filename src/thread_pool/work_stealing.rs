@@ -0,0 +1,277 @@
+use std::cell::RefCell;
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use anyhow::Result;
+use crossbeam::deque::{Injector, Stealer, Worker};
+
+use crate::thread_pool::metrics::{metered, PoolMetrics, PoolStats};
+use crate::thread_pool::{PanicHandler, Priority, ThreadPool, WorkerOptions};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// a queue per priority, in the order of Priority::ALL
+type Queues<T> = [T; Priority::ALL.len()];
+
+// the deques of a worker
+type Local = Queues<Worker<Job>>;
+
+// WorkStealingThreadPool gives every worker its own deque. Jobs spawned from
+// outside go to a global queue, jobs spawned by a job go to the deque of its
+// worker. A worker without jobs takes a batch from the global queue or steals
+// from the other workers, so the workers rarely contend on one queue.
+// Every queue is kept per priority, a worker looks for a job of a higher
+// priority in all of them before one of a lower priority.
+pub struct WorkStealingThreadPool {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+struct Shared {
+    injectors: Queues<Injector<Job>>,
+    stealers: Vec<Queues<Stealer<Job>>>,
+    // workers without a job wait on the condvar, spawn only takes the lock
+    // when one of them sleeps
+    sleep: Mutex<()>,
+    wake: Condvar,
+    sleeping: AtomicUsize,
+    shutdown: AtomicBool,
+    options: WorkerOptions,
+    metrics: Arc<PoolMetrics>,
+}
+
+// WorkStealingBuilder sets the size of the pool and the options of its
+// workers.
+#[derive(Debug)]
+pub struct WorkStealingBuilder {
+    threads: u32,
+    options: WorkerOptions,
+}
+
+impl Default for WorkStealingBuilder {
+    fn default() -> Self {
+        WorkStealingBuilder {
+            threads: 4,
+            options: WorkerOptions::default(),
+        }
+    }
+}
+
+impl WorkStealingBuilder {
+    pub fn threads(mut self, threads: u32) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    pub fn thread_name(mut self, prefix: impl Into<String>) -> Self {
+        self.options.thread_name = Some(prefix.into());
+        self
+    }
+
+    pub fn stack_size(mut self, bytes: usize) -> Self {
+        self.options.stack_size = Some(bytes);
+        self
+    }
+
+    pub fn panic_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.options.panic_handler = Some(Arc::new(handler) as PanicHandler);
+        self
+    }
+
+    pub fn build(self) -> Result<WorkStealingThreadPool> {
+        WorkStealingThreadPool::build(self.threads, self.options)
+    }
+}
+
+thread_local! {
+    // the deque of the current worker and the pool it belongs to
+    static LOCAL: RefCell<Option<(usize, Rc<Local>)>> = const { RefCell::new(None) };
+}
+
+impl WorkStealingThreadPool {
+    pub fn builder() -> WorkStealingBuilder {
+        WorkStealingBuilder::default()
+    }
+
+    // the counters of the pool, they outlive it
+    pub fn metrics(&self) -> Arc<PoolMetrics> {
+        self.shared.metrics.clone()
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.shared.metrics.stats()
+    }
+
+    fn id(&self) -> usize {
+        Arc::as_ptr(&self.shared) as usize
+    }
+
+    fn build(num: u32, options: WorkerOptions) -> Result<Self> {
+        let locals: Vec<Local> = (0..num.max(1))
+            .map(|_| Priority::ALL.map(|_| Worker::new_fifo()))
+            .collect();
+        let shared = Arc::new(Shared {
+            injectors: Priority::ALL.map(|_| Injector::new()),
+            stealers: locals
+                .iter()
+                .map(|local| local.each_ref().map(|worker| worker.stealer()))
+                .collect(),
+            sleep: Mutex::new(()),
+            wake: Condvar::new(),
+            sleeping: AtomicUsize::new(0),
+            shutdown: AtomicBool::new(false),
+            options,
+            metrics: Arc::new(PoolMetrics::default()),
+        });
+
+        let mut pool = WorkStealingThreadPool {
+            shared,
+            workers: Vec::with_capacity(locals.len()),
+        };
+        for (n, local) in locals.into_iter().enumerate() {
+            let (shared, id) = (pool.shared.clone(), pool.id());
+            // a failed spawn drops the pool, which stops the started workers
+            let handle = pool
+                .shared
+                .options
+                .thread(n as u64)
+                .spawn(move || run_worker(id, local, &shared))?;
+            pool.workers.push(handle);
+        }
+        Ok(pool)
+    }
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(num: u32) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::build(num, WorkerOptions::default())
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn_with_priority(Priority::Normal, job)
+    }
+
+    fn spawn_with_priority<F>(&self, priority: Priority, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let job: Job = Box::new(metered(&self.shared.metrics, &self.shared.options, job));
+        let id = self.id();
+        let job = LOCAL.with(|local| match local.borrow().as_ref() {
+            Some((pool, local)) if *pool == id => {
+                local[priority.index()].push(job);
+                None
+            }
+            _ => Some(job),
+        });
+        if let Some(job) = job {
+            self.shared.injectors[priority.index()].push(job);
+        }
+        self.shared.notify();
+    }
+}
+
+impl Drop for WorkStealingThreadPool {
+    // the workers run every queued job before they exit
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        {
+            let _sleep = self.shared.sleep.lock().unwrap_or_else(|e| e.into_inner());
+            self.shared.wake.notify_all();
+        }
+        for worker in self.workers.drain(..) {
+            // a job dropping the pool runs on one of the workers
+            if worker.thread().id() != thread::current().id() {
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+impl Shared {
+    // wakes a sleeping worker after a job was pushed. A worker going to sleep
+    // checks the queues after counting itself, so one of the two sees the
+    // other.
+    fn notify(&self) {
+        atomic::fence(Ordering::SeqCst);
+        if self.sleeping.load(Ordering::SeqCst) > 0 {
+            let _sleep = self.sleep.lock().unwrap_or_else(|e| e.into_inner());
+            self.wake.notify_one();
+        }
+    }
+
+    fn has_jobs(&self) -> bool {
+        self.injectors.iter().any(|injector| !injector.is_empty())
+            || self
+                .stealers
+                .iter()
+                .flatten()
+                .any(|stealer| !stealer.is_empty())
+    }
+
+    // the job of the highest priority there is
+    fn find_job(&self, local: &Local) -> Option<Job> {
+        Priority::ALL
+            .iter()
+            .find_map(|priority| self.find_job_of(priority.index(), &local[priority.index()]))
+    }
+
+    // the own deque first, then a batch of the global queue, then one job of
+    // another worker
+    fn find_job_of(&self, priority: usize, local: &Worker<Job>) -> Option<Job> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injectors[priority]
+                    .steal_batch_and_pop(local)
+                    .or_else(|| {
+                        self.stealers
+                            .iter()
+                            .map(|stealers| stealers[priority].steal())
+                            .collect()
+                    })
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(|steal| steal.success())
+        })
+    }
+}
+
+fn run_worker(id: usize, local: Local, shared: &Shared) {
+    let local = Rc::new(local);
+    LOCAL.with(|cell| *cell.borrow_mut() = Some((id, local.clone())));
+
+    loop {
+        if let Some(job) = shared.find_job(&local) {
+            // jobs catch their panics, a panicking panic handler must not take
+            // the jobs of the deque with it
+            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            continue;
+        }
+        if shared.shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let sleep = shared.sleep.lock().unwrap_or_else(|e| e.into_inner());
+        shared.sleeping.fetch_add(1, Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+        if !shared.has_jobs() && !shared.shutdown.load(Ordering::SeqCst) {
+            let _sleep = shared.wake.wait(sleep).unwrap_or_else(|e| e.into_inner());
+        }
+        shared.sleeping.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    LOCAL.with(|cell| *cell.borrow_mut() = None);
+}
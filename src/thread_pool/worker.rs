@@ -0,0 +1,49 @@
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+
+// called on the worker thread with the message of a panicked job
+pub type PanicHandler = Arc<dyn Fn(&str) + Send + Sync>;
+
+// WorkerOptions are the options of the worker threads set by the pool
+// builders.
+#[derive(Clone, Default)]
+pub struct WorkerOptions {
+    // workers are named `<prefix>-<n>`, unnamed if none
+    pub thread_name: Option<String>,
+    // bytes, the std default if none
+    pub stack_size: Option<usize>,
+    // logs the panic as an error if none
+    pub panic_handler: Option<PanicHandler>,
+}
+
+impl WorkerOptions {
+    // the builder of the n-th worker thread of a pool
+    pub(crate) fn thread(&self, n: u64) -> thread::Builder {
+        let mut builder = thread::Builder::new();
+        if let Some(prefix) = &self.thread_name {
+            builder = builder.name(format!("{}-{}", prefix, n));
+        }
+        if let Some(stack_size) = self.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        builder
+    }
+
+    pub(crate) fn report_panic(&self, message: &str) {
+        match &self.panic_handler {
+            Some(handler) => handler(message),
+            None => log::error!("job panicked: {}", message),
+        }
+    }
+}
+
+impl fmt::Debug for WorkerOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WorkerOptions")
+            .field("thread_name", &self.thread_name)
+            .field("stack_size", &self.stack_size)
+            .field("panic_handler", &self.panic_handler.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,114 @@
+// optimistic multi-key transactions, not to be confused with the records of
+// the KVStore log
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::engine::{KeyNotFound, KvsEngine};
+
+// Transaction reads through to the engine and buffers the writes until the
+// commit, which fails with Conflict if a key it read was written by someone
+// else since it began. A transaction dropped without a commit is aborted.
+pub struct Transaction<E: KvsEngine> {
+    engine: E,
+    start: u64,
+    buffer: Buffer,
+    done: bool,
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub fn begin(engine: &E) -> Result<Self> {
+        Ok(Transaction {
+            engine: engine.clone(),
+            start: engine.begin_transaction()?,
+            buffer: Buffer::default(),
+            done: false,
+        })
+    }
+
+    // the sequence number of the last write the transaction may have seen
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    // the value of the key, none if it is not found or removed by the
+    // transaction
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.buffer.get(&key) {
+            return Ok(value);
+        }
+        let value = match self.engine.get(key.clone()) {
+            Ok(value) => Some(value),
+            Err(e) if e.is::<KeyNotFound>() => None,
+            Err(e) => return Err(e),
+        };
+        self.buffer.read(key, value.clone());
+        Ok(value)
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.buffer.write(key, Some(value));
+    }
+
+    pub fn remove(&mut self, key: String) {
+        self.buffer.write(key, None);
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        self.done = true;
+        let buffer = std::mem::take(&mut self.buffer);
+        self.engine
+            .commit_transaction(buffer.into_commit(self.start))
+    }
+}
+
+impl<E: KvsEngine> Drop for Transaction<E> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.engine.abort_transaction(self.start);
+        }
+    }
+}
+
+// Commit carries a transaction to the engine, the reads with the values the
+// transaction saw and the writes in key order, none for a remove.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Commit {
+    pub start: u64,
+    pub reads: Vec<(String, Option<String>)>,
+    pub writes: Vec<(String, Option<String>)>,
+}
+
+// the keys a transaction read and wrote so far
+#[derive(Default)]
+pub(crate) struct Buffer {
+    reads: BTreeMap<String, Option<String>>,
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl Buffer {
+    // the value the transaction sees, none if it did not read or write the key
+    pub(crate) fn get(&self, key: &str) -> Option<Option<String>> {
+        self.writes
+            .get(key)
+            .or_else(|| self.reads.get(key))
+            .cloned()
+    }
+
+    pub(crate) fn read(&mut self, key: String, value: Option<String>) {
+        self.reads.insert(key, value);
+    }
+
+    pub(crate) fn write(&mut self, key: String, value: Option<String>) {
+        self.writes.insert(key, value);
+    }
+
+    pub(crate) fn into_commit(self, start: u64) -> Commit {
+        Commit {
+            start,
+            reads: self.reads.into_iter().collect(),
+            writes: self.writes.into_iter().collect(),
+        }
+    }
+}
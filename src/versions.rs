@@ -0,0 +1,176 @@
+// the older versions of keys, kept for the read views of the engines
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::engine::SnapshotExpired;
+
+// how long a view keeps the versions it sees while the keys are written
+pub(crate) const SNAPSHOT_MAX_AGE: Duration = Duration::from_secs(60);
+
+// Versions keeps the value a key had before each write, as long as a read
+// view pinned to a sequence number before the write is alive. V is how the
+// engine finds the value, a position in the log or the value itself.
+pub(crate) struct Versions<V> {
+    inner: Mutex<Inner<V>>,
+}
+
+struct Inner<V> {
+    // the sequence numbers of the live views, how many views have them and
+    // when the last of them was taken
+    pinned: BTreeMap<u64, (usize, Instant)>,
+    // the views older than max_age when a write came, their versions are
+    // dropped and their reads fail until they are unpinned
+    expired: BTreeMap<u64, usize>,
+    max_age: Duration,
+    // per key the sequence number of every write and the version before it,
+    // none if the key did not exist, in write order
+    history: HashMap<String, Vec<(u64, Option<V>)>>,
+}
+
+impl<V> Default for Versions<V> {
+    fn default() -> Self {
+        Versions {
+            inner: Mutex::new(Inner {
+                pinned: BTreeMap::new(),
+                expired: BTreeMap::new(),
+                max_age: SNAPSHOT_MAX_AGE,
+                history: HashMap::new(),
+            }),
+        }
+    }
+}
+
+impl<V: Clone> Versions<V> {
+    pub(crate) fn pin(&self, seq: u64) {
+        let mut inner = self.inner();
+        inner.expire();
+        let (views, at) = inner.pinned.entry(seq).or_insert((0, Instant::now()));
+        *views += 1;
+        *at = Instant::now();
+    }
+
+    pub(crate) fn set_max_age(&self, max_age: Duration) {
+        self.inner().max_age = max_age;
+    }
+
+    // Fails if the view pinned to seq expired. A read checks it after the
+    // versions are read, the ones it saw were still complete then.
+    pub(crate) fn check(&self, seq: u64) -> Result<()> {
+        if self.inner().expired.contains_key(&seq) {
+            return Err(SnapshotExpired.into());
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_pinned(&self) -> bool {
+        !self.inner().pinned.is_empty()
+    }
+
+    // drops the versions no live view can see anymore
+    pub(crate) fn unpin(&self, seq: u64) {
+        let mut inner = self.inner();
+        if let Some((views, _)) = inner.pinned.get_mut(&seq) {
+            *views -= 1;
+            if *views == 0 {
+                inner.pinned.remove(&seq);
+            }
+        } else if let Some(views) = inner.expired.get_mut(&seq) {
+            *views -= 1;
+            if *views == 0 {
+                inner.expired.remove(&seq);
+            }
+        }
+        inner.prune();
+    }
+
+    // Records the version a write with the sequence number replaces, the
+    // writes of a key are recorded in order before they are visible.
+    pub(crate) fn record(&self, key: &str, seq: u64, previous: Option<V>) {
+        let mut inner = self.inner();
+        inner.expire();
+        if inner.pinned.is_empty() {
+            return;
+        }
+        inner
+            .history
+            .entry(key.to_string())
+            .or_default()
+            .push((seq, previous));
+    }
+
+    // The version of the key a view pinned to seq sees, none if it sees the
+    // current one. The current version is read before this is asked, so a
+    // write between the two is found here.
+    pub(crate) fn get(&self, key: &str, seq: u64) -> Option<Option<V>> {
+        let inner = self.inner();
+        let writes = inner.history.get(key)?;
+        writes
+            .iter()
+            .find(|(write_seq, _)| *write_seq > seq)
+            .map(|(_, previous)| previous.clone())
+    }
+
+    // the keys written since the oldest live view that start with prefix
+    pub(crate) fn keys(&self, prefix: &str) -> Vec<String> {
+        self.inner()
+            .history
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn for_each_mut(&self, mut f: impl FnMut(&mut V) -> Result<()>) -> Result<()> {
+        let mut inner = self.inner();
+        for writes in inner.history.values_mut() {
+            for version in writes
+                .iter_mut()
+                .filter_map(|(_, previous)| previous.as_mut())
+            {
+                f(version)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn inner(&self) -> std::sync::MutexGuard<'_, Inner<V>> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<V> Inner<V> {
+    // Moves the views older than max_age to the expired ones, so a view
+    // left open does not keep every write after it.
+    fn expire(&mut self) {
+        let max_age = self.max_age;
+        let old: Vec<u64> = self
+            .pinned
+            .iter()
+            .filter(|(_, (_, at))| at.elapsed() > max_age)
+            .map(|(seq, _)| *seq)
+            .collect();
+        if old.is_empty() {
+            return;
+        }
+        for seq in old {
+            if let Some((views, _)) = self.pinned.remove(&seq) {
+                *self.expired.entry(seq).or_insert(0) += views;
+            }
+        }
+        self.prune();
+    }
+
+    // a view sees the version before a write only if it is older
+    fn prune(&mut self) {
+        match self.pinned.keys().next().cloned() {
+            Some(oldest) => self.history.retain(|_, writes| {
+                writes.retain(|(write_seq, _)| *write_seq > oldest);
+                !writes.is_empty()
+            }),
+            None => self.history.clear(),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+// key change notifications, fed from the write path of the engines
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+// the events a watcher may fall behind by before it is closed
+pub const WATCH_BUFFER: usize = 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    Set {
+        seq: u64,
+        key: String,
+        value: String,
+    },
+    Remove {
+        seq: u64,
+        key: String,
+    },
+}
+
+impl WatchEvent {
+    pub fn key(&self) -> &str {
+        match self {
+            WatchEvent::Set { key, .. } | WatchEvent::Remove { key, .. } => key,
+        }
+    }
+
+    pub fn seq(&self) -> u64 {
+        match self {
+            WatchEvent::Set { seq, .. } | WatchEvent::Remove { seq, .. } => *seq,
+        }
+    }
+}
+
+// Watcher receives the events of the keys matching a prefix, the
+// subscription ends when it is dropped. A watcher that falls behind by
+// WATCH_BUFFER events is closed instead of buffering the writes without
+// bound, and reports itself as lagged.
+pub struct Watcher {
+    receiver: Receiver<WatchEvent>,
+    lagged: Arc<AtomicBool>,
+}
+
+impl Watcher {
+    pub fn channel() -> (WatchSender, Watcher) {
+        let (sender, receiver) = channel(WATCH_BUFFER);
+        let lagged = Arc::new(AtomicBool::new(false));
+        let sender = WatchSender {
+            sender,
+            lagged: lagged.clone(),
+        };
+        (sender, Watcher { receiver, lagged })
+    }
+
+    // returns none once the engine stops sending events
+    pub async fn next(&mut self) -> Option<WatchEvent> {
+        self.receiver.recv().await
+    }
+
+    pub fn blocking_next(&mut self) -> Option<WatchEvent> {
+        self.receiver.blocking_recv()
+    }
+
+    // an event already received, none if there is none yet
+    pub fn try_next(&mut self) -> Option<WatchEvent> {
+        self.receiver.try_recv().ok()
+    }
+
+    // Whether the events ended because the watcher fell behind, the keys
+    // have to be read again to catch up.
+    pub fn lagged(&self) -> bool {
+        self.lagged.load(Ordering::SeqCst)
+    }
+}
+
+// WatchSender is the engine side of a Watcher, it never waits for the watcher.
+pub struct WatchSender {
+    sender: Sender<WatchEvent>,
+    lagged: Arc<AtomicBool>,
+}
+
+impl WatchSender {
+    // Sends the event, false if the watcher is gone or was closed because its
+    // buffer is full. Either way no more events are sent to it.
+    pub fn send(&self, event: WatchEvent) -> bool {
+        match self.sender.try_send(event) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.lagged.store(true, Ordering::SeqCst);
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+// WatchRegistry keeps the subscribers of an engine without native watch support.
+#[derive(Clone, Default)]
+pub struct WatchRegistry {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+// the watched prefix and the sender of the watcher
+type Subscriber = (String, WatchSender);
+
+impl WatchRegistry {
+    pub fn subscribe(&self, prefix: String) -> Watcher {
+        let (sender, watcher) = Watcher::channel();
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push((prefix, sender));
+        }
+        watcher
+    }
+
+    // sends the event to the matching subscribers and forgets the dropped ones
+    pub fn publish(&self, event: WatchEvent) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.retain(|(prefix, sender)| {
+                if sender.is_closed() {
+                    return false;
+                }
+                !event.key().starts_with(prefix.as_str()) || sender.send(event.clone())
+            });
+        }
+    }
+}